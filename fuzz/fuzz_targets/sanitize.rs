@@ -0,0 +1,28 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Clipboard content is always text, so anything else would never reach the sanitizer
+    if let Ok(text) = std::str::from_utf8(data) {
+        clipboard_sanitizer::fuzz_sanitize(text);
+    }
+});
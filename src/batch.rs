@@ -0,0 +1,84 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::Sanitizer;
+
+/// Summary counts the URLs found in a batch of text and how many of them were changed, per domain
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub found: usize,
+    pub changed: usize,
+    pub domains: BTreeMap<String, (usize, usize)>,
+}
+
+impl Summary {
+    fn record(&mut self, domain: &str, changed: bool) {
+        let (found, domain_changed) = self.domains.entry(domain.to_string()).or_default();
+        *found += 1;
+        self.found += 1;
+        if changed {
+            *domain_changed += 1;
+            self.changed += 1;
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} URLs found, {} changed", self.found, self.changed)?;
+        for (domain, (found, changed)) in &self.domains {
+            writeln!(f, "  {}: {} found, {} changed", domain, found, changed)?;
+        }
+        Ok(())
+    }
+}
+
+/// summarize counts the whitespace-separated URLs in the text that the sanitizer would change
+pub fn summarize(sanitizer: &Sanitizer, text: &str) -> Summary {
+    let mut summary = Summary::default();
+    for word in text.split_whitespace() {
+        let (_, word, _) = sanitizer.split_word(word);
+        if let Some(url) = sanitizer.parse_text(word) {
+            let host = url.host_str().unwrap_or(url.scheme()).to_string();
+            summary.record(&host, sanitizer.sanitize(&url) != url);
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize_fixture() {
+        let summary = summarize(
+            &crate::tests::test_sanitizer(),
+            include_str!("../tests/fixtures/links.txt"),
+        );
+        assert_eq!(summary.found, 5);
+        assert_eq!(summary.changed, 3);
+        assert_eq!(summary.domains["example.com"], (2, 1));
+        assert_eq!(summary.domains["twitter.com"], (1, 1));
+        assert_eq!(summary.domains["youtu.be"], (1, 1));
+        assert_eq!(summary.domains["www.rust-lang.org"], (1, 0));
+    }
+}
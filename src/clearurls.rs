@@ -0,0 +1,321 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use dirs::config_dir;
+use log::{debug, error, warn};
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+use url::Url;
+
+use crate::params::TrackingParams;
+use crate::settings::{self, Settings};
+use crate::{apply_rule, Stage, Trace};
+
+/// ClearUrls is a ruleset in the data.json format of the ClearURLs browser extension, which lists the tracking
+/// params and redirect wrappers of each provider, like a site or an ad network.
+#[derive(Debug, Default)]
+pub struct ClearUrls {
+    providers: Vec<Provider>,
+}
+
+/// Provider is one site or service of the ruleset. Every regex of it is case-insensitive, like in ClearURLs.
+#[derive(Debug)]
+struct Provider {
+    name: String,
+    url_pattern: Regex,
+    /// URLs matching one of the exceptions are left alone, e.g. login pages that need their params
+    exceptions: Vec<Regex>,
+    /// Regexes whose first group is the percent-encoded destination of a redirect wrapper
+    redirections: Vec<Regex>,
+    /// Regexes that are removed from anywhere in the URL, e.g. tracking path segments
+    raw_rules: Vec<Regex>,
+    params: TrackingParams,
+}
+
+impl ClearUrls {
+    /// parse reads the ruleset. The referralMarketing params like affiliate tags are only stripped with
+    /// strip_referral, like STRIP_REFERRAL does for the built-in rules. Providers with completeProvider set
+    /// are skipped, since ClearURLs blocks those URLs completely instead of cleaning them, and so are
+    /// rules with regexes that don't compile, which are logged.
+    pub fn parse(json: &str, strip_referral: bool) -> Result<ClearUrls, String> {
+        let data: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let providers = data
+            .get("providers")
+            .and_then(Value::as_object)
+            .ok_or("no providers object")?;
+        let mut rules = ClearUrls::default();
+        for (name, provider) in providers {
+            if provider.get("completeProvider").and_then(Value::as_bool) == Some(true) {
+                debug!("Skipping ClearURLs provider {}, it blocks whole URLs", name);
+                continue;
+            }
+            let Some(url_pattern) = provider.get("urlPattern").and_then(Value::as_str) else {
+                warn!("Skipping ClearURLs provider {} without a urlPattern", name);
+                continue;
+            };
+            let Some(url_pattern) = compile(name, url_pattern) else {
+                continue;
+            };
+            let regexes = |field: &str| -> Vec<Regex> {
+                strings(provider, field)
+                    .filter_map(|pattern| compile(name, pattern))
+                    .collect()
+            };
+            let mut params = TrackingParams::default();
+            let mut param_rules: Vec<&str> = strings(provider, "rules").collect();
+            if strip_referral {
+                param_rules.extend(strings(provider, "referralMarketing"));
+            }
+            for rule in param_rules {
+                // Params have to match a rule completely
+                if let Some(regex) = compile(name, &format!("^(?:{})$", rule)) {
+                    params.add_regex(regex);
+                }
+            }
+            rules.providers.push(Provider {
+                name: name.clone(),
+                url_pattern,
+                exceptions: regexes("exceptions"),
+                redirections: regexes("redirections"),
+                raw_rules: regexes("rawRules"),
+                params,
+            });
+        }
+        Ok(rules)
+    }
+
+    pub fn load(path: &Path, strip_referral: bool) -> Result<ClearUrls, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        ClearUrls::parse(&json, strip_referral)
+    }
+
+    /// redirect returns the destination of a redirect wrapper listed in the ruleset
+    pub(crate) fn redirect(&self, url: &Url, trace: &mut Trace) -> Option<Url> {
+        self.matching(url).find_map(|provider| {
+            let destination = provider.redirections.iter().find_map(|redirection| {
+                let encoded = redirection.captures(url.as_str())?.get(1)?.as_str();
+                let decoded = percent_encoding::percent_decode_str(encoded)
+                    .decode_utf8()
+                    .ok()?;
+                Url::parse(&decoded)
+                    .ok()
+                    .filter(|destination| matches!(destination.scheme(), "http" | "https"))
+            })?;
+            trace.record(Stage::ClearUrls, || {
+                format!("{} redirection, rewrote to {}", provider.name, destination)
+            });
+            Some(destination)
+        })
+    }
+
+    /// strip removes the raw rules and params of every provider that matches the URL, except the params in keep
+    pub(crate) fn strip(&self, url: &Url, keep: &[String], trace: &mut Trace) -> Url {
+        let providers: Vec<&Provider> = self.matching(url).collect();
+        let mut url = url.clone();
+        for provider in providers {
+            for raw_rule in &provider.raw_rules {
+                let stripped = raw_rule.replace_all(url.as_str(), "");
+                if stripped == url.as_str() {
+                    continue;
+                }
+                match Url::parse(&stripped) {
+                    Ok(stripped_url) => {
+                        trace.record(Stage::ClearUrls, || {
+                            format!("{} raw rule removed {:?}", provider.name, raw_rule.as_str())
+                        });
+                        url = stripped_url;
+                    }
+                    Err(e) => warn!(
+                        "ClearURLs raw rule {:?} of {} made {} invalid: {}",
+                        raw_rule.as_str(),
+                        provider.name,
+                        url,
+                        e
+                    ),
+                }
+            }
+            url = apply_rule(
+                Stage::ClearUrls,
+                &provider.name,
+                &url,
+                &provider.params,
+                keep,
+                trace,
+            );
+        }
+        url
+    }
+
+    fn matching<'a>(&'a self, url: &'a Url) -> impl Iterator<Item = &'a Provider> {
+        self.providers.iter().filter(|provider| {
+            provider.url_pattern.is_match(url.as_str())
+                && !provider
+                    .exceptions
+                    .iter()
+                    .any(|exception| exception.is_match(url.as_str()))
+        })
+    }
+}
+
+/// file returns the path of the ruleset: CLEARURLS_FILE, or clearurls.json in the config directory if only
+/// CLEARURLS_URL is set. Returns None if neither is set.
+pub fn file(config: &Settings) -> Option<PathBuf> {
+    if let Some(path) = settings::read(config, "CLEARURLS_FILE").filter(|path| !path.is_empty()) {
+        return Some(PathBuf::from(path));
+    }
+    settings::read(config, "CLEARURLS_URL").filter(|url| !url.is_empty())?;
+    config_dir().map(|dir| dir.join("clipboard-sanitizer").join("clearurls.json"))
+}
+
+/// load_configured loads the ruleset from file, logging why if it can't. A file that hasn't been downloaded
+/// from CLEARURLS_URL yet isn't an error.
+pub(crate) fn load_configured(config: &Settings, strip_referral: bool) -> Option<ClearUrls> {
+    let path = file(config)?;
+    let downloaded = settings::read(config, "CLEARURLS_URL").is_some_and(|url| !url.is_empty());
+    if downloaded && !path.exists() {
+        debug!("ClearURLs rules {:?} haven't been downloaded yet", path);
+        return None;
+    }
+    match ClearUrls::load(&path, strip_referral) {
+        Ok(rules) => Some(rules),
+        Err(e) => {
+            error!("Failed to load ClearURLs rules from {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// download fetches a ruleset with curl and replaces the file with it if it's valid. The file is written
+/// next to the path first and renamed over it, so the sanitizer never reads a partly written ruleset.
+pub fn download(url: &str, path: &Path, timeout: Duration) -> Result<(), String> {
+    // The URL is passed in a config on stdin, like for expanding short links
+    let quoted = url.replace('\\', "\\\\").replace('"', "\\\"");
+    let config = format!("url = \"{}\"\n", quoted);
+    let json = crate::run_command(
+        "curl --silent --fail --location --config -",
+        &config,
+        timeout,
+    )
+    .map_err(|e| format!("curl {}", e))?;
+    ClearUrls::parse(&json, false)?;
+    let partial = path.with_extension("part");
+    std::fs::write(&partial, json)
+        .and_then(|_| std::fs::rename(&partial, path))
+        .map_err(|e| e.to_string())
+}
+
+/// strings returns the strings in an array field of a provider, ignoring anything else
+fn strings<'a>(provider: &'a Value, field: &str) -> impl Iterator<Item = &'a str> {
+    provider
+        .get(field)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+}
+
+fn compile(provider: &str, pattern: &str) -> Option<Regex> {
+    match RegexBuilder::new(pattern).case_insensitive(true).build() {
+        Ok(regex) => Some(regex),
+        Err(e) => {
+            warn!(
+                "Skipping ClearURLs regex {:?} of {}: {}",
+                pattern, provider, e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"{
+        "providers": {
+            "example": {
+                "urlPattern": "^https?:\\/\\/(?:[a-z0-9-]+\\.)*?example\\.com",
+                "completeProvider": false,
+                "rules": ["ref_src", "track_[a-z]+"],
+                "referralMarketing": ["aff"],
+                "rawRules": ["\\/ref=[^/?]*"],
+                "exceptions": ["^https?:\\/\\/login\\.example\\.com"],
+                "redirections": ["^https?:\\/\\/(?:[a-z0-9-]+\\.)*?example\\.com\\/out\\?.*?to=([^&]*)"],
+                "forceRedirection": false
+            },
+            "ads": {
+                "urlPattern": "^https?:\\/\\/ads\\.example\\.net",
+                "completeProvider": true
+            },
+            "broken": {
+                "urlPattern": "^https?:\\/\\/broken\\.example\\.org",
+                "rules": ["(unclosed", "id"]
+            }
+        }
+    }"#;
+
+    fn strip(rules: &ClearUrls, url: &str) -> String {
+        let url = Url::parse(url).unwrap();
+        rules
+            .strip(&url, &["keep".to_string()], &mut Trace::default())
+            .to_string()
+    }
+
+    #[test]
+    fn test_clearurls_rules() {
+        let rules = ClearUrls::parse(RULES, false).unwrap();
+        assert_eq!(rules.providers.len(), 2);
+        assert_eq!(
+            strip(
+                &rules,
+                "https://www.example.com/item/ref=sr_1?TRACK_id=1&ref_src=x&aff=y&keep=1&id=2"
+            ),
+            "https://www.example.com/item?aff=y&keep=1&id=2"
+        );
+        assert_eq!(
+            strip(&rules, "https://login.example.com/?ref_src=x"),
+            "https://login.example.com/?ref_src=x"
+        );
+        assert_eq!(
+            strip(&rules, "https://broken.example.org/?id=1&x=2"),
+            "https://broken.example.org/?x=2"
+        );
+
+        let rules = ClearUrls::parse(RULES, true).unwrap();
+        assert_eq!(
+            strip(&rules, "https://example.com/?aff=y&keep=1"),
+            "https://example.com/?keep=1"
+        );
+
+        let wrapped =
+            Url::parse("https://example.com/out?id=1&to=https%3A%2F%2Fexample.org%2F%3Fa%3Db")
+                .unwrap();
+        assert_eq!(
+            rules.redirect(&wrapped, &mut Trace::default()),
+            Some(Url::parse("https://example.org/?a=b").unwrap())
+        );
+        let wrapped = Url::parse("https://example.com/out?to=javascript%3Aalert(1)").unwrap();
+        assert_eq!(rules.redirect(&wrapped, &mut Trace::default()), None);
+
+        assert!(ClearUrls::parse("{}", false).is_err());
+        assert!(ClearUrls::parse("not json", false).is_err());
+    }
+}
@@ -0,0 +1,798 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use log::{debug, info};
+
+/// Names accepted by open_backend, in the order auto-detection tries them
+pub const BACKENDS: [&str; 3] = ["arboard", "wl-clipboard", "xclip"];
+
+/// Selection is which clipboard a backend reads and writes. Primary is the Linux selection of the text selected
+/// last, which middle-click pastes. Other platforms only have the clipboard.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Selection {
+    Clipboard,
+    Primary,
+}
+
+/// ClipboardBackend reads and writes the text content of the system clipboard
+pub trait ClipboardBackend {
+    fn get_text(&mut self) -> Result<String, ClipboardError>;
+    fn set_text(&mut self, text: String) -> Result<(), ClipboardError>;
+
+    /// get_html returns the HTML version of the clipboard content, if the backend supports it and there is one
+    fn get_html(&mut self) -> Option<String> {
+        None
+    }
+
+    /// set_text_and_html replaces the clipboard content with both the text and its HTML version.
+    /// Backends without HTML support only set the text.
+    fn set_text_and_html(&mut self, text: String, _html: String) -> Result<(), ClipboardError> {
+        self.set_text(text)
+    }
+
+    /// source guesses whether the current clipboard content was copied by the user or set by a program
+    fn source(&mut self) -> CopySource {
+        copy_source()
+    }
+
+    /// sequence_number returns a number that changes whenever the clipboard changes, where the platform has one.
+    /// The clipboard isn't read while it stays the same.
+    fn sequence_number(&mut self) -> Option<u32> {
+        sequence_number()
+    }
+
+    /// has_other_content returns true when the clipboard holds files or an image besides the text, like a file
+    /// copied in a file manager, whose text is its path. Writing the cleaned text would replace them.
+    fn has_other_content(&mut self) -> bool {
+        false
+    }
+}
+
+/// ChangeEvents is notified by the platform whenever the clipboard changes, so it doesn't have to be polled
+pub struct ChangeEvents {
+    receiver: mpsc::Receiver<()>,
+    /// The wl-paste --watch process on Wayland, killed when the events are dropped
+    child: Option<Child>,
+}
+
+impl ChangeEvents {
+    /// wait blocks until the clipboard changes or the timeout passes. Returns true if it changed.
+    /// Err means the events stopped, e.g. because wl-paste exited, and the clipboard has to be polled.
+    pub fn wait(&mut self, timeout: Duration) -> Result<bool, ClipboardError> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(()) => {
+                // Several changes in a row only need one check
+                while self.receiver.try_recv().is_ok() {}
+                Ok(true)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(false),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(ClipboardError::Unavailable(
+                "clipboard change events stopped".to_string(),
+            )),
+        }
+    }
+}
+
+impl Drop for ChangeEvents {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// watch_changes listens for clipboard changes with AddClipboardFormatListener on Windows and wl-paste --watch on
+/// Wayland compositors with data-control. Other platforms have no change events that work without a window.
+pub fn watch_changes() -> Result<ChangeEvents, ClipboardError> {
+    let (sender, receiver) = mpsc::channel();
+    #[cfg(windows)]
+    {
+        let (ready_sender, ready) = mpsc::channel();
+        std::thread::spawn(move || listen_windows(sender, ready_sender));
+        ready
+            .recv()
+            .unwrap_or_else(|_| Err("listener thread exited".to_string()))
+            .map_err(ClipboardError::Unavailable)?;
+        Ok(ChangeEvents {
+            receiver,
+            child: None,
+        })
+    }
+    #[cfg(all(
+        unix,
+        not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+    ))]
+    {
+        if !wayland_session() {
+            return Err(ClipboardError::Unavailable(
+                "X11 has no clipboard change events without a window".to_string(),
+            ));
+        }
+        // wl-paste runs echo on every change, so every line it prints is one change
+        let mut child = Command::new("wl-paste")
+            .args(["--watch", "echo"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| ClipboardError::Unavailable(format!("wl-paste: {}", e)))?;
+        let stdout = child.stdout.take().unwrap();
+        std::thread::spawn(move || {
+            use std::io::BufRead;
+            for line in std::io::BufReader::new(stdout).lines() {
+                if line.is_err() || sender.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(ChangeEvents {
+            receiver,
+            child: Some(child),
+        })
+    }
+    #[cfg(not(any(
+        windows,
+        all(
+            unix,
+            not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+        )
+    )))]
+    {
+        drop((sender, receiver));
+        Err(ClipboardError::Unavailable(
+            "clipboard change events aren't supported on this platform".to_string(),
+        ))
+    }
+}
+
+/// listen_windows creates a message-only window that receives WM_CLIPBOARDUPDATE and runs its message loop.
+/// ready gets the result of setting it up.
+#[cfg(windows)]
+fn listen_windows(sender: mpsc::Sender<()>, ready: mpsc::Sender<Result<(), String>>) {
+    use windows_sys::Win32::Foundation::{HWND, LPARAM, LRESULT, WPARAM};
+    use windows_sys::Win32::System::DataExchange::AddClipboardFormatListener;
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassW,
+        HWND_MESSAGE, MSG, WM_CLIPBOARDUPDATE, WNDCLASSW,
+    };
+
+    thread_local! {
+        static SENDER: std::cell::RefCell<Option<mpsc::Sender<()>>> = const { std::cell::RefCell::new(None) };
+    }
+
+    unsafe extern "system" fn window_proc(
+        window: HWND,
+        message: u32,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        if message == WM_CLIPBOARDUPDATE {
+            SENDER.with(|sender| {
+                if let Some(sender) = &*sender.borrow() {
+                    let _ = sender.send(());
+                }
+            });
+            return 0;
+        }
+        DefWindowProcW(window, message, wparam, lparam)
+    }
+
+    SENDER.with(|cell| *cell.borrow_mut() = Some(sender));
+    let class_name: Vec<u16> = "clipboard-sanitizer-listener\0".encode_utf16().collect();
+    // SAFETY: The class name outlives the window, and the window is only used on this thread
+    unsafe {
+        let class = WNDCLASSW {
+            style: 0,
+            lpfnWndProc: Some(window_proc),
+            cbClsExtra: 0,
+            cbWndExtra: 0,
+            hInstance: 0,
+            hIcon: 0,
+            hCursor: 0,
+            hbrBackground: 0,
+            lpszMenuName: std::ptr::null(),
+            lpszClassName: class_name.as_ptr(),
+        };
+        RegisterClassW(&class);
+        let window = CreateWindowExW(
+            0,
+            class_name.as_ptr(),
+            std::ptr::null(),
+            0,
+            0,
+            0,
+            0,
+            0,
+            HWND_MESSAGE,
+            0,
+            0,
+            std::ptr::null(),
+        );
+        if window == 0 || AddClipboardFormatListener(window) == 0 {
+            let _ = ready.send(Err(std::io::Error::last_os_error().to_string()));
+            return;
+        }
+        let _ = ready.send(Ok(()));
+        let mut message: MSG = std::mem::zeroed();
+        while GetMessageW(&mut message, window, 0, 0) > 0 {
+            DispatchMessageW(&message);
+        }
+    }
+}
+
+/// CopySource is a best-effort guess of who put the content on the clipboard
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CopySource {
+    /// Copied from the focused window, most likely by the user
+    #[cfg_attr(not(windows), allow(dead_code))]
+    User,
+    /// Set by a program in the background
+    #[cfg_attr(not(windows), allow(dead_code))]
+    Program,
+    /// The platform doesn't tell us
+    #[default]
+    Unknown,
+}
+
+/// sequence_number returns the clipboard sequence number on Windows, which goes up on every change
+#[cfg(windows)]
+fn sequence_number() -> Option<u32> {
+    // SAFETY: This only reads a counter. It's 0 if the window station has no clipboard access.
+    let sequence =
+        unsafe { windows_sys::Win32::System::DataExchange::GetClipboardSequenceNumber() };
+    (sequence != 0).then_some(sequence)
+}
+
+/// sequence_number returns the changeCount of the general pasteboard on macOS, which goes up on every copy. It's
+/// called through the Objective-C runtime, since it's the only AppKit call the binary makes.
+#[cfg(target_os = "macos")]
+fn sequence_number() -> Option<u32> {
+    use std::ffi::{c_char, c_void};
+
+    #[link(name = "AppKit", kind = "framework")]
+    extern "C" {}
+    #[link(name = "objc")]
+    extern "C" {
+        fn objc_getClass(name: *const c_char) -> *mut c_void;
+        fn sel_registerName(name: *const c_char) -> *mut c_void;
+        fn objc_msgSend();
+    }
+
+    // SAFETY: objc_msgSend is cast to the signatures of the two methods called, +[NSPasteboard generalPasteboard]
+    // returning an object and -[NSPasteboard changeCount] returning an NSInteger. Neither takes arguments.
+    unsafe {
+        let send_object: unsafe extern "C" fn(*mut c_void, *mut c_void) -> *mut c_void =
+            std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+        let send_integer: unsafe extern "C" fn(*mut c_void, *mut c_void) -> isize =
+            std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+        let class = objc_getClass(c"NSPasteboard".as_ptr());
+        if class.is_null() {
+            return None;
+        }
+        let pasteboard = send_object(class, sel_registerName(c"generalPasteboard".as_ptr()));
+        if pasteboard.is_null() {
+            return None;
+        }
+        Some(send_integer(pasteboard, sel_registerName(c"changeCount".as_ptr())) as u32)
+    }
+}
+
+/// X11 and Wayland have no change counter that arboard exposes, so the content is read and compared every time
+#[cfg(not(any(windows, target_os = "macos")))]
+fn sequence_number() -> Option<u32> {
+    None
+}
+
+/// copy_source compares the process owning the clipboard to the process of the focused window.
+/// Users copy from the window they're using, while programs setting the clipboard usually aren't focused.
+#[cfg(windows)]
+fn copy_source() -> CopySource {
+    use windows_sys::Win32::System::DataExchange::GetClipboardOwner;
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        GetForegroundWindow, GetWindowThreadProcessId,
+    };
+
+    // SAFETY: These only read window handles and write the process IDs to the locals
+    unsafe {
+        let owner = GetClipboardOwner();
+        let foreground = GetForegroundWindow();
+        if owner == 0 || foreground == 0 {
+            return CopySource::Unknown;
+        }
+        let mut owner_pid = 0;
+        let mut foreground_pid = 0;
+        GetWindowThreadProcessId(owner, &mut owner_pid);
+        GetWindowThreadProcessId(foreground, &mut foreground_pid);
+        if owner_pid == 0 || foreground_pid == 0 {
+            CopySource::Unknown
+        } else if owner_pid == foreground_pid {
+            CopySource::User
+        } else {
+            CopySource::Program
+        }
+    }
+}
+
+/// copy_source can't tell on this platform. X11 and Wayland don't reliably expose the owning process,
+/// and macOS doesn't expose the owner at all.
+#[cfg(not(windows))]
+fn copy_source() -> CopySource {
+    CopySource::Unknown
+}
+
+#[derive(Debug)]
+pub enum ClipboardError {
+    /// The backend couldn't be opened
+    Unavailable(String),
+    /// The clipboard is empty or doesn't contain text, which isn't an error for us
+    NoText,
+    Read(String),
+    Write(String),
+}
+
+impl fmt::Display for ClipboardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClipboardError::Unavailable(e) => write!(f, "clipboard unavailable: {}", e),
+            ClipboardError::NoText => write!(f, "clipboard doesn't contain text"),
+            ClipboardError::Read(e) => write!(f, "failed to read clipboard: {}", e),
+            ClipboardError::Write(e) => write!(f, "failed to write clipboard: {}", e),
+        }
+    }
+}
+
+/// open_backend opens the named backend. An empty name or "auto" uses the first backend that works.
+pub fn open_backend(name: &str) -> Result<Box<dyn ClipboardBackend>, ClipboardError> {
+    open_selection(name, Selection::Clipboard)
+}
+
+/// open_selection opens the named backend for the selection, like open_backend does for the clipboard
+pub fn open_selection(
+    name: &str,
+    selection: Selection,
+) -> Result<Box<dyn ClipboardBackend>, ClipboardError> {
+    #[cfg(not(all(
+        unix,
+        not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+    )))]
+    if selection == Selection::Primary && name != "mock" {
+        return Err(ClipboardError::Unavailable(
+            "the primary selection only exists on Linux".to_string(),
+        ));
+    }
+    let primary = selection == Selection::Primary;
+    match name {
+        "" | "auto" => {
+            for name in BACKENDS {
+                match open_selection(name, selection) {
+                    Ok(backend) => {
+                        info!("Using clipboard backend {}", name);
+                        return Ok(backend);
+                    }
+                    Err(e) => debug!("Clipboard backend {} isn't usable: {}", name, e),
+                }
+            }
+            Err(ClipboardError::Unavailable(
+                "none of the clipboard backends work".to_string(),
+            ))
+        }
+        "arboard" => match arboard::Clipboard::new() {
+            Ok(clipboard) => {
+                #[cfg(all(
+                    unix,
+                    not(any(
+                        target_os = "macos",
+                        target_os = "android",
+                        target_os = "emscripten"
+                    ))
+                ))]
+                {
+                    if wayland_session() {
+                        check_data_control()?;
+                    }
+                    if primary {
+                        return Ok(Box::new(ArboardPrimaryBackend(clipboard)));
+                    }
+                }
+                Ok(Box::new(ArboardBackend(clipboard)))
+            }
+            Err(e) => Err(ClipboardError::Unavailable(e.to_string())),
+        },
+        "wl-clipboard" => {
+            #[cfg(all(
+                unix,
+                not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+            ))]
+            if !wayland_session() {
+                return Err(ClipboardError::Unavailable(
+                    "WAYLAND_DISPLAY isn't set".to_string(),
+                ));
+            }
+            if primary {
+                CommandBackend::open(
+                    ("wl-paste", &["--no-newline", "--primary"]),
+                    ("wl-copy", &["--primary"]),
+                )
+            } else {
+                CommandBackend::open(("wl-paste", &["--no-newline"]), ("wl-copy", &[]))
+            }
+        }
+        "xclip" if primary => CommandBackend::open(
+            ("xclip", &["-selection", "primary", "-out"]),
+            ("xclip", &["-selection", "primary", "-in"]),
+        ),
+        "xclip" => CommandBackend::open(
+            ("xclip", &["-selection", "clipboard", "-out"]),
+            ("xclip", &["-selection", "clipboard", "-in"]),
+        ),
+        #[cfg(test)]
+        "mock" => Ok(Box::<MockBackend>::default()),
+        _ => Err(ClipboardError::Unavailable(format!(
+            "unknown clipboard backend {:?}, expected one of auto, {}",
+            name,
+            BACKENDS.join(", ")
+        ))),
+    }
+}
+
+/// wayland_session returns true when running under a Wayland compositor, which only lets the focused window read
+/// the clipboard except through the data-control protocol
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+fn wayland_session() -> bool {
+    std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+/// check_data_control asks the compositor for the wlr or ext data-control protocol, which arboard reads the
+/// clipboard through. Without it arboard quietly falls back to XWayland's clipboard, so that's logged, and the
+/// backend fails when there's no XWayland either so that auto moves on to wl-clipboard.
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+fn check_data_control() -> Result<(), ClipboardError> {
+    use wl_clipboard_rs::utils::{is_primary_selection_supported, PrimarySelectionCheckError};
+
+    let reason = match is_primary_selection_supported() {
+        Ok(_) => {
+            debug!("Reading the Wayland clipboard through data-control");
+            return Ok(());
+        }
+        Err(PrimarySelectionCheckError::MissingProtocol) => {
+            "the compositor doesn't support data-control".to_string()
+        }
+        Err(e) => format!("data-control can't be used: {}", e),
+    };
+    if std::env::var_os("DISPLAY").is_none() {
+        return Err(ClipboardError::Unavailable(format!(
+            "{} and XWayland isn't running",
+            reason
+        )));
+    }
+    log::warn!(
+        "Reading XWayland's clipboard because {}, it only sees what Wayland windows copy while the compositor keeps it in sync",
+        reason
+    );
+    Ok(())
+}
+
+struct ArboardBackend(arboard::Clipboard);
+
+impl ClipboardBackend for ArboardBackend {
+    fn get_text(&mut self) -> Result<String, ClipboardError> {
+        self.0.get_text().map_err(|e| match e {
+            arboard::Error::ContentNotAvailable => ClipboardError::NoText,
+            e => ClipboardError::Read(e.to_string()),
+        })
+    }
+
+    fn set_text(&mut self, text: String) -> Result<(), ClipboardError> {
+        self.0
+            .set_text(text)
+            .map_err(|e| ClipboardError::Write(e.to_string()))
+    }
+
+    fn get_html(&mut self) -> Option<String> {
+        self.0.get().html().ok()
+    }
+
+    fn set_text_and_html(&mut self, text: String, html: String) -> Result<(), ClipboardError> {
+        self.0
+            .set_html(html, Some(text))
+            .map_err(|e| ClipboardError::Write(e.to_string()))
+    }
+
+    fn has_other_content(&mut self) -> bool {
+        self.0
+            .get()
+            .file_list()
+            .is_ok_and(|files| !files.is_empty())
+            || self.0.get_image().is_ok()
+    }
+}
+
+/// ArboardPrimaryBackend reads and writes the primary selection. It only has text, so there's no HTML to keep.
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+struct ArboardPrimaryBackend(arboard::Clipboard);
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+impl ClipboardBackend for ArboardPrimaryBackend {
+    fn get_text(&mut self) -> Result<String, ClipboardError> {
+        use arboard::GetExtLinux;
+
+        self.0
+            .get()
+            .clipboard(arboard::LinuxClipboardKind::Primary)
+            .text()
+            .map_err(|e| match e {
+                arboard::Error::ContentNotAvailable => ClipboardError::NoText,
+                e => ClipboardError::Read(e.to_string()),
+            })
+    }
+
+    fn set_text(&mut self, text: String) -> Result<(), ClipboardError> {
+        use arboard::SetExtLinux;
+
+        self.0
+            .set()
+            .clipboard(arboard::LinuxClipboardKind::Primary)
+            .text(text)
+            .map_err(|e| ClipboardError::Write(e.to_string()))
+    }
+}
+
+/// CommandBackend uses external clipboard tools like wl-paste/wl-copy or xclip
+struct CommandBackend {
+    read: (&'static str, &'static [&'static str]),
+    write: (&'static str, &'static [&'static str]),
+}
+
+impl CommandBackend {
+    fn open(
+        read: (&'static str, &'static [&'static str]),
+        write: (&'static str, &'static [&'static str]),
+    ) -> Result<Box<dyn ClipboardBackend>, ClipboardError> {
+        // Check the tools are installed before committing to them. The status doesn't matter since
+        // reading fails when the clipboard is empty.
+        for program in [read.0, write.0] {
+            if let Err(e) = Command::new(program)
+                .arg("--version")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+            {
+                return Err(ClipboardError::Unavailable(format!("{}: {}", program, e)));
+            }
+        }
+        Ok(Box::new(CommandBackend { read, write }))
+    }
+}
+
+impl ClipboardBackend for CommandBackend {
+    fn get_text(&mut self) -> Result<String, ClipboardError> {
+        let output = Command::new(self.read.0)
+            .args(self.read.1)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| ClipboardError::Read(e.to_string()))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // The tools also exit with an error when the clipboard is empty or doesn't have text
+            if is_no_text_message(&stderr) {
+                return Err(ClipboardError::NoText);
+            }
+            return Err(ClipboardError::Read(format!(
+                "{} exited with {}: {}",
+                self.read.0,
+                output.status,
+                stderr.trim()
+            )));
+        }
+        String::from_utf8(output.stdout).map_err(|e| ClipboardError::Read(e.to_string()))
+    }
+
+    fn set_text(&mut self, text: String) -> Result<(), ClipboardError> {
+        let mut child = Command::new(self.write.0)
+            .args(self.write.1)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| ClipboardError::Write(e.to_string()))?;
+        let written = child.stdin.take().unwrap().write_all(text.as_bytes());
+        let status = child
+            .wait()
+            .map_err(|e| ClipboardError::Write(e.to_string()))?;
+        written.map_err(|e| ClipboardError::Write(e.to_string()))?;
+        if !status.success() {
+            return Err(ClipboardError::Write(format!(
+                "{} exited with {}",
+                self.write.0, status
+            )));
+        }
+        Ok(())
+    }
+
+    fn has_other_content(&mut self) -> bool {
+        // Both tools print the types the clipboard has, one per line
+        let list: &[&str] = match self.read.0 {
+            "xclip" => &["-target", "TARGETS"],
+            _ => &["--list-types"],
+        };
+        let Ok(output) = Command::new(self.read.0)
+            .args(self.read.1)
+            .args(list)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+        else {
+            return false;
+        };
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(is_other_content_type)
+    }
+}
+
+/// is_no_text_message returns true for the errors wl-paste and xclip print when the clipboard is empty or has no text
+fn is_no_text_message(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    [
+        "nothing is copied",
+        "no selection",
+        "no suitable type",
+        "not available",
+    ]
+    .iter()
+    .any(|message| stderr.contains(message))
+}
+
+/// is_other_content_type returns true for the MIME types of images, and the types file managers use for copied files
+fn is_other_content_type(mime: &str) -> bool {
+    mime.starts_with("image/")
+        || mime == "x-special/gnome-copied-files"
+        || mime == "application/x-kde4-urilist"
+}
+
+/// MockBackend is an in-memory clipboard for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockBackend {
+    pub text: Option<String>,
+    pub html: Option<String>,
+    /// writes counts the writes to the clipboard
+    pub writes: u32,
+    pub fail_reads: bool,
+    pub fail_writes: bool,
+    pub source: CopySource,
+    pub sequence: Option<u32>,
+    /// other_content stands for files or an image on the clipboard besides the text
+    pub other_content: bool,
+    /// other_content_checks counts the calls of has_other_content, which are slow on real clipboards
+    pub other_content_checks: u32,
+}
+
+#[cfg(test)]
+impl ClipboardBackend for MockBackend {
+    fn get_text(&mut self) -> Result<String, ClipboardError> {
+        if self.fail_reads {
+            return Err(ClipboardError::Read("mock clipboard is locked".to_string()));
+        }
+        self.text.clone().ok_or(ClipboardError::NoText)
+    }
+
+    fn set_text(&mut self, text: String) -> Result<(), ClipboardError> {
+        if self.fail_writes {
+            return Err(ClipboardError::Write(
+                "mock clipboard is read-only".to_string(),
+            ));
+        }
+        self.writes += 1;
+        self.text = Some(text);
+        // Setting the text replaces every other representation
+        self.html = None;
+        self.other_content = false;
+        Ok(())
+    }
+
+    fn get_html(&mut self) -> Option<String> {
+        self.html.clone()
+    }
+
+    fn set_text_and_html(&mut self, text: String, html: String) -> Result<(), ClipboardError> {
+        self.set_text(text)?;
+        self.html = Some(html);
+        Ok(())
+    }
+
+    fn source(&mut self) -> CopySource {
+        self.source
+    }
+
+    fn sequence_number(&mut self) -> Option<u32> {
+        self.sequence
+    }
+
+    fn has_other_content(&mut self) -> bool {
+        self.other_content_checks += 1;
+        self.other_content
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_backend_by_name() {
+        let mut backend = open_backend("mock").unwrap();
+        assert!(backend.get_text().is_err());
+        backend
+            .set_text("https://example.com/".to_string())
+            .unwrap();
+        assert_eq!(backend.get_text().unwrap(), "https://example.com/");
+
+        match open_backend("pbcopy") {
+            Err(ClipboardError::Unavailable(e)) => assert!(e.contains("unknown clipboard backend")),
+            _ => panic!("Unknown backend was opened"),
+        }
+    }
+
+    #[test]
+    fn test_is_no_text_message() {
+        assert!(is_no_text_message("Nothing is copied\n"));
+        assert!(is_no_text_message("No suitable type of content copied\n"));
+        assert!(is_no_text_message("Error: target STRING not available\n"));
+        assert!(!is_no_text_message(
+            "Failed to connect to a Wayland server\n"
+        ));
+        assert!(!is_no_text_message(""));
+    }
+
+    #[test]
+    fn test_change_events_wait() {
+        let (sender, receiver) = mpsc::channel();
+        let mut events = ChangeEvents {
+            receiver,
+            child: None,
+        };
+        sender.send(()).unwrap();
+        sender.send(()).unwrap();
+        assert!(events.wait(Duration::from_secs(5)).unwrap());
+        // Both changes were handled by the one check
+        assert!(!events.wait(Duration::from_millis(10)).unwrap());
+        drop(sender);
+        assert!(events.wait(Duration::from_millis(10)).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, error, info};
+
+/// ControlSocket accepts commands like `reload` on a Unix socket. It's polled from the main loop
+/// between clipboard checks, so commands never run concurrently with sanitizing.
+pub struct ControlSocket {
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl ControlSocket {
+    pub fn bind(path: &Path) -> io::Result<ControlSocket> {
+        // A socket left behind by a previous run that didn't shut down cleanly would make binding fail
+        if path.exists() && UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        info!("Listening for control commands on {:?}", path);
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+            listener,
+        })
+    }
+
+    /// poll answers every pending connection with the handler's response to the command it sent.
+    /// Each connection sends one command line and gets the response back before it's closed.
+    pub fn poll(&self, mut handler: impl FnMut(&str) -> String) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Failed to accept control connection: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = answer(stream, &mut handler) {
+                debug!("Failed to answer control command: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn answer(stream: UnixStream, handler: &mut impl FnMut(&str) -> String) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    // Don't let a client that never sends a command stall clipboard checks
+    stream.set_read_timeout(Some(Duration::from_millis(500)))?;
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+    let command = command.trim();
+    debug!("Control command: {}", command);
+    let mut response = handler(command);
+    if !response.ends_with('\n') {
+        response.push('\n');
+    }
+    (&stream).write_all(response.as_bytes())
+}
+
+/// send sends the command to the socket and returns the response
+pub fn send(path: &Path, command: &str) -> io::Result<String> {
+    use std::io::Read;
+
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_socket() {
+        let path = std::env::temp_dir().join(format!(
+            "clipboard-sanitizer-control-{}.sock",
+            std::process::id()
+        ));
+        let socket = ControlSocket::bind(&path).unwrap();
+        // Nothing is pending
+        socket.poll(|_| unreachable!());
+
+        let client = {
+            let path = path.clone();
+            std::thread::spawn(move || send(&path, "ping").unwrap())
+        };
+        let mut answered = false;
+        while !answered {
+            socket.poll(|command| {
+                answered = true;
+                format!("pong to {}", command)
+            });
+        }
+        assert_eq!(client.join().unwrap(), "pong to ping\n");
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,191 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::warn;
+use url::Url;
+
+use crate::metrics::{ErrorCategory, ErrorMetrics};
+
+/// How many finished expansions are remembered before they're forgotten
+const MAX_EXPANDED: usize = 1000;
+
+/// ExpandError is why a short link couldn't be followed to its destination
+#[derive(Debug, PartialEq)]
+pub enum ExpandError {
+    Timeout,
+    /// curl failed, e.g. because the host can't be reached or there were too many redirects
+    Failed(String),
+    /// The destination answered with a status that isn't 2xx, so it may not be the real destination
+    Status(String),
+}
+
+impl std::fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExpandError::Timeout => write!(f, "timed out"),
+            ExpandError::Failed(e) => write!(f, "{}", e),
+            ExpandError::Status(status) => write!(f, "destination answered with status {}", status),
+        }
+    }
+}
+
+/// Expansion is what's known about a short link's destination
+#[derive(Debug, PartialEq)]
+pub enum Expansion {
+    Expanded(Url),
+    Failed,
+    /// Pending links are being expanded on the worker thread
+    Pending,
+}
+
+/// Expander follows short links on a worker thread, so a slow shortener never holds up the clipboard checks.
+/// A link is expanded the first time it's asked for, and its destination is returned once the worker is done.
+pub struct Expander {
+    requests: Sender<Url>,
+    results: Mutex<Receiver<(Url, Result<Url, ExpandError>)>>,
+    /// expanded are the finished expansions by short link, None if expanding it failed
+    expanded: Mutex<HashMap<Url, Option<Url>>>,
+    pending: Mutex<HashSet<Url>>,
+}
+
+impl Expander {
+    /// new starts the worker thread. It stops when the expander is dropped.
+    pub fn new(timeout: Duration, max_redirects: u64) -> Expander {
+        let (requests, worker_requests) = channel::<Url>();
+        let (worker_results, results) = channel();
+        std::thread::spawn(move || {
+            for url in worker_requests {
+                let result = expand(&url, timeout, max_redirects);
+                if worker_results.send((url, result)).is_err() {
+                    break;
+                }
+            }
+        });
+        Expander {
+            requests,
+            results: Mutex::new(results),
+            expanded: Mutex::default(),
+            pending: Mutex::default(),
+        }
+    }
+
+    /// expansion returns the destination of the short link if it's been expanded, or starts expanding it
+    pub fn expansion(&self, url: &Url, errors: &ErrorMetrics) -> Expansion {
+        self.finished(errors);
+        match self.expanded.lock().unwrap().get(url) {
+            Some(Some(destination)) => return Expansion::Expanded(destination.clone()),
+            Some(None) => return Expansion::Failed,
+            None => {}
+        }
+        if self.pending.lock().unwrap().insert(url.clone())
+            && self.requests.send(url.clone()).is_err()
+        {
+            return Expansion::Failed;
+        }
+        Expansion::Pending
+    }
+
+    /// finished collects the expansions the worker has finished since the last call, and returns whether there
+    /// were any. Failures are logged and timeouts counted here, on the caller's thread.
+    pub fn finished(&self, errors: &ErrorMetrics) -> bool {
+        let results: Vec<_> = self.results.lock().unwrap().try_iter().collect();
+        if results.is_empty() {
+            return false;
+        }
+        let mut expanded = self.expanded.lock().unwrap();
+        let mut pending = self.pending.lock().unwrap();
+        if expanded.len() + results.len() > MAX_EXPANDED {
+            expanded.clear();
+        }
+        for (url, result) in results {
+            pending.remove(&url);
+            let destination = match result {
+                Ok(destination) => Some(destination),
+                Err(e) => {
+                    if e == ExpandError::Timeout {
+                        errors.increment(ErrorCategory::NetworkTimeout);
+                    }
+                    warn!(
+                        "Failed to expand {}, only its params are stripped: {}",
+                        url, e
+                    );
+                    None
+                }
+            };
+            expanded.insert(url, destination);
+        }
+        true
+    }
+}
+
+/// expand follows the redirects of a short link with a HEAD request by curl, which is on the PATH of
+/// most systems including Windows 10 and later. This is the only network access the sanitizer does.
+pub fn expand(url: &Url, timeout: Duration, max_redirects: u64) -> Result<Url, ExpandError> {
+    let null = if cfg!(windows) { "NUL" } else { "/dev/null" };
+    // The URL is passed in a config on stdin rather than as an argument, so the shell never sees it
+    let cmd = format!(
+        "curl --silent --head --location --max-redirs {} --output {} --write-out \"%{{http_code}} %{{url_effective}}\" --config -",
+        max_redirects, null
+    );
+    let quoted = url.as_str().replace('\\', "\\\\").replace('"', "\\\"");
+    let config = format!("url = \"{}\"\n", quoted);
+    match crate::run_command(&cmd, &config, timeout) {
+        Ok(output) => parse_output(&output),
+        Err(e) if e.starts_with("timed out") => Err(ExpandError::Timeout),
+        Err(e) => Err(ExpandError::Failed(format!("curl {}", e))),
+    }
+}
+
+/// parse_output parses the `status url` that curl writes out after following the redirects
+fn parse_output(output: &str) -> Result<Url, ExpandError> {
+    let (status, location) = output
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| ExpandError::Failed(format!("unexpected curl output {:?}", output)))?;
+    if !status.starts_with('2') {
+        return Err(ExpandError::Status(status.to_string()));
+    }
+    Url::parse(location).map_err(|e| ExpandError::Failed(format!("invalid destination: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_curl_output() {
+        assert_eq!(
+            parse_output("200 https://example.com/article?utm_source=x"),
+            Ok(Url::parse("https://example.com/article?utm_source=x").unwrap())
+        );
+        assert_eq!(
+            parse_output("404 https://example.com/gone"),
+            Err(ExpandError::Status("404".to_string()))
+        );
+        assert!(matches!(parse_output(""), Err(ExpandError::Failed(_))));
+        assert!(matches!(
+            parse_output("200 not a url"),
+            Err(ExpandError::Failed(_))
+        ));
+    }
+}
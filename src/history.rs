@@ -0,0 +1,278 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::error;
+
+/// Entry is one replacement of the clipboard content
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub original: String,
+    pub cleaned: String,
+}
+
+/// History remembers the last replacements of the clipboard content, newest first, so they can be listed and
+/// undone. With a file the last size replacements are also saved in it, and loaded from it at startup.
+/// Listing and undoing go through the control socket, so elsewhere than on Unix there's only the file.
+pub struct History {
+    entries: VecDeque<Entry>,
+    size: usize,
+    file: Option<PathBuf>,
+}
+
+impl History {
+    pub fn new(size: usize, file: Option<PathBuf>) -> History {
+        let mut history = History {
+            entries: VecDeque::new(),
+            size,
+            file: None,
+        };
+        if let Some(path) = &file {
+            match load(path) {
+                Ok(entries) => {
+                    for entry in entries {
+                        history.push(entry);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => error!("Failed to read history file {:?}: {}", path, e),
+            }
+        }
+        history.file = file;
+        history
+    }
+
+    /// configure changes how many entries are kept and where they're saved, keeping the current entries
+    pub fn configure(&mut self, size: usize, file: Option<PathBuf>) {
+        self.size = size;
+        self.entries.truncate(size);
+        self.file = file;
+    }
+
+    /// record adds a replacement of original with cleaned
+    pub fn record(&mut self, original: String, cleaned: String) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let entry = Entry {
+            time,
+            original,
+            cleaned,
+        };
+        if let Some(path) = &self.file {
+            if let Err(e) = append(path, &entry, self.size) {
+                error!("Failed to write history file {:?}: {}", path, e);
+            }
+        }
+        self.push(entry);
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.entries.push_front(entry);
+        self.entries.truncate(self.size);
+    }
+
+    /// last returns the newest replacement
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn last(&self) -> Option<&Entry> {
+        self.entries.front()
+    }
+
+    /// undo forgets the newest replacement, so the next undo goes back one more. The file is a log of the
+    /// replacements and keeps it.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn undo(&mut self) -> Option<Entry> {
+        self.entries.pop_front()
+    }
+
+    /// list returns a line per replacement, newest first, like `5m ago  original -> cleaned`
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn list(&self, now: u64) -> String {
+        self.entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{} ago  {} -> {}\n",
+                    ago(now.saturating_sub(entry.time)),
+                    escape(&entry.original),
+                    escape(&entry.cleaned)
+                )
+            })
+            .collect()
+    }
+}
+
+/// ago formats a number of seconds in its largest unit, like 5m or 2d
+#[cfg_attr(not(unix), allow(dead_code))]
+fn ago(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// append adds the entry to the file, which is rewritten with only the newest size entries so it doesn't grow
+/// forever. The file has a line per entry with its time, original and cleaned content separated by tabs.
+/// Backslashes, tabs and line breaks in the content are escaped like in Rust strings so multiline content fits on
+/// one line. The copied content can be private, so on Unix the file is created readable only by the user.
+fn append(path: &Path, entry: &Entry, size: usize) -> io::Result<()> {
+    let mut entries = match load(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e),
+    };
+    entries.push(entry.clone());
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    for entry in &entries[entries.len().saturating_sub(size)..] {
+        writeln!(
+            file,
+            "{}\t{}\t{}",
+            entry.time,
+            escape(&entry.original),
+            escape(&entry.cleaned)
+        )?;
+    }
+    Ok(())
+}
+
+/// load reads the entries in the file, oldest first. Lines that aren't entries are skipped.
+fn load(path: &Path) -> io::Result<Vec<Entry>> {
+    let file = std::fs::File::open(path)?;
+    let mut entries = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let mut fields = line.splitn(3, '\t');
+        let (Some(time), Some(original), Some(cleaned)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let Ok(time) = time.parse() else {
+            continue;
+        };
+        entries.push(Entry {
+            time,
+            original: unescape(original),
+            cleaned: unescape(cleaned),
+        });
+    }
+    Ok(entries)
+}
+
+fn escape(content: &str) -> String {
+    let mut escaped = String::with_capacity(content.len());
+    for c in content.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(escaped: &str) -> String {
+    let mut content = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            content.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => content.push('\t'),
+            Some('n') => content.push('\n'),
+            Some('r') => content.push('\r'),
+            Some(c) => content.push(c),
+            None => content.push('\\'),
+        }
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let path = std::env::temp_dir().join(format!(
+            "clipboard-sanitizer-history-{}.txt",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let mut history = History::new(2, Some(path.clone()));
+        assert_eq!(history.last(), None);
+        history.record("a?utm_source=x".to_string(), "a".to_string());
+        history.record("b\\c\n\td?gclid=1".to_string(), "b\\c\n\td".to_string());
+        history.record("e?fbclid=1".to_string(), "e".to_string());
+        let time = history.last().unwrap().time;
+        assert_eq!(
+            history.list(time + 90),
+            "1m ago  e?fbclid=1 -> e\n1m ago  b\\\\c\\n\\td?gclid=1 -> b\\\\c\\n\\td\n"
+        );
+
+        assert_eq!(history.undo().unwrap().original, "e?fbclid=1");
+        assert_eq!(history.last().unwrap().original, "b\\c\n\td?gclid=1");
+
+        // The file keeps the last replacements, also the undone one, and they're loaded from it
+        let loaded = History::new(2, Some(path.clone()));
+        assert_eq!(loaded.entries.len(), 2);
+        assert_eq!(loaded.entries[1].cleaned, "b\\c\n\td");
+        assert_eq!(loaded.last().unwrap().original, "e?fbclid=1");
+        assert_eq!(load(&path).unwrap().len(), 2);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        history.configure(1, None);
+        assert_eq!(
+            history.list(time),
+            "0s ago  b\\\\c\\n\\td?gclid=1 -> b\\\\c\\n\\td\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_ago() {
+        assert_eq!(ago(5), "5s");
+        assert_eq!(ago(3599), "59m");
+        assert_eq!(ago(7200), "2h");
+        assert_eq!(ago(200000), "2d");
+    }
+}
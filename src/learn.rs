@@ -0,0 +1,146 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use log::{error, info};
+
+/// How many times the user has to restore a param on a domain before it's no longer stripped there
+const LEARN_AFTER_OVERRIDES: u32 = 2;
+
+/// Learner turns params the user keeps restoring by copying the original URL again into learned
+/// exceptions that are kept on that domain from then on. Learned exceptions are saved to a state file
+/// with one `domain param` pair per line.
+#[derive(Debug, Default)]
+pub struct Learner {
+    path: Option<PathBuf>,
+    overrides: HashMap<(String, String), u32>,
+    learned: Vec<(String, String)>,
+}
+
+impl Learner {
+    /// load reads the exceptions learned earlier from the state file, if there is one
+    pub fn load(path: Option<PathBuf>) -> Learner {
+        let mut learner = Learner {
+            path,
+            ..Default::default()
+        };
+        let Some(path) = &learner.path else {
+            return learner;
+        };
+        match std::fs::read_to_string(path) {
+            Ok(state) => {
+                for line in state.lines() {
+                    if let Some((domain, param)) = line.trim().split_once(' ') {
+                        learner
+                            .learned
+                            .push((domain.to_ascii_lowercase(), param.to_string()));
+                    }
+                }
+                info!(
+                    "Loaded {} learned exceptions from {:?}",
+                    learner.learned.len(),
+                    path
+                );
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to read learned exceptions from {:?}: {}", path, e),
+        }
+        learner
+    }
+
+    /// keep_params returns the learned exceptions for the domain
+    pub fn keep_params<'a>(&'a self, domain: &'a str) -> impl Iterator<Item = &'a String> {
+        self.learned
+            .iter()
+            .filter(move |(learned_domain, _)| learned_domain == domain)
+            .map(|(_, param)| param)
+    }
+
+    /// record_override counts the params the rules removed from original when the user copied it again.
+    /// Params restored often enough on the domain are learned and saved.
+    pub fn record_override(&mut self, original: &url::Url, removed: &[String]) {
+        let Some(domain) = original.domain() else {
+            return;
+        };
+        let mut learned_any = false;
+        // Params removed from a URL a redirect was unwrapped to aren't in the original query and aren't learned
+        for (key, _) in original.query_pairs() {
+            if !removed.iter().any(|param| *param == key) {
+                continue;
+            }
+            let exception = (domain.to_string(), key.to_string());
+            if self.learned.contains(&exception) {
+                continue;
+            }
+            let count = self.overrides.entry(exception.clone()).or_default();
+            *count += 1;
+            if *count >= LEARN_AFTER_OVERRIDES {
+                info!("Learned to keep {} on {}", exception.1, exception.0);
+                self.overrides.remove(&exception);
+                self.learned.push(exception);
+                learned_any = true;
+            }
+        }
+        if learned_any {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let state: String = self
+            .learned
+            .iter()
+            .map(|(domain, param)| format!("{} {}\n", domain, param))
+            .collect();
+        if let Err(e) = std::fs::write(path, state) {
+            error!("Failed to save learned exceptions to {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_learned_exceptions_are_saved() {
+        let path = std::env::temp_dir().join(format!(
+            "clipboard-sanitizer-learned-{}.txt",
+            std::process::id()
+        ));
+        let mut learner = Learner::load(Some(path.clone()));
+        let original = url::Url::parse("https://example.com/?ref=a&utm_source=b&id=1").unwrap();
+        let removed = ["ref".to_string(), "utm_source".to_string()];
+        learner.record_override(&original, &removed);
+        assert!(!path.exists());
+        learner.record_override(&original, &removed);
+
+        let learner = Learner::load(Some(path.clone()));
+        let mut learned: Vec<&String> = learner.keep_params("example.com").collect();
+        learned.sort();
+        assert_eq!(learned, vec!["ref", "utm_source"]);
+        assert_eq!(learner.keep_params("other.com").count(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,4253 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Strips tracking parameters from URLs and from text containing URLs.
+//!
+//! Build a [`Sanitizer`] from the settings in the config file with [`Sanitizer::from_config`], then clean URLs
+//! with [`Sanitizer::sanitize`] or text with [`Sanitizer::sanitize_text`].
+
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use dirs::config_dir;
+use log::{debug, error, info, warn};
+use url::Url;
+
+pub mod batch;
+pub mod clearurls;
+mod expand;
+mod learn;
+pub mod metrics;
+mod params;
+pub mod pipeline;
+pub mod settings;
+
+use metrics::{ErrorCategory, ErrorMetrics, TrackingStats};
+use params::{RuleParams, TrackingParams, TrackingScore};
+use pipeline::{Pipeline, Step, Transform};
+use settings::Settings;
+
+const EXCLUDED_SCHEMES: [&str; 3] = ["data", "blob", "javascript"];
+/// Link shorteners whose links hide the destination URL and its tracking
+const SHORTENER_DOMAINS: [&str; 10] = [
+    "bit.ly",
+    "t.co",
+    "tinyurl.com",
+    "goo.gl",
+    "ow.ly",
+    "buff.ly",
+    "lnkd.in",
+    "amzn.to",
+    "trib.al",
+    "dlvr.it",
+];
+/// Characters that commonly follow URLs in text but are rarely the last character of one
+const DEFAULT_TRIM_TRAILING_CHARS: &str = ".,;:!?)]>\"'";
+/// Params that only news sites' analytics put in the fragment, stripped from fragments besides the common params.
+/// xtor is AT Internet's campaign tag, like #xtor=RSS-3208.
+const DEFAULT_FRAGMENT_PARAMS: [&str; 1] = ["xtor"];
+/// Redirect wrappers that put the destination URL in a query param: domain, path and param.
+/// The domain also matches its subdomains, e.g. the regional Outlook safelinks hosts.
+const REDIRECTORS: [(&str, &str, &str); 8] = [
+    ("google.com", "/url", "q"),
+    ("safelinks.protection.outlook.com", "/", "url"),
+    ("l.facebook.com", "/l.php", "u"),
+    ("lm.facebook.com", "/l.php", "u"),
+    ("l.instagram.com", "/", "u"),
+    ("youtube.com", "/redirect", "q"),
+    ("duckduckgo.com", "/l/", "uddg"),
+    ("slack-redir.net", "/link", "url"),
+];
+/// Names of the rules that DISABLED_RULES can turn off. A disabled site rule falls through to the common rule.
+const RULE_NAMES: [&str; 9] = [
+    "youtube",
+    "twitter",
+    "facebook",
+    "instagram",
+    "google",
+    "amazon",
+    "calendar",
+    "zoom",
+    "common",
+];
+/// How many times a rule may rewrite a URL into one that is matched by another rule
+const MAX_REWRITE_DEPTH: usize = 4;
+
+#[derive(Default)]
+pub struct Sanitizer {
+    youtube_prefixes: Vec<String>,
+    canonical_encode: bool,
+    process_tabular: bool,
+    keep_params: Vec<(String, Vec<String>)>,
+    /// keep_params_everywhere are never stripped from any domain
+    keep_params_everywhere: Vec<String>,
+    /// domain_rules are the rules of the RULES and DOMAINS tables, most specific first
+    domain_rules: Vec<DomainRule>,
+    post_process_cmd: Option<String>,
+    post_process_timeout: Duration,
+    process_magnet: bool,
+    magnet_strip_params: TrackingParams,
+    shortener_action: ShortenerAction,
+    /// shortener_domains are SHORTENER_DOMAINS with EXTRA_SHORTENER_DOMAINS applied
+    shortener_domains: Vec<String>,
+    /// expander follows short links in the background if SHORTENER_ACTION is expand
+    expander: Option<expand::Expander>,
+    multi_url_mode: MultiUrlMode,
+    process_ip_hosts: bool,
+    trim_trailing_chars: Vec<char>,
+    strip_hashbang_params: bool,
+    verify_idempotent: bool,
+    process_mailto: bool,
+    unwrap_redirects: bool,
+    /// strip_nested_urls strips the URLs in param values, like the destination of a redirect, in place
+    strip_nested_urls: bool,
+    /// strip_fragments strips scroll-to-text directives and tracking params like xtor= from fragments
+    strip_fragments: bool,
+    /// keep_text_fragments keeps the #:~:text= scroll-to-text directives when stripping fragments
+    keep_text_fragments: bool,
+    /// fragment_params are the params stripped from fragments that look like a query, like #xtor=RSS-1
+    fragment_params: TrackingParams,
+    /// deamp rewrites AMP links to the page they're a copy of
+    deamp: bool,
+    /// twitter_host is the host twitter.com and x.com links are rewritten to, if set
+    twitter_host: Option<String>,
+    /// amazon_canonical rewrites Amazon product links to www.amazon.<tld>/dp/<ASIN>
+    amazon_canonical: bool,
+    /// desktop_hosts drops the m. and mobile. labels of mobile sites
+    desktop_hosts: bool,
+    /// host_rewrites are the HOST_REWRITES domains and what they're replaced with, longest first
+    host_rewrites: Vec<(String, String)>,
+    process_protocol_relative: bool,
+    /// only_domains limits stripping to these domains and their subdomains if it isn't empty
+    only_domains: Vec<String>,
+    /// skip_domains are never stripped, even if they're in only_domains
+    skip_domains: Vec<String>,
+    /// disabled_rules are the names from RULE_NAMES that are skipped
+    disabled_rules: Vec<String>,
+    max_unwrap_hops: usize,
+    /// learner is set if LEARN_EXCEPTIONS is enabled
+    learner: Option<Mutex<learn::Learner>>,
+    /// clearurls is the ClearURLs ruleset from CLEARURLS_FILE, if there is one
+    clearurls: Option<clearurls::ClearUrls>,
+    params: RuleParams,
+    /// pipeline is the order of the stages from PIPELINE and the custom transforms
+    pipeline: Pipeline,
+    errors: Arc<ErrorMetrics>,
+    tracking: Arc<TrackingStats>,
+}
+
+impl Sanitizer {
+    /// from_config builds a sanitizer from the settings in the config file. See config.toml for the settings.
+    pub fn from_config(config: &Settings) -> Sanitizer {
+        let mut magnet_strip_params = TrackingParams::default();
+        magnet_strip_params.merge(&parse_list(
+            &settings::read(config, "MAGNET_STRIP_PARAMS").unwrap_or_else(|| "tr".to_string()),
+        ));
+        let strip_tracking = settings::read_bool(config, "STRIP_TRACKING", true);
+        let strip_referral = settings::read_bool(config, "STRIP_REFERRAL", false);
+        let mut params = RuleParams::new(strip_tracking, strip_referral);
+        let mut domain_rules = parse_domain_rules(settings::read_table(config, "DOMAINS"));
+        // "*" in DOMAINS is another name for DEFAULT_PARAMS
+        let default_params = settings::read(config, "DEFAULT_PARAMS").or_else(|| {
+            let index = domain_rules.iter().position(|(domain, _)| domain == "*")?;
+            Some(domain_rules.remove(index).1)
+        });
+        if let Some(default_params) = default_params {
+            params.set_common_params(&parse_list(&default_params));
+        }
+        let extra_params =
+            parse_list(&settings::read(config, "EXTRA_TRACKING_PARAMS").unwrap_or_default());
+        params.common.merge(&extra_params);
+        // Params the user chose to strip are clearly tracking, but the protected params of the conservative
+        // rules are still kept
+        params.conservative.merge(&extra_params);
+        if let Some(prefixes) =
+            settings::read(config, "TRACKING_PREFIXES").filter(|_| strip_tracking)
+        {
+            params.common.set_prefixes(&parse_list(&prefixes));
+        }
+        params.common.add_patterns(&parse_list(
+            &settings::read(config, "STRIP_PATTERNS").unwrap_or_default(),
+        ));
+        if settings::read_bool(config, "STRIP_REF_PREFIX", false) {
+            params.common.add_prefix("ref_");
+        }
+        let ignore_case = settings::read_bool(config, "IGNORE_PARAM_CASE", true);
+        let shortener_action =
+            ShortenerAction::from_setting(settings::read(config, "SHORTENER_ACTION"));
+        params.set_ignore_case(ignore_case);
+        magnet_strip_params.set_ignore_case(ignore_case);
+        let mut domain_rules = DomainRule::from_tables(
+            settings::read_table(config, "RULES"),
+            domain_rules,
+            &params.common,
+        );
+        for rule in &mut domain_rules {
+            rule.strip.set_ignore_case(ignore_case);
+        }
+        let mut fragment_params = params.common.clone();
+        fragment_params.extend(&TrackingParams::from_defaults(&DEFAULT_FRAGMENT_PARAMS));
+        fragment_params.merge(&parse_list(
+            &settings::read(config, "FRAGMENT_PARAMS").unwrap_or_default(),
+        ));
+        Sanitizer {
+            youtube_prefixes: parse_prefixes(settings::read(config, "YOUTUBE_PREFIXES")),
+            canonical_encode: settings::read_bool(config, "CANONICAL_ENCODE", false),
+            process_tabular: settings::read_bool(config, "PROCESS_TABULAR", false),
+            keep_params: parse_domain_params(settings::read(config, "DOMAIN_KEEP_PARAMS")),
+            keep_params_everywhere: parse_list(
+                &settings::read(config, "KEEP_PARAMS").unwrap_or_default(),
+            ),
+            domain_rules,
+            post_process_cmd: settings::read(config, "POST_PROCESS_CMD")
+                .filter(|cmd| !cmd.is_empty()),
+            post_process_timeout: Duration::from_millis(settings::read_u64(
+                config,
+                "POST_PROCESS_TIMEOUT_MS",
+                1000,
+            )),
+            process_magnet: settings::read_bool(config, "PROCESS_MAGNET", false),
+            magnet_strip_params,
+            shortener_action,
+            shortener_domains: parse_shortener_domains(settings::read(
+                config,
+                "EXTRA_SHORTENER_DOMAINS",
+            )),
+            expander: (shortener_action == ShortenerAction::Expand).then(|| {
+                expand::Expander::new(
+                    Duration::from_millis(settings::read_u64(config, "EXPAND_TIMEOUT_MS", 2000)),
+                    settings::read_u64(config, "EXPAND_MAX_REDIRECTS", 5),
+                )
+            }),
+            multi_url_mode: MultiUrlMode::from_setting(settings::read(config, "MULTI_URL_MODE")),
+            process_ip_hosts: settings::read_bool(config, "PROCESS_IP_HOSTS", false),
+            strip_hashbang_params: settings::read_bool(config, "STRIP_HASHBANG_PARAMS", false),
+            verify_idempotent: settings::read_bool(config, "VERIFY_IDEMPOTENT", false),
+            process_mailto: settings::read_bool(config, "PROCESS_MAILTO", false),
+            unwrap_redirects: settings::read_bool(config, "UNWRAP_REDIRECTS", false),
+            strip_nested_urls: settings::read_bool(config, "STRIP_NESTED_URLS", false),
+            strip_fragments: settings::read_bool(config, "STRIP_FRAGMENTS", false),
+            keep_text_fragments: settings::read_bool(config, "KEEP_TEXT_FRAGMENTS", false),
+            fragment_params,
+            deamp: settings::read_bool(config, "DEAMP", false),
+            amazon_canonical: settings::read_bool(config, "AMAZON_CANONICAL", false),
+            twitter_host: parse_twitter_host(settings::read(config, "TWITTER_HOST")),
+            desktop_hosts: settings::read_bool(config, "DESKTOP_HOSTS", false),
+            host_rewrites: parse_host_rewrites(settings::read_table(config, "HOST_REWRITES")),
+            process_protocol_relative: settings::read_bool(
+                config,
+                "PROCESS_PROTOCOL_RELATIVE",
+                false,
+            ),
+            only_domains: parse_domains(settings::read(config, "ONLY_DOMAINS")),
+            skip_domains: parse_domains(settings::read(config, "SKIP_DOMAINS")),
+            disabled_rules: parse_disabled_rules(settings::read(config, "DISABLED_RULES")),
+            pipeline: Pipeline::from_names(&parse_list(
+                &settings::read(config, "PIPELINE").unwrap_or_default(),
+            )),
+            max_unwrap_hops: settings::read_u64(config, "MAX_UNWRAP_HOPS", 5) as usize,
+            trim_trailing_chars: settings::read(config, "TRIM_TRAILING_CHARS")
+                .unwrap_or_else(|| DEFAULT_TRIM_TRAILING_CHARS.to_string())
+                .chars()
+                .collect(),
+            learner: settings::read_bool(config, "LEARN_EXCEPTIONS", false).then(|| {
+                let path = settings::read(config, "LEARNED_EXCEPTIONS_FILE")
+                    .filter(|path| !path.is_empty())
+                    .map(std::path::PathBuf::from)
+                    .or_else(|| {
+                        config_dir().map(|dir| {
+                            dir.join("clipboard-sanitizer")
+                                .join("learned_exceptions.txt")
+                        })
+                    });
+                Mutex::new(learn::Learner::load(path))
+            }),
+            clearurls: clearurls::load_configured(config, strip_referral),
+            params,
+            errors: Arc::default(),
+            tracking: Arc::default(),
+        }
+    }
+
+    /// errors are the error counters of everything this sanitizer did
+    pub fn errors(&self) -> &ErrorMetrics {
+        &self.errors
+    }
+
+    /// tracking has the stats of the params this sanitizer stripped
+    pub fn tracking(&self) -> &TrackingStats {
+        &self.tracking
+    }
+
+    /// with_transform adds a custom stage to the pipeline. It runs where PIPELINE lists its name, or last.
+    pub fn with_transform(mut self, transform: impl Transform + 'static) -> Sanitizer {
+        self.pipeline.add(Box::new(transform));
+        self
+    }
+
+    /// with_stats_of shares the error counters and tracking stats of a previous sanitizer, e.g. after a reload
+    pub fn with_stats_of(self, previous: &Sanitizer) -> Sanitizer {
+        Sanitizer {
+            errors: previous.errors.clone(),
+            tracking: previous.tracking.clone(),
+            ..self
+        }
+    }
+
+    /// parse_text returns the URL if the text is a URL with a domain name that isn't using an excluded scheme.
+    /// Magnet and mailto: links and URLs with IP address hosts are also returned if they are enabled.
+    pub fn parse_text(&self, potential_url: &str) -> Option<url::Url> {
+        if let Some(scheme) = excluded_scheme(potential_url) {
+            // These can be huge and are never tracking URLs, so don't parse or log their contents
+            debug!(
+                "Skipping clipboard content with excluded scheme: {}",
+                scheme
+            );
+            return None;
+        }
+        // The url crate would percent-encode whitespace inside the text, but text with several words isn't a URL
+        if potential_url.trim().contains(char::is_whitespace) {
+            return None;
+        }
+        if let Ok(url) = Url::parse(potential_url) {
+            debug!("Found URL: {}", url);
+            if url.domain().is_some()
+                || (self.process_magnet && url.scheme() == "magnet")
+                || (self.process_mailto && url.scheme() == "mailto")
+                || (self.process_ip_hosts && is_ip_host(&url))
+            {
+                return Some(url);
+            }
+        } else {
+            debug!("Clipboard content is not a URL: {}", potential_url);
+        }
+        None
+    }
+
+    /// sanitize_text strips tracking from every whitespace-separated URL in the text, or just the first one.
+    /// Everything else in the text, including URLs with nothing to strip, is left as it is.
+    pub fn sanitize_text(&self, text: &str, first_only: bool) -> String {
+        self.sanitize_words(text, first_only, false)
+    }
+
+    /// sanitize_words is sanitize_text, also adding what was stripped to the tracking stats with record
+    fn sanitize_words(&self, text: &str, first_only: bool, record: bool) -> String {
+        let mut sanitized = String::with_capacity(text.len());
+        let mut rest = text;
+        while !rest.is_empty() {
+            let word_start = rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+            sanitized.push_str(&rest[..word_start]);
+            rest = &rest[word_start..];
+            let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (opening_quote, word, trailing) = self.split_word(&rest[..word_end]);
+            rest = &rest[word_end..];
+            sanitized.push_str(opening_quote);
+            let cleaned = match self.parse_text(word) {
+                Some(url) => {
+                    let stripped_url = self.sanitize_url(&url, record);
+                    Some(if stripped_url != url {
+                        stripped_url.to_string()
+                    } else {
+                        word.to_string()
+                    })
+                }
+                None => self.sanitize_protocol_relative(word, record),
+            };
+            match cleaned {
+                Some(cleaned) => {
+                    sanitized.push_str(&cleaned);
+                    sanitized.push_str(trailing);
+                    if first_only {
+                        sanitized.push_str(rest);
+                        break;
+                    }
+                }
+                None => {
+                    sanitized.push_str(word);
+                    sanitized.push_str(trailing);
+                }
+            }
+        }
+        sanitized
+    }
+
+    /// sanitize_protocol_relative cleans a protocol-relative URL like //example.com/path, as found in HTML attributes,
+    /// by parsing it with an https: scheme. Returns the URL without the scheme again, unchanged if there was
+    /// nothing to strip, or None if the text isn't a protocol-relative URL or PROCESS_PROTOCOL_RELATIVE is off.
+    fn sanitize_protocol_relative(&self, text: &str, record: bool) -> Option<String> {
+        if !self.process_protocol_relative || !text.starts_with("//") || text.starts_with("///") {
+            return None;
+        }
+        let url = self.parse_text(&format!("https:{}", text))?;
+        let stripped_url = self.sanitize_url(&url, record);
+        if stripped_url == url {
+            return Some(text.to_string());
+        }
+        match stripped_url.as_str().strip_prefix("https:") {
+            Some(relative) => Some(relative.to_string()),
+            // A rule like post-processing changed the scheme, so the URL can't be made protocol-relative again
+            None => Some(stripped_url.to_string()),
+        }
+    }
+
+    /// split_word splits punctuation that surrounds URLs in text, like a full stop or closing bracket,
+    /// from the end of the word so it isn't parsed as part of the URL. An opening quote is split off
+    /// the start if the trailing characters close it, and so is anything before an http(s) URL that
+    /// ends in punctuation, like an opening bracket or `link:`. Text after the end of the URL, like a closing
+    /// curly quote or `|`, is trailing too. Returns the leading text, URL and trailing characters.
+    fn split_word<'a>(&self, word: &'a str) -> (&'a str, &'a str, &'a str) {
+        let url = word.trim_end_matches(self.trim_trailing_chars.as_slice());
+        let (leading, url) = match url.chars().next() {
+            Some(quote @ ('"' | '\'')) if word[url.len()..].starts_with(quote) => {
+                (&url[..1], &url[1..])
+            }
+            _ => match embedded_url_start(url) {
+                Some(start) => (&url[..start], &url[start..]),
+                None => ("", url),
+            },
+        };
+        let url = url[..url_end(url)].trim_end_matches(self.trim_trailing_chars.as_slice());
+        (leading, url, &word[leading.len() + url.len()..])
+    }
+
+    /// sanitize_clipboard returns the new clipboard content if anything in it was sanitized, and adds what was
+    /// stripped to the tracking stats. Sanitized URLs are serialized by the url crate, which always drops an
+    /// explicit default port like `:443` on https. Content with nothing to strip isn't rewritten, so its port is
+    /// never touched.
+    pub fn sanitize_clipboard(&self, content: &str) -> Option<String> {
+        self.sanitize_content(content, true)
+    }
+
+    /// expansions_finished returns whether short links have been expanded in the background since the last call.
+    /// Clipboard content with short links in it can then be sanitized again with sanitize_expanded.
+    pub fn expansions_finished(&self) -> bool {
+        self.expander
+            .as_ref()
+            .is_some_and(|expander| expander.finished(&self.errors))
+    }
+
+    /// sanitize_expanded is sanitize_clipboard for content that was already sanitized before its short links
+    /// were expanded, so what was stripped isn't added to the tracking stats again
+    pub fn sanitize_expanded(&self, content: &str) -> Option<String> {
+        self.sanitize_content(content, false)
+    }
+
+    fn sanitize_content(&self, content: &str, record: bool) -> Option<String> {
+        if let Some(url) = self.parse_text(content) {
+            let stripped_url = self.sanitize_url(&url, record);
+            if stripped_url != url {
+                return Some(stripped_url.to_string());
+            }
+            return None;
+        }
+        if let Some(cleaned) = self.sanitize_protocol_relative(content, record) {
+            return (cleaned != content).then_some(cleaned);
+        }
+        // A string literal copied from code or JSON keeps its quotes
+        if let Some((quote, inner)) = unquote(content) {
+            if let Some(url) = self.parse_text(inner) {
+                let stripped_url = self.sanitize_url(&url, record);
+                if stripped_url != url {
+                    return Some(format!("{}{}{}", quote, stripped_url, quote));
+                }
+                return None;
+            }
+            if let Some(cleaned) = self.sanitize_protocol_relative(inner, record) {
+                return (cleaned != inner).then(|| format!("{}{}{}", quote, cleaned, quote));
+            }
+        }
+        if self.process_tabular {
+            if let Some(sanitized) = self.sanitize_tabular(content, record) {
+                return Some(sanitized);
+            }
+        }
+        let first_only = match self.multi_url_mode {
+            MultiUrlMode::All => false,
+            MultiUrlMode::First => true,
+            MultiUrlMode::Off => return None,
+        };
+        let sanitized = self.sanitize_words(content, first_only, record);
+        if sanitized != content {
+            return Some(sanitized);
+        }
+        None
+    }
+
+    /// sanitize_html cleans the URLs in the href attributes and text of an HTML clipboard, like the one browsers
+    /// put next to the text when copying a link. Anything with a character reference other than &amp; is left alone,
+    /// since it can't be cleaned without fully decoding the HTML.
+    pub fn sanitize_html(&self, html: &str) -> String {
+        let mut sanitized = String::with_capacity(html.len());
+        let mut rest = html;
+        while let Some(start) = rest.find('<') {
+            sanitized.push_str(&self.sanitize_html_value(&rest[..start]));
+            let (tag, length) = self.sanitize_html_tag(&rest[start..]);
+            sanitized.push_str(&tag);
+            rest = &rest[start + length..];
+        }
+        sanitized.push_str(&self.sanitize_html_value(rest));
+        sanitized
+    }
+
+    /// sanitize_html_tag cleans the URLs in the href attributes of the tag html starts with, and returns the cleaned
+    /// tag and how long it was. The attributes are parsed, so a > in a quoted value doesn't end the tag and other
+    /// attributes like data-href are left alone. Comments are returned unchanged.
+    fn sanitize_html_tag(&self, html: &str) -> (String, usize) {
+        if html.starts_with("<!--") {
+            let end = html.find("-->").map_or(html.len(), |end| end + "-->".len());
+            return (html[..end].to_string(), end);
+        }
+        let bytes = html.as_bytes();
+        let skip = |mut i: usize, stop: fn(u8) -> bool| {
+            while i < bytes.len() && !stop(bytes[i]) {
+                i += 1;
+            }
+            i
+        };
+        let mut sanitized = String::new();
+        let mut copied = 0;
+        // The tag's name ends where its attributes start
+        let mut i = skip(1, |b| b.is_ascii_whitespace() || b == b'>');
+        let end = loop {
+            i = skip(i, |b| !b.is_ascii_whitespace());
+            match bytes.get(i) {
+                None => break bytes.len(),
+                Some(b'>') => break i + 1,
+                Some(b'/') => {
+                    i += 1;
+                    continue;
+                }
+                Some(_) => {}
+            }
+            let name_start = i;
+            i = skip(i, |b| {
+                b.is_ascii_whitespace() || matches!(b, b'=' | b'>' | b'/')
+            });
+            if i == name_start {
+                i += 1;
+                continue;
+            }
+            let name = &html[name_start..i];
+            let after_name = skip(i, |b| !b.is_ascii_whitespace());
+            if bytes.get(after_name) != Some(&b'=') {
+                continue;
+            }
+            let value_start = skip(after_name + 1, |b| !b.is_ascii_whitespace());
+            let (value_start, value_end) = match bytes.get(value_start) {
+                Some(&quote @ (b'"' | b'\'')) => {
+                    let value_end = html[value_start + 1..]
+                        .find(quote as char)
+                        .map_or(bytes.len(), |length| value_start + 1 + length);
+                    i = value_end + 1;
+                    (value_start + 1, value_end)
+                }
+                _ => {
+                    i = skip(value_start, |b| b.is_ascii_whitespace() || b == b'>');
+                    (value_start, i)
+                }
+            };
+            if name.eq_ignore_ascii_case("href") {
+                sanitized.push_str(&html[copied..value_start]);
+                sanitized.push_str(&self.sanitize_html_value(&html[value_start..value_end]));
+                copied = value_end;
+            }
+        };
+        let end = end.min(bytes.len());
+        sanitized.push_str(&html[copied..end]);
+        (sanitized, end)
+    }
+
+    /// sanitize_html_value cleans an attribute value or text between tags like clipboard content
+    fn sanitize_html_value(&self, value: &str) -> String {
+        let decoded = value.replace("&amp;", "&");
+        if decoded.trim().is_empty()
+            || decoded.matches('&').count() != value.matches("&amp;").count()
+        {
+            return value.to_string();
+        }
+        match self.sanitize_clipboard(&decoded) {
+            Some(sanitized) => sanitized.replace('&', "&amp;"),
+            None => value.to_string(),
+        }
+    }
+
+    /// sanitize_tabular cleans the URLs in the cells of tab or comma separated content, e.g. copied from a spreadsheet.
+    /// The content is only rewritten if a URL changed, and only the URLs are replaced, so the quoting and spacing of
+    /// the cells stay as they were.
+    fn sanitize_tabular(&self, content: &str, record: bool) -> Option<String> {
+        let delimiter = if content.contains('\t') {
+            b'\t'
+        } else if content.contains(',') {
+            b','
+        } else {
+            return None;
+        };
+
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .has_headers(false)
+            .from_reader(content.as_bytes());
+        let mut records = vec![];
+        for record in reader.records() {
+            match record {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    debug!("Clipboard content is not tabular: {}", e);
+                    return None;
+                }
+            }
+        }
+        if records.iter().all(|record| record.len() < 2) {
+            return None;
+        }
+
+        let mut sanitized = String::with_capacity(content.len());
+        let mut copied = 0;
+        for span in cell_spans(content, delimiter) {
+            let cell = &content[span.clone()];
+            let trimmed = cell.trim();
+            let Some(url) = self.parse_text(trimmed) else {
+                continue;
+            };
+            let stripped_url = self.sanitize_url(&url, record);
+            if stripped_url == url {
+                continue;
+            }
+            let start = span.start + (cell.len() - cell.trim_start().len());
+            sanitized.push_str(&content[copied..start]);
+            sanitized.push_str(stripped_url.as_str());
+            copied = start + trimmed.len();
+        }
+        if copied == 0 {
+            return None;
+        }
+        sanitized.push_str(&content[copied..]);
+        Some(sanitized)
+    }
+
+    /// sanitize strips tracking from the URL and applies the enabled output options
+    pub fn sanitize(&self, url: &url::Url) -> url::Url {
+        self.sanitize_url(url, false)
+    }
+
+    /// sanitize_url is sanitize, also adding the params the rules removed to the tracking stats with record. Only
+    /// clipboard content is recorded, so URLs checked again, like by --explain or --summary, aren't counted twice.
+    fn sanitize_url(&self, url: &url::Url, record: bool) -> url::Url {
+        let mut trace = Trace::default();
+        let stripped_url = self.sanitize_traced(url, &mut trace);
+        if record && stripped_url != *url && matches!(url.scheme(), "http" | "https") {
+            let mut score = TrackingScore::default();
+            for param in &trace.removed {
+                score.add(param);
+            }
+            if score.score() > 0 {
+                let domain = url.host_str().unwrap_or_default();
+                info!("Tracking score {} for {}", score, domain);
+                self.tracking.record(domain, &score);
+            }
+        }
+        stripped_url
+    }
+
+    /// explain sanitizes the text like clipboard content and returns every decision made along the way
+    pub fn explain(&self, text: &str) -> Trace {
+        let mut trace = Trace::enabled();
+        if let Some(scheme) = excluded_scheme(text) {
+            trace.record(Stage::Exclusion, || {
+                format!("{}: URIs are never processed", scheme)
+            });
+            return trace;
+        }
+        match self.parse_text(text) {
+            Some(url) => {
+                let result = self.sanitize_traced(&url, &mut trace);
+                trace.result = Some(result);
+            }
+            None => trace.record(Stage::Exclusion, || {
+                "not a URL with a domain name, left unchanged".to_string()
+            }),
+        }
+        trace
+    }
+
+    fn sanitize_traced(&self, url: &url::Url, trace: &mut Trace) -> url::Url {
+        let original = url;
+        let unwrapped_url;
+        let url = if self.unwrap_redirects {
+            unwrapped_url = unwrap_chain(url, self.max_unwrap_hops, unwrap_redirect, trace);
+            &unwrapped_url
+        } else {
+            url
+        };
+        let mut stripped_url = self.strip_tracking(url, trace);
+        if self.verify_idempotent {
+            self.check_idempotent(url, &stripped_url);
+        }
+        if self.strip_hashbang_params {
+            let keep = stripped_url
+                .host_str()
+                .map(|host| self.keep_params_for(host))
+                .unwrap_or_default();
+            let hashbang_url = strip_hashbang_params(&stripped_url, &self.params.common, &keep);
+            if hashbang_url != stripped_url {
+                trace.record(Stage::Hashbang, || {
+                    format!("{} -> {}", stripped_url, hashbang_url)
+                });
+                stripped_url = hashbang_url;
+            }
+        }
+        if self.canonical_encode {
+            let canonical_url = canonical_encode(&stripped_url);
+            trace.record(Stage::CanonicalEncode, || {
+                format!("{} -> {}", stripped_url, canonical_url)
+            });
+            stripped_url = canonical_url;
+        }
+        // The command is only started for URLs that changed, not for every URL and word that is checked
+        if let Some(cmd) = self
+            .post_process_cmd
+            .as_ref()
+            .filter(|_| stripped_url != *original)
+        {
+            let processed_url =
+                post_process(cmd, &stripped_url, self.post_process_timeout, &self.errors);
+            trace.record(Stage::PostProcess, || {
+                format!("{:?}: {} -> {}", cmd, stripped_url, processed_url)
+            });
+            stripped_url = processed_url;
+        }
+        stripped_url
+    }
+
+    fn strip_tracking(&self, url: &url::Url, trace: &mut Trace) -> url::Url {
+        self.strip_tracking_at_depth(url, 0, trace)
+    }
+
+    /// strip_mailto cleans the URLs inside the subject and body of a mailto: link
+    fn strip_mailto(&self, url: &url::Url) -> url::Url {
+        let Some(query) = url.query() else {
+            return url.clone();
+        };
+        let mut changed = false;
+        let pairs: Vec<String> = query
+            .split('&')
+            .map(|pair| {
+                // mailto: encodes spaces as %20, so a + is a literal plus rather than a space
+                let decoded = pair.replace('+', "%2B");
+                let Some((key, value)) = url::form_urlencoded::parse(decoded.as_bytes()).next()
+                else {
+                    return pair.to_string();
+                };
+                if !key.eq_ignore_ascii_case("subject") && !key.eq_ignore_ascii_case("body") {
+                    return pair.to_string();
+                }
+                let cleaned = self.sanitize_text(&value, false);
+                if cleaned == value {
+                    return pair.to_string();
+                }
+                changed = true;
+                let encoded: String = url::form_urlencoded::byte_serialize(cleaned.as_bytes())
+                    .collect::<String>()
+                    .replace('+', "%20");
+                format!("{}={}", pair.split('=').next().unwrap_or_default(), encoded)
+            })
+            .collect();
+        if !changed {
+            return url.clone();
+        }
+        let mut new_url = url.clone();
+        new_url.set_query(Some(&pairs.join("&")));
+        new_url
+    }
+
+    /// check_idempotent strips the stripped URL again and logs an error if that changes it, which means some
+    /// rules undo or feed into each other. Returns true if the second pass didn't change the URL.
+    fn check_idempotent(&self, url: &url::Url, stripped_url: &url::Url) -> bool {
+        let stripped_again = self.strip_tracking(stripped_url, &mut Trace::default());
+        if stripped_again != *stripped_url {
+            error!(
+                "Stripping isn't idempotent for {}: {} was stripped again to {}",
+                url, stripped_url, stripped_again
+            );
+            return false;
+        }
+        true
+    }
+
+    /// strip_tracking_at_depth strips a URL that rules have already rewritten depth times
+    fn strip_tracking_at_depth(&self, url: &url::Url, depth: usize, trace: &mut Trace) -> url::Url {
+        if depth > MAX_REWRITE_DEPTH {
+            self.errors.increment(ErrorCategory::RecursionLimit);
+            error!("Rules rewrote URL too many times, stopping at {}", url);
+            return url.clone();
+        }
+        if url.scheme() == "magnet" {
+            return apply_rule(
+                Stage::Magnet,
+                "magnet",
+                url,
+                &self.magnet_strip_params,
+                &[],
+                trace,
+            );
+        }
+        if url.scheme() == "mailto" {
+            let stripped_url = self.strip_mailto(url);
+            trace.record(Stage::Mailto, || {
+                format!("cleaned URLs in the subject and body: {}", stripped_url)
+            });
+            return stripped_url;
+        }
+        // Every URL a rule unwraps or rewrites to is checked too, so a skipped destination comes out unchanged.
+        // Same-site rewrites like youtube.com watch links to youtu.be are finished by their rule without coming back
+        // here, so the check of the youtube.com URL covers them.
+        if let Some(host) = url.host_str() {
+            if !self.domain_allowed(host) {
+                trace.record(Stage::Exclusion, || {
+                    format!("{} is excluded by ONLY_DOMAINS or SKIP_DOMAINS", host)
+                });
+                return url.clone();
+            }
+        }
+        if url.domain().is_none() {
+            if self.process_ip_hosts && is_ip_host(url) {
+                // Only the common params apply since the domain rules are for names.
+                // url.host() serializes IPv6 hosts with their brackets.
+                let host = url.host().map(|host| host.to_string()).unwrap_or_default();
+                let keep = self.keep_params_for(&host);
+                return apply_rule(
+                    Stage::CommonParams,
+                    "common",
+                    url,
+                    &self.params.common,
+                    &keep,
+                    trace,
+                );
+            }
+            return url.clone();
+        }
+        let mut url = url.clone();
+        for step in self.pipeline.steps() {
+            // A custom stage could have replaced the host with an IP address
+            let Some(domain) = url.domain() else {
+                return url;
+            };
+            match self.run_step(step, &url, domain, depth, trace) {
+                Next::Continue(next) => url = next,
+                Next::Restart(rewritten) => {
+                    return self.strip_tracking_at_depth(&rewritten, depth + 1, trace)
+                }
+                Next::Done(done) => return done,
+            }
+        }
+        url
+    }
+
+    /// run_step runs one stage of the pipeline on a URL with a domain name
+    fn run_step(
+        &self,
+        step: &Step,
+        url: &url::Url,
+        domain: &str,
+        depth: usize,
+        trace: &mut Trace,
+    ) -> Next {
+        match step {
+            Step::ClearUrlsRedirect => match self
+                .clearurls
+                .as_ref()
+                .and_then(|rules| rules.redirect(url, trace))
+            {
+                Some(destination) => Next::Restart(destination),
+                None => Next::Continue(url.clone()),
+            },
+            Step::HostRewrite => match self.rewrite_host(url, domain) {
+                Some(rewritten) => {
+                    trace.record(Stage::HostRewrite, || {
+                        format!(
+                            "rewrote host {} to {}",
+                            domain,
+                            rewritten.host_str().unwrap_or_default()
+                        )
+                    });
+                    Next::Restart(rewritten)
+                }
+                None => Next::Continue(url.clone()),
+            },
+            Step::Deamp => match self.deamp.then(|| deamp(url)).flatten() {
+                Some(canonical) => {
+                    trace.record(Stage::Amp, || format!("AMP link, rewrote to {}", canonical));
+                    Next::Restart(canonical)
+                }
+                None => Next::Continue(url.clone()),
+            },
+            Step::Shortener => self.expand_shortener(url, domain, trace),
+            Step::Params => {
+                let keep = self.keep_params_for(domain);
+                Next::Continue(self.strip_domain_params(url, domain, &keep, depth, trace))
+            }
+            Step::ClearUrls => match &self.clearurls {
+                Some(rules) => {
+                    Next::Continue(rules.strip(url, &self.keep_params_for(domain), trace))
+                }
+                None => Next::Continue(url.clone()),
+            },
+            Step::NestedUrls if self.strip_nested_urls => {
+                Next::Continue(self.strip_nested(url, depth, trace))
+            }
+            Step::NestedUrls => Next::Continue(url.clone()),
+            Step::Fragment if self.strip_fragments => {
+                let keep = self.keep_params_for(domain);
+                let stripped =
+                    strip_fragment(url, &self.fragment_params, &keep, self.keep_text_fragments);
+                if stripped != *url {
+                    trace.record(Stage::Fragment, || {
+                        format!(
+                            "#{} -> #{}",
+                            url.fragment().unwrap_or_default(),
+                            stripped.fragment().unwrap_or_default()
+                        )
+                    });
+                }
+                Next::Continue(stripped)
+            }
+            Step::Fragment => Next::Continue(url.clone()),
+            Step::Custom(name) => {
+                let transformed = self
+                    .pipeline
+                    .transform(name)
+                    .and_then(|transform| transform.apply(url))
+                    .filter(|transformed| transformed != url);
+                match transformed {
+                    Some(transformed) => {
+                        trace.record(Stage::Custom, || {
+                            format!("{}: {} -> {}", name, url, transformed)
+                        });
+                        Next::Continue(transformed)
+                    }
+                    None => Next::Continue(url.clone()),
+                }
+            }
+        }
+    }
+
+    /// expand_shortener expands or handles a shortener link by SHORTENER_ACTION. Other links are passed on.
+    fn expand_shortener(&self, url: &url::Url, domain: &str, trace: &mut Trace) -> Next {
+        if !self
+            .shortener_domains
+            .iter()
+            .any(|shortener| shortener == domain)
+        {
+            return Next::Continue(url.clone());
+        }
+        match self.shortener_action {
+            ShortenerAction::Expand => {
+                let expansion = match &self.expander {
+                    Some(expander) => expander.expansion(url, &self.errors),
+                    None => expand::Expansion::Failed,
+                };
+                match expansion {
+                    expand::Expansion::Expanded(destination) => {
+                        trace.record(Stage::Shortener, || {
+                            format!("{} is a shortener, expanded to {}", domain, destination)
+                        });
+                        return Next::Restart(destination);
+                    }
+                    expand::Expansion::Failed => trace.record(Stage::Shortener, || {
+                        format!(
+                            "{} is a shortener, expanding failed so stripping its params",
+                            domain
+                        )
+                    }),
+                    expand::Expansion::Pending => trace.record(Stage::Shortener, || {
+                        format!(
+                            "{} is a shortener, stripping its params while it's expanded",
+                            domain
+                        )
+                    }),
+                }
+            }
+            ShortenerAction::StripParams => {
+                trace.record(Stage::Shortener, || {
+                    format!("{} is a shortener, stripping its params", domain)
+                });
+            }
+            ShortenerAction::Warn => {
+                warn!(
+                    "Shortened link likely hides tracking, left unchanged: {}",
+                    url
+                );
+                trace.record(Stage::Shortener, || {
+                    format!("{} is a shortener, warned and left unchanged", domain)
+                });
+                return Next::Done(url.clone());
+            }
+            ShortenerAction::Ignore => {
+                trace.record(Stage::Shortener, || {
+                    format!("{} is a shortener, left unchanged", domain)
+                });
+                return Next::Done(url.clone());
+            }
+        }
+        Next::Continue(url.clone())
+    }
+
+    /// strip_domain_params strips the params of the domain's rule in RULES or DOMAINS, or of its built-in rule
+    fn strip_domain_params(
+        &self,
+        url: &url::Url,
+        domain: &str,
+        keep: &[String],
+        depth: usize,
+        trace: &mut Trace,
+    ) -> url::Url {
+        match self.domain_rule_for(domain) {
+            Some(rule) if rule.extend => {
+                let stripped_url = self.strip_builtin(url, domain, keep, depth, trace);
+                apply_rule(
+                    Stage::DomainRule,
+                    &rule.domain,
+                    &stripped_url,
+                    &rule.strip,
+                    keep,
+                    trace,
+                )
+            }
+            Some(rule) => apply_rule(
+                Stage::DomainRule,
+                &rule.domain,
+                url,
+                &rule.strip,
+                keep,
+                trace,
+            ),
+            None => self.strip_builtin(url, domain, keep, depth, trace),
+        }
+    }
+
+    /// strip_nested strips the http(s) URLs in the query param values of a stripped URL, like `continue=` or the
+    /// destination of a redirect, and encodes them back in place. Every level of nesting counts towards
+    /// MAX_REWRITE_DEPTH, and URLs nested deeper are left as they are.
+    fn strip_nested(&self, url: &url::Url, depth: usize, trace: &mut Trace) -> url::Url {
+        if depth >= MAX_REWRITE_DEPTH {
+            return url.clone();
+        }
+        let Some(query) = url.query() else {
+            return url.clone();
+        };
+        let mut changed = false;
+        let pairs: Vec<String> = query
+            .split('&')
+            .map(|pair| {
+                let Some((key, value)) = url::form_urlencoded::parse(pair.as_bytes()).next() else {
+                    return pair.to_string();
+                };
+                let Some(nested) = parse_nested_url(&value) else {
+                    return pair.to_string();
+                };
+                let stripped = self.strip_tracking_at_depth(&nested, depth + 1, trace);
+                if stripped == nested {
+                    return pair.to_string();
+                }
+                trace.record(Stage::NestedUrl, || {
+                    format!("{}: {} -> {}", key, nested, stripped)
+                });
+                changed = true;
+                let encoded: String =
+                    url::form_urlencoded::byte_serialize(stripped.as_str().as_bytes()).collect();
+                format!("{}={}", pair.split('=').next().unwrap_or_default(), encoded)
+            })
+            .collect();
+        if !changed {
+            return url.clone();
+        }
+        let mut new_url = url.clone();
+        new_url.set_query(Some(&pairs.join("&")));
+        new_url
+    }
+
+    /// strip_builtin strips the URL with the built-in rule for its domain, or the common rule
+    fn strip_builtin(
+        &self,
+        url: &url::Url,
+        domain: &str,
+        keep: &[String],
+        depth: usize,
+        trace: &mut Trace,
+    ) -> url::Url {
+        match domain {
+            "www.youtube.com" | "youtube.com" if self.rule_enabled("youtube") => {
+                self.strip_full_youtube(url, keep, depth, trace)
+            }
+            "twitter.com" | "x.com" | "www.twitter.com" | "www.x.com"
+                if self.rule_enabled("twitter") =>
+            {
+                self.strip_twitter(url, domain, keep, trace)
+            }
+            domain
+                if is_same_or_subdomain(domain, "facebook.com")
+                    && self.rule_enabled("facebook") =>
+            {
+                self.strip_site(url, "facebook", &self.params.facebook, keep, depth, trace)
+            }
+            domain
+                if is_same_or_subdomain(domain, "instagram.com")
+                    && self.rule_enabled("instagram") =>
+            {
+                self.strip_site(url, "instagram", &self.params.instagram, keep, depth, trace)
+            }
+            domain if is_amazon(domain) && self.rule_enabled("amazon") => {
+                if let Some(canonical) = self
+                    .canonical_amazon(url, domain, keep)
+                    .filter(|_| self.amazon_canonical)
+                {
+                    trace.record(Stage::DomainRule, || {
+                        format!("amazon product link, rewrote to {}", canonical)
+                    });
+                    return canonical;
+                }
+                apply_rule(
+                    Stage::DomainRule,
+                    "amazon",
+                    url,
+                    &self.params.amazon,
+                    keep,
+                    trace,
+                )
+            }
+            domain if is_google_search(domain) && self.rule_enabled("google") => {
+                self.strip_site(url, "google", &self.params.google, keep, depth, trace)
+            }
+            "news.google.com" if self.rule_enabled("google") => {
+                self.strip_site(url, "google", &self.params.google, keep, depth, trace)
+            }
+            "calendar.google.com" if self.rule_enabled("calendar") => apply_rule(
+                Stage::DomainRule,
+                "calendar",
+                url,
+                &self.params.conservative,
+                &with_protected(keep, &params::CALENDAR_PROTECTED_PARAMS),
+                trace,
+            ),
+            domain if is_zoom(domain) && self.rule_enabled("zoom") => apply_rule(
+                Stage::DomainRule,
+                "zoom",
+                url,
+                &self.params.conservative,
+                &with_protected(keep, &params::ZOOM_PROTECTED_PARAMS),
+                trace,
+            ),
+            "youtu.be" | "music.youtube.com" if self.rule_enabled("youtube") => apply_rule(
+                Stage::DomainRule,
+                "youtube",
+                url,
+                &self.params.youtube,
+                keep,
+                trace,
+            ),
+            _ if self.rule_enabled("common") => apply_rule(
+                Stage::CommonParams,
+                "common",
+                url,
+                &self.params.common,
+                keep,
+                trace,
+            ),
+            _ => {
+                trace.record(Stage::DomainRule, || {
+                    format!("rules for {} are disabled in DISABLED_RULES", domain)
+                });
+                url.clone()
+            }
+        }
+    }
+
+    /// domain_rule_for returns the most specific rule in RULES or DOMAINS for the domain or its parent domains
+    fn domain_rule_for(&self, domain: &str) -> Option<&DomainRule> {
+        self.domain_rules.iter().find(|rule| rule.matches(domain))
+    }
+
+    /// domain_allowed returns false if ONLY_DOMAINS is set and doesn't have the domain, or if SKIP_DOMAINS has it
+    fn domain_allowed(&self, domain: &str) -> bool {
+        let listed =
+            |domains: &[String]| domains.iter().any(|listed| domain_matches(domain, listed));
+        (self.only_domains.is_empty() || listed(&self.only_domains)) && !listed(&self.skip_domains)
+    }
+
+    fn rule_enabled(&self, name: &str) -> bool {
+        !self.disabled_rules.iter().any(|rule| rule == name)
+    }
+
+    /// strip_twitter strips the share tokens of twitter.com and x.com links and rewrites them to TWITTER_HOST
+    fn strip_twitter(
+        &self,
+        url: &url::Url,
+        domain: &str,
+        keep: &[String],
+        trace: &mut Trace,
+    ) -> url::Url {
+        let mut stripped_url = apply_rule(
+            Stage::DomainRule,
+            "twitter",
+            url,
+            &self.params.twitter,
+            keep,
+            trace,
+        );
+        if let Some(host) = self.twitter_host.as_deref().filter(|host| *host != domain) {
+            if stripped_url.set_host(Some(host)).is_ok() {
+                trace.record(Stage::DomainRule, || {
+                    format!("twitter rule rewrote host {} to {}", domain, host)
+                });
+            }
+        }
+        stripped_url
+    }
+
+    /// strip_site strips the params of the facebook, instagram or google rule and the common params. The sites' own
+    /// redirect links like l.facebook.com/l.php?u= and Google's /url result links are replaced with their destination,
+    /// even without UNWRAP_REDIRECTS, and so are Google News links with the publisher URL in them. Like the wrappers
+    /// UNWRAP_REDIRECTS unwraps, they count against MAX_UNWRAP_HOPS.
+    fn strip_site(
+        &self,
+        url: &url::Url,
+        name: &str,
+        params: &TrackingParams,
+        keep: &[String],
+        depth: usize,
+        trace: &mut Trace,
+    ) -> url::Url {
+        let destination = match name {
+            "google" => google_destination(url),
+            _ => unwrap_redirect(url),
+        };
+        if let Some(destination) = destination {
+            if trace.unwrap_hops < self.max_unwrap_hops {
+                trace.unwrap_hops += 1;
+                trace.record(Stage::DomainRule, || {
+                    format!("{} redirect link, unwrapped to {}", name, destination)
+                });
+                return self.strip_tracking_at_depth(&destination, depth + 1, trace);
+            }
+            warn!(
+                "Unwrapping {} hit the limit of {} hops, stripping it instead",
+                url, self.max_unwrap_hops
+            );
+        }
+        let stripped_url = apply_rule(Stage::DomainRule, name, url, params, keep, trace);
+        if !self.rule_enabled("common") {
+            return stripped_url;
+        }
+        apply_rule(
+            Stage::CommonParams,
+            "common",
+            &stripped_url,
+            &self.params.common,
+            keep,
+            trace,
+        )
+    }
+
+    fn strip_full_youtube(
+        &self,
+        url: &url::Url,
+        keep: &[String],
+        depth: usize,
+        trace: &mut Trace,
+    ) -> url::Url {
+        for prefix in &self.youtube_prefixes {
+            if let Some(new_url) = map_youtube_prefix(url, prefix) {
+                trace.record(Stage::YoutubePrefix, || {
+                    format!("{} matched, rewrote to {}", prefix, new_url)
+                });
+                return self.strip_tracking_at_depth(&new_url, depth + 1, trace);
+            }
+        }
+
+        if let Some(video_id) = get_query_value(url, "v") {
+            let mut new_url = url.clone();
+            if new_url.set_host(Some("youtu.be")).is_ok() {
+                new_url.set_path(&format!("/{}", video_id));
+                trace.record(Stage::DomainRule, || {
+                    format!("youtube: watch URL shortened to youtu.be/{}", video_id)
+                });
+                // The video ID moved into the path, so dropping it from the query isn't stripping tracking
+                let new_url = strip_params(&new_url, &TrackingParams::from_defaults(&["v"]), keep);
+                return apply_rule(
+                    Stage::DomainRule,
+                    "youtube",
+                    &new_url,
+                    &self.params.youtube,
+                    keep,
+                    trace,
+                );
+            }
+        }
+        apply_rule(
+            Stage::DomainRule,
+            "youtube",
+            url,
+            &self.params.youtube,
+            keep,
+            trace,
+        )
+    }
+
+    /// rewrite_host returns the URL with the host replaced by the HOST_REWRITES entry of the domain. The entry
+    /// of a domain also rewrites its subdomains, e.g. old.example.com = "new.example.com" rewrites
+    /// www.old.example.com to www.new.example.com. Without an entry DESKTOP_HOSTS drops mobile labels.
+    fn rewrite_host(&self, url: &url::Url, domain: &str) -> Option<url::Url> {
+        let host = match self
+            .host_rewrites
+            .iter()
+            .find(|(from, _)| is_same_or_subdomain(domain, from))
+        {
+            Some((from, to)) => format!("{}{}", &domain[..domain.len() - from.len()], to),
+            None if self.desktop_hosts => desktop_host(domain)?,
+            None => return None,
+        };
+        if host == domain {
+            return None;
+        }
+        let mut rewritten = url.clone();
+        rewritten.set_host(Some(&host)).ok()?;
+        rewritten.domain()?;
+        Some(rewritten)
+    }
+
+    /// canonical_amazon returns the www.amazon.<tld>/dp/<ASIN> link of a product page. Only the referral params
+    /// like tag that the amazon rule keeps and the params in keep are kept. Returns None for other pages.
+    fn canonical_amazon(&self, url: &url::Url, domain: &str, keep: &[String]) -> Option<url::Url> {
+        let asin = amazon_asin(url.path())?;
+        let domain = domain.strip_prefix("www.").unwrap_or(domain);
+        let mut canonical = Url::parse(&format!("https://www.{}/dp/{}", domain, asin)).ok()?;
+        let kept: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(key, _)| {
+                self.params.amazon.is_kept(key, keep)
+                    || (params::ParamCategory::of(key) == params::ParamCategory::Referral
+                        && !self.params.amazon.contains(key))
+            })
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        if !kept.is_empty() {
+            canonical.query_pairs_mut().extend_pairs(kept);
+        }
+        Some(canonical)
+    }
+
+    /// keep_params_for returns the params protected from stripping on the domain or its subdomains,
+    /// and the exceptions learned for the domain
+    fn keep_params_for(&self, domain: &str) -> Vec<String> {
+        let mut keep = self.keep_params_everywhere.clone();
+        if let Some((_, params)) = self
+            .keep_params
+            .iter()
+            .find(|(keep_domain, _)| domain_matches(domain, keep_domain))
+        {
+            keep.extend(params.iter().cloned());
+        }
+        if let Some(learner) = &self.learner {
+            keep.extend(learner.lock().unwrap().keep_params(domain).cloned());
+        }
+        keep
+    }
+
+    /// record_override tells the learner that the user copied the content again after it was stripped
+    pub fn record_override(&self, content: &str) {
+        let Some(learner) = &self.learner else {
+            return;
+        };
+        if let Some(url) = self.parse_text(content) {
+            let mut trace = Trace::default();
+            self.strip_tracking(&url, &mut trace);
+            learner
+                .lock()
+                .unwrap()
+                .record_override(&url, &trace.removed);
+        }
+    }
+}
+
+/// DomainRule is the params to strip on a domain and its subdomains, configured in the RULES or DOMAINS table
+#[derive(Debug, Default)]
+struct DomainRule {
+    domain: String,
+    /// subdomains_only is set for `*.example.com`, which doesn't match example.com itself
+    subdomains_only: bool,
+    strip: TrackingParams,
+    /// extend strips the params after the built-in rule for the domain instead of replacing it
+    extend: bool,
+}
+
+impl DomainRule {
+    /// from_tables builds the rules of the RULES table, whose keys are `domain.field`, and the simpler DOMAINS table.
+    /// A domain in both uses its RULES rule. More specific domains are listed first so they win over their parents.
+    fn from_tables(
+        rules: Vec<(String, String)>,
+        domains: Vec<(String, String)>,
+        common: &TrackingParams,
+    ) -> Vec<DomainRule> {
+        let mut by_domain: Vec<(String, Settings)> = vec![];
+        for (key, value) in rules {
+            let Some((domain, field)) = key.rsplit_once('.') else {
+                error!(
+                    "Invalid key in RULES: {:?}, expected a table per domain like [RULES.\"example.com\"]",
+                    key
+                );
+                continue;
+            };
+            let domain = domain.trim().to_ascii_lowercase();
+            match by_domain
+                .iter_mut()
+                .find(|(rule_domain, _)| *rule_domain == domain)
+            {
+                Some((_, fields)) => {
+                    fields.insert(field.to_string(), value);
+                }
+                None => by_domain.push((domain, Settings::from([(field.to_string(), value)]))),
+            }
+        }
+
+        let mut domain_rules = vec![];
+        for (domain, fields) in by_domain {
+            for field in fields.keys() {
+                if !["params", "patterns", "common", "extend"].contains(&field.as_str()) {
+                    error!(
+                        "Unknown field {:?} in the rule for {}, expected params, patterns, common or extend",
+                        field, domain
+                    );
+                }
+            }
+            let mut rule = DomainRule::new(
+                &domain,
+                &settings::read(&fields, "params").unwrap_or_default(),
+            );
+            // Compiled once here, like STRIP_PATTERNS, and matched against every param name of the domain's links
+            rule.strip.add_patterns(&parse_list(
+                &settings::read(&fields, "patterns").unwrap_or_default(),
+            ));
+            if settings::read_bool(&fields, "common", false) {
+                rule.strip.extend(common);
+            }
+            rule.extend = settings::read_bool(&fields, "extend", false);
+            domain_rules.push(rule);
+        }
+        for (domain, params) in domains {
+            if !domain_rules
+                .iter()
+                .any(|rule: &DomainRule| rule.domain == domain)
+            {
+                domain_rules.push(DomainRule::new(&domain, &params));
+            }
+        }
+        domain_rules.sort_by_key(|rule| std::cmp::Reverse(rule.domain.split('.').count()));
+        domain_rules
+    }
+
+    fn new(domain: &str, params: &str) -> DomainRule {
+        let mut strip = TrackingParams::default();
+        strip.merge(&parse_list(params));
+        let subdomains_only = domain.starts_with("*.");
+        DomainRule {
+            domain: domain.trim_start_matches("*.").to_string(),
+            subdomains_only,
+            strip,
+            extend: false,
+        }
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        if self.subdomains_only {
+            domain.ends_with(&format!(".{}", self.domain))
+        } else {
+            is_same_or_subdomain(domain, &self.domain)
+        }
+    }
+}
+
+/// ShortenerAction is what to do with links from SHORTENER_DOMAINS
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum ShortenerAction {
+    /// Strip the common tracking params from the short link itself
+    #[default]
+    StripParams,
+    /// Follow the short link to its destination and strip that. If that fails, the short link's params are stripped.
+    Expand,
+    /// Log a warning and leave the link alone
+    Warn,
+    Ignore,
+}
+
+impl ShortenerAction {
+    fn from_setting(value: Option<String>) -> ShortenerAction {
+        match value.as_deref() {
+            None | Some("") | Some("strip_params") => ShortenerAction::StripParams,
+            Some("expand") => ShortenerAction::Expand,
+            Some("warn") => ShortenerAction::Warn,
+            Some("ignore") => ShortenerAction::Ignore,
+            Some(value) => {
+                error!(
+                    "Invalid value for SHORTENER_ACTION: {:?}, expected strip_params, expand, warn or ignore",
+                    value
+                );
+                ShortenerAction::default()
+            }
+        }
+    }
+}
+
+/// MultiUrlMode is which URLs are cleaned in clipboard text that isn't just a URL
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum MultiUrlMode {
+    #[default]
+    All,
+    First,
+    /// Only clean the clipboard if it's a single URL
+    Off,
+}
+
+impl MultiUrlMode {
+    fn from_setting(value: Option<String>) -> MultiUrlMode {
+        match value.as_deref() {
+            None | Some("") | Some("all") => MultiUrlMode::All,
+            Some("first") => MultiUrlMode::First,
+            Some("off") => MultiUrlMode::Off,
+            Some(value) => {
+                error!(
+                    "Invalid value for MULTI_URL_MODE: {:?}, expected all, first or off",
+                    value
+                );
+                MultiUrlMode::default()
+            }
+        }
+    }
+}
+
+/// Stage is a step of the sanitizing pipeline that a Trace records a decision for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Exclusion,
+    Unwrap,
+    NestedUrl,
+    ClearUrls,
+    HostRewrite,
+    Amp,
+    Magnet,
+    Mailto,
+    Shortener,
+    YoutubePrefix,
+    DomainRule,
+    CommonParams,
+    Hashbang,
+    Fragment,
+    KeepParams,
+    CanonicalEncode,
+    PostProcess,
+    Custom,
+}
+
+/// Next is what the pipeline does after a stage
+enum Next {
+    /// Continue passes the URL on to the next stage
+    Continue(url::Url),
+    /// Restart runs the whole pipeline again on a URL the stage rewrote the URL into
+    Restart(url::Url),
+    /// Done stops the pipeline with the URL
+    Done(url::Url),
+}
+
+/// Trace records what the sanitizer did to a URL for --explain. Nothing is recorded unless it's enabled.
+#[derive(Default)]
+pub struct Trace {
+    enabled: bool,
+    steps: Vec<(Stage, String)>,
+    result: Option<url::Url>,
+    /// removed are the params the rules stripped, for the tracking score. They're kept also when not enabled.
+    removed: Vec<String>,
+    /// unwrap_hops counts the redirect wrappers unwrapped so far, by UNWRAP_REDIRECTS and the site rules
+    unwrap_hops: usize,
+}
+
+impl Trace {
+    fn enabled() -> Trace {
+        Trace {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    /// record adds a step. The detail is only formatted when the trace is enabled.
+    fn record(&mut self, stage: Stage, detail: impl FnOnce() -> String) {
+        if self.enabled {
+            self.steps.push((stage, detail()));
+        }
+    }
+}
+
+/// Formats the steps as `Stage: detail` lines followed by the result, which is what --explain prints
+impl std::fmt::Display for Trace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (stage, detail) in &self.steps {
+            writeln!(f, "{:?}: {}", stage, detail)?;
+        }
+        match &self.result {
+            Some(url) => writeln!(f, "Result: {}", url),
+            None => writeln!(f, "Result: unchanged"),
+        }
+    }
+}
+
+/// apply_rule strips the rule's params from the URL and records which ones were removed or kept
+fn apply_rule(
+    stage: Stage,
+    name: &str,
+    url: &url::Url,
+    strip: &TrackingParams,
+    keep: &[String],
+    trace: &mut Trace,
+) -> url::Url {
+    let present: Vec<String> = url.query_pairs().map(|(key, _)| key.to_string()).collect();
+    let removed: Vec<&String> = present
+        .iter()
+        .filter(|key| strip.strips(key, keep))
+        .collect();
+    if trace.enabled {
+        let kept: Vec<&String> = present
+            .iter()
+            .filter(|key| strip.contains(key) && strip.is_kept(key, keep))
+            .collect();
+        trace.record(stage, || format!("{} rule removed {:?}", name, removed));
+        if !kept.is_empty() {
+            trace.record(Stage::KeepParams, || {
+                format!(
+                    "kept {:?} listed in KEEP_PARAMS, DOMAIN_KEEP_PARAMS or learned",
+                    kept
+                )
+            });
+        }
+    }
+    trace.removed.extend(removed.into_iter().cloned());
+    strip_params(url, strip, keep)
+}
+
+/// post_process pipes the URL through the command and returns the URL it prints.
+/// If the command fails, times out or doesn't print a valid URL, the URL is returned unchanged.
+fn post_process(cmd: &str, url: &url::Url, timeout: Duration, errors: &ErrorMetrics) -> url::Url {
+    match run_command(cmd, url.as_str(), timeout) {
+        Ok(output) => match Url::parse(output.trim_end_matches(['\r', '\n'])) {
+            Ok(new_url) => new_url,
+            Err(e) => {
+                errors.increment(ErrorCategory::Parse);
+                error!(
+                    "Post-process command printed an invalid URL {:?}: {}",
+                    output, e
+                );
+                url.clone()
+            }
+        },
+        Err(e) => {
+            error!("Post-process command failed for {}: {}", url, e);
+            url.clone()
+        }
+    }
+}
+
+/// run_command runs the command through the shell with input on stdin and returns its stdout.
+/// The command is killed if it doesn't exit within timeout so it can't hang the main loop.
+fn run_command(cmd: &str, input: &str, timeout: Duration) -> Result<String, String> {
+    use std::io::{Read, Write};
+    use std::process::{Command, Stdio};
+
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    };
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+
+    // Read and write on their own threads so a command that doesn't read stdin or fills the stdout pipe can't block us
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_string();
+    std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let mut stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut output = String::new();
+        let _ = sender.send(stdout.read_to_string(&mut output).map(|_| output));
+    });
+
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => break,
+            Ok(Some(status)) => return Err(format!("exited with {}", status)),
+            Ok(None) if started.elapsed() >= timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("timed out after {:?}", timeout));
+            }
+            Ok(None) => std::thread::sleep(Duration::from_millis(5)),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+
+    // The command exited, but something it spawned could still hold stdout open
+    match receiver.recv_timeout(timeout.saturating_sub(started.elapsed())) {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("timed out after {:?} reading output", timeout)),
+    }
+}
+
+/// DEFAULT_SANITIZER is built by the first call to sanitize_url or sanitize_text and reused by the later ones
+static DEFAULT_SANITIZER: OnceLock<Sanitizer> = OnceLock::new();
+
+/// sanitize_url strips tracking from the URL with the default settings, the same as an empty config file.
+/// Build a Sanitizer with from_config instead to use other settings.
+pub fn sanitize_url(url: &url::Url) -> url::Url {
+    default_sanitizer().sanitize(url)
+}
+
+/// sanitize_text strips tracking from every URL in the text with the default settings, like sanitize_url
+pub fn sanitize_text(text: &str) -> String {
+    default_sanitizer().sanitize_text(text, false)
+}
+
+fn default_sanitizer() -> &'static Sanitizer {
+    DEFAULT_SANITIZER.get_or_init(|| Sanitizer::from_config(&Settings::new()))
+}
+
+/// fuzz_sanitize runs arbitrary text through the clipboard pipeline with every option enabled.
+/// It is used by the fuzz targets in fuzz/ and panics if the output isn't usable.
+#[cfg(fuzzing)]
+pub fn fuzz_sanitize(text: &str) {
+    for canonical_encode in [false, true] {
+        let sanitizer = Sanitizer {
+            youtube_prefixes: parse_prefixes(Some("live,shorts".to_string())),
+            canonical_encode,
+            process_tabular: true,
+            keep_params: parse_domain_params(Some("example.com:utm_source".to_string())),
+            deamp: true,
+            desktop_hosts: true,
+            strip_nested_urls: true,
+            ..Default::default()
+        };
+        sanitizer.sanitize_clipboard(text);
+        sanitizer.sanitize_html(text);
+        if let Some(url) = sanitizer.parse_text(text) {
+            let sanitized = sanitizer.sanitize(&url);
+            if let Err(e) = Url::parse(sanitized.as_str()) {
+                panic!("Sanitized {} to invalid URL {}: {}", url, sanitized, e);
+            }
+        }
+    }
+}
+
+/// excluded_scheme returns the scheme of the text if it's one we never process
+fn excluded_scheme(text: &str) -> Option<&'static str> {
+    let text = text.trim_start();
+    EXCLUDED_SCHEMES.into_iter().find(|scheme| {
+        text.len() > scheme.len()
+            && text.as_bytes()[scheme.len()] == b':'
+            && text[..scheme.len()].eq_ignore_ascii_case(scheme)
+    })
+}
+
+/// unwrap_redirect returns the destination of a link through one of the REDIRECTORS
+fn unwrap_redirect(url: &url::Url) -> Option<url::Url> {
+    let domain = url.domain()?;
+    let (_, _, param) = REDIRECTORS.iter().find(|(redirector, path, _)| {
+        (domain == *redirector || domain.ends_with(&format!(".{}", redirector)))
+            && url.path() == *path
+    })?;
+    let target = get_query_value(url, param)?;
+    let target = Url::parse(&target).ok()?;
+    matches!(target.scheme(), "http" | "https").then_some(target)
+}
+
+/// is_google_search returns true for Google Search on google.com and the country domains like google.co.uk
+/// and google.de, with or without www. Other Google services on subdomains have their own params.
+fn is_google_search(domain: &str) -> bool {
+    let domain = domain.strip_prefix("www.").unwrap_or(domain);
+    let Some(suffix) = domain.strip_prefix("google.") else {
+        return false;
+    };
+    match suffix.split_once('.') {
+        None => !suffix.is_empty(),
+        Some((second, country)) => {
+            matches!(second, "co" | "com") && country.len() == 2 && !country.contains('.')
+        }
+    }
+}
+
+/// google_destination returns the destination of a Google Search /url result link, or the publisher URL of a
+/// Google News link. News article IDs in the older format are base64 encoded and contain the publisher URL,
+/// newer ones can only be resolved by Google and return None.
+fn google_destination(url: &url::Url) -> Option<url::Url> {
+    let destination = if url.domain() == Some("news.google.com") {
+        get_query_value(url, "url").or_else(|| {
+            let mut segments = url.path_segments()?;
+            segments.find(|segment| *segment == "articles")?;
+            google_news_article_url(segments.next()?)
+        })?
+    } else if url.path() == "/url" {
+        // Result links have the destination in q, or in url with an empty q
+        get_query_value(url, "q")
+            .filter(|q| !q.is_empty())
+            .or_else(|| get_query_value(url, "url"))?
+    } else {
+        return None;
+    };
+    let destination = Url::parse(&destination).ok()?;
+    matches!(destination.scheme(), "http" | "https").then_some(destination)
+}
+
+/// google_news_article_url decodes the publisher URL from a Google News article ID, which is a base64url encoded
+/// message with the URL as a length-prefixed string after the 08 13 22 header
+fn google_news_article_url(id: &str) -> Option<String> {
+    let bytes = decode_base64url(id)?;
+    let rest = bytes.strip_prefix(&[0x08, 0x13, 0x22])?;
+    // The length is a varint of one or two bytes, since article URLs are shorter than 16 KiB
+    let (length, rest) = match rest {
+        [low, rest @ ..] if low & 0x80 == 0 => (*low as usize, rest),
+        [low, high, rest @ ..] if high & 0x80 == 0 => {
+            ((low & 0x7f) as usize | (*high as usize) << 7, rest)
+        }
+        _ => return None,
+    };
+    let url = std::str::from_utf8(rest.get(..length)?).ok()?;
+    url.starts_with("http").then(|| url.to_string())
+}
+
+/// decode_base64url decodes unpadded or padded base64 with the URL-safe alphabet
+fn decode_base64url(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        buffer = buffer << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// desktop_host returns the domain without its m. or mobile. labels, like en.m.wikipedia.org to en.wikipedia.org
+/// and mobile.twitter.com to twitter.com. The last two labels are never dropped, so mobile.de stays.
+fn desktop_host(domain: &str) -> Option<String> {
+    let labels: Vec<&str> = domain.split('.').collect();
+    let site = labels.len().saturating_sub(2);
+    let desktop: Vec<&str> = labels
+        .iter()
+        .enumerate()
+        .filter(|(i, label)| *i >= site || !matches!(**label, "m" | "mobile"))
+        .map(|(_, label)| *label)
+        .collect();
+    (desktop.len() < labels.len()).then(|| desktop.join("."))
+}
+
+/// deamp returns the page an AMP link is a copy of. It handles AMP cache links like
+/// example-com.cdn.ampproject.org/c/s/example.com/article, Google's google.com/amp/s/example.com/article and
+/// pages with a trailing /amp path segment. Returns None if the link isn't AMP or the page can't be worked out.
+fn deamp(url: &url::Url) -> Option<url::Url> {
+    let domain = url.domain()?;
+    let path = url.path();
+    let embedded = if domain.ends_with(".cdn.ampproject.org") {
+        // The first segment is the kind of content, like c for pages, v for viewer pages and i for images
+        let (_, rest) = path.strip_prefix('/')?.split_once('/')?;
+        Some(rest)
+    } else if domain == "google.com" || domain.ends_with(".google.com") {
+        Some(path.strip_prefix("/amp/")?)
+    } else {
+        None
+    };
+    let Some(embedded) = embedded else {
+        let page = path
+            .strip_suffix("/amp")
+            .or_else(|| path.strip_suffix("/amp/"))
+            .filter(|page| !page.is_empty())?;
+        let mut canonical = url.clone();
+        canonical.set_path(page);
+        return Some(canonical);
+    };
+    // An s segment marks an https page
+    let canonical = match embedded.strip_prefix("s/") {
+        Some(location) => format!("https://{}", location),
+        None => format!("http://{}", embedded),
+    };
+    let mut canonical = Url::parse(&canonical).ok()?;
+    canonical.domain()?;
+    canonical.set_query(url.query());
+    canonical.set_fragment(url.fragment());
+    Some(canonical)
+}
+
+/// unwrap_chain follows redirect wrappers with step until the URL isn't a wrapper. It stops once the trace has
+/// max_hops unwrapped, or when a wrapper points back to a URL seen earlier, and then returns the last URL reached.
+fn unwrap_chain(
+    url: &url::Url,
+    max_hops: usize,
+    step: impl Fn(&url::Url) -> Option<url::Url>,
+    trace: &mut Trace,
+) -> url::Url {
+    let mut visited = vec![url.clone()];
+    while let Some(next) = step(visited.last().unwrap()) {
+        if visited.contains(&next) {
+            warn!("Redirect loop at {}, stopping unwrapping {}", next, url);
+            break;
+        }
+        if trace.unwrap_hops >= max_hops {
+            warn!(
+                "Unwrapping {} hit the limit of {} hops, stopping at {}",
+                url,
+                max_hops,
+                visited.last().unwrap()
+            );
+            break;
+        }
+        trace.record(Stage::Unwrap, || {
+            format!("{} -> {}", visited.last().unwrap(), next)
+        });
+        trace.unwrap_hops += 1;
+        visited.push(next);
+    }
+    visited.pop().unwrap()
+}
+
+/// embedded_url_start returns where an http(s) URL starts in a word that has other text before it.
+/// The text before it has to end in punctuation other than a quote, since quotes are only split off when they're
+/// closed after the URL.
+fn embedded_url_start(word: &str) -> Option<usize> {
+    let lowercase = word.to_ascii_lowercase();
+    let start = ["http://", "https://"]
+        .iter()
+        .filter_map(|scheme| lowercase.find(scheme))
+        .min()?;
+    let before = word[..start].chars().next_back()?;
+    (!before.is_alphanumeric() && before != '"' && before != '\'').then_some(start)
+}
+
+/// url_end returns where a URL at the start of a word ends, like a linkifier would find it: before the first
+/// character that can't be in a URL without percent-encoding, a non-ASCII quote or bracket like `”` or `」`,
+/// or a closing bracket without an opening one in the URL. Returns the length of the word if it's all URL.
+fn url_end(word: &str) -> usize {
+    let mut open_brackets = 0usize;
+    for (i, c) in word.char_indices() {
+        match c {
+            '(' | '[' => open_brackets += 1,
+            ')' | ']' if open_brackets == 0 => return i,
+            ')' | ']' => open_brackets -= 1,
+            '<' | '>' | '"' | '{' | '}' | '|' | '\\' | '^' | '`' => return i,
+            '“' | '”' | '„' | '‘' | '’' | '«' | '»' | '‹' | '›' | '「' | '」' | '『' | '』'
+            | '（' | '）' | '【' | '】' => return i,
+            _ => {}
+        }
+    }
+    word.len()
+}
+
+/// unquote returns the quote and the text inside it if the text is wrapped in matching single or double quotes
+fn unquote(text: &str) -> Option<(char, &str)> {
+    let quote = text.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let inner = text.strip_prefix(quote)?.strip_suffix(quote)?;
+    Some((quote, inner))
+}
+
+/// amazon_asin returns the product ID in the path of an Amazon product page, uppercased. Product pages are
+/// /dp/<ASIN> with an optional name slug before it, /gp/product/<ASIN>, /gp/aw/d/<ASIN> on mobile and the
+/// old /exec/obidos/ASIN/<ASIN> and /o/ASIN/<ASIN>. Anything after the ASIN, like /ref=sr_1_1, is dropped.
+fn amazon_asin(path: &str) -> Option<String> {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    segments.iter().enumerate().find_map(|(i, segment)| {
+        let before = i.checked_sub(1).map(|before| segments[before]);
+        let product = match *segment {
+            "dp" | "ASIN" => true,
+            "product" => before == Some("gp"),
+            "d" => before == Some("aw"),
+            _ => false,
+        };
+        let asin = segments.get(i + 1).filter(|_| product)?;
+        (asin.len() == 10 && asin.chars().all(|c| c.is_ascii_alphanumeric()))
+            .then(|| asin.to_ascii_uppercase())
+    })
+}
+
+/// is_amazon returns true for Amazon's store domains in every country, like amazon.com and www.amazon.co.uk
+fn is_amazon(domain: &str) -> bool {
+    let domain = domain.strip_prefix("www.").unwrap_or(domain);
+    domain
+        .strip_prefix("amazon.")
+        .is_some_and(|tld| !tld.is_empty() && tld.split('.').count() <= 2)
+}
+
+/// parse_twitter_host parses TWITTER_HOST, which is x.com, twitter.com or empty to keep the host of the link
+fn parse_twitter_host(setting: Option<String>) -> Option<String> {
+    let host = setting?.trim().to_ascii_lowercase();
+    match host.as_str() {
+        "" => None,
+        "x.com" | "twitter.com" => Some(host),
+        _ => {
+            error!(
+                "Invalid TWITTER_HOST {:?}, expected x.com, twitter.com or empty",
+                host
+            );
+            None
+        }
+    }
+}
+
+/// parse_shortener_domains applies EXTRA_SHORTENER_DOMAINS to the built-in SHORTENER_DOMAINS.
+/// Domains are added, or removed if they start with `-`.
+fn parse_shortener_domains(setting: Option<String>) -> Vec<String> {
+    let mut domains: Vec<String> = SHORTENER_DOMAINS.map(String::from).to_vec();
+    for domain in parse_list(&setting.unwrap_or_default().to_ascii_lowercase()) {
+        match domain.strip_prefix('-') {
+            Some(domain) => domains.retain(|shortener| shortener != domain),
+            None => domains.push(domain),
+        }
+    }
+    domains
+}
+
+/// is_same_or_subdomain returns true if the domain is parent or one of its subdomains
+fn is_same_or_subdomain(domain: &str, parent: &str) -> bool {
+    domain == parent || domain.ends_with(&format!(".{}", parent))
+}
+
+/// domain_matches returns true if the domain is the listed one or one of its subdomains. A listed domain with
+/// `*` in it is a pattern instead, where every `*` matches one or more characters: `*.example.com` only
+/// matches the subdomains of example.com and `intranet.*` every domain starting with intranet.
+fn domain_matches(domain: &str, listed: &str) -> bool {
+    if listed.contains('*') {
+        glob_matches(domain, listed)
+    } else {
+        is_same_or_subdomain(domain, listed)
+    }
+}
+
+fn glob_matches(text: &str, pattern: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return text == pattern;
+    };
+    let Some(text) = text.strip_prefix(prefix) else {
+        return false;
+    };
+    (1..=text.len())
+        .filter(|end| text.is_char_boundary(*end))
+        .any(|end| glob_matches(&text[end..], rest))
+}
+
+/// parse_domains parses a comma-separated list of domains
+fn parse_domains(setting: Option<String>) -> Vec<String> {
+    // Hosts in parsed URLs are always lowercase
+    parse_list(&setting.unwrap_or_default().to_ascii_lowercase())
+}
+
+/// parse_disabled_rules parses the comma-separated DISABLED_RULES, ignoring unknown rule names
+fn parse_disabled_rules(setting: Option<String>) -> Vec<String> {
+    parse_list(&setting.unwrap_or_default())
+        .into_iter()
+        .map(|rule| rule.to_lowercase())
+        .filter(|rule| {
+            let known = RULE_NAMES.contains(&rule.as_str());
+            if !known {
+                error!(
+                    "Unknown rule {:?} in DISABLED_RULES, expected one of {}",
+                    rule,
+                    RULE_NAMES.join(", ")
+                );
+            }
+            known
+        })
+        .collect()
+}
+
+/// is_zoom returns true for zoom.us and its subdomains, like the company-specific us02web.zoom.us
+fn is_zoom(domain: &str) -> bool {
+    domain == "zoom.us" || domain.ends_with(".zoom.us")
+}
+
+/// with_protected adds a rule's protected params to the params kept on the domain
+fn with_protected(keep: &[String], protected: &[&str]) -> Vec<String> {
+    keep.iter()
+        .cloned()
+        .chain(protected.iter().map(|param| param.to_string()))
+        .collect()
+}
+
+/// is_ip_host returns true if the URL's host is an IPv4 or IPv6 address
+fn is_ip_host(url: &url::Url) -> bool {
+    matches!(
+        url.host(),
+        Some(url::Host::Ipv4(_)) | Some(url::Host::Ipv6(_))
+    )
+}
+
+/// canonical_encode normalizes the percent-encoding of the URL so that equivalent URLs serialize identically.
+/// Escapes of unreserved characters are decoded and all other escapes use uppercase hex digits.
+/// Scheme and host case is already normalized by the url crate when parsing.
+fn canonical_encode(url: &url::Url) -> url::Url {
+    // Serialized URLs are always ASCII, so it is fine to work on bytes
+    let bytes = url.as_str().as_bytes();
+    let mut canonical = String::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && bytes[i + 1].is_ascii_hexdigit()
+            && bytes[i + 2].is_ascii_hexdigit()
+        {
+            let byte = u8::from_str_radix(&url.as_str()[i + 1..i + 3], 16).unwrap();
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                canonical.push(byte as char);
+            } else {
+                canonical.push_str(&format!("%{:02X}", byte));
+            }
+            i += 3;
+            continue;
+        }
+        canonical.push(bytes[i] as char);
+        i += 1;
+    }
+    match Url::parse(&canonical) {
+        Ok(new_url) => new_url,
+        Err(e) => {
+            error!("Failed to canonicalize URL {}: {}", url, e);
+            url.clone()
+        }
+    }
+}
+
+fn parse_prefixes(prefixes_csv: Option<String>) -> Vec<String> {
+    let mut prefixes = vec![];
+    if let Some(prefixes_csv) = prefixes_csv {
+        if prefixes_csv.is_empty() {
+            return prefixes;
+        }
+        for prefix in prefixes_csv.split(',') {
+            prefixes.push(format!("/{}/", prefix));
+        }
+    }
+    prefixes
+}
+
+/// parse_list splits a comma-separated list, ignoring empty entries
+fn parse_list(csv: &str) -> Vec<String> {
+    csv.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// parse_domain_params parses `domain:param,param;domain:param` into a list of params per domain.
+/// More specific domains are listed first so they win over their parent domains.
+fn parse_domain_params(domains_csv: Option<String>) -> Vec<(String, Vec<String>)> {
+    let mut domains: Vec<(String, Vec<String>)> = vec![];
+    for rule in domains_csv.unwrap_or_default().split(';') {
+        let Some((domain, params)) = rule.rsplit_once(':') else {
+            if !rule.trim().is_empty() {
+                error!(
+                    "Invalid domain params, expected domain:param,param: {}",
+                    rule
+                );
+            }
+            continue;
+        };
+        // Hosts in parsed URLs are always lowercase
+        domains.push((domain.trim().to_ascii_lowercase(), parse_list(params)));
+    }
+    domains.sort_by_key(|(domain, _)| std::cmp::Reverse(domain.split('.').count()));
+    domains
+}
+
+/// parse_host_rewrites parses the HOST_REWRITES table, sorted so the entries of subdomains come before the entries
+/// of their parents
+fn parse_host_rewrites(table: Vec<(String, String)>) -> Vec<(String, String)> {
+    let mut rewrites: Vec<(String, String)> = table
+        .into_iter()
+        .map(|(from, to)| {
+            (
+                from.trim().to_ascii_lowercase(),
+                to.trim().to_ascii_lowercase(),
+            )
+        })
+        .filter(|(from, to)| !from.is_empty() && !to.is_empty())
+        .collect();
+    rewrites.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+    rewrites
+}
+
+/// parse_domain_rules lowercases the domains of the DOMAINS table
+fn parse_domain_rules(table: Vec<(String, String)>) -> Vec<(String, String)> {
+    table
+        .into_iter()
+        .map(|(domain, params)| (domain.trim().to_ascii_lowercase(), params))
+        .collect()
+}
+
+fn map_youtube_prefix(url: &url::Url, prefix: &str) -> Option<url::Url> {
+    let video_id = url.path().strip_prefix(prefix)?.split('/').next()?;
+    let mut new_url = url.clone();
+    new_url.set_host(Some("youtu.be")).ok()?;
+    new_url.set_path(&format!("/{}", video_id));
+    Some(new_url)
+}
+
+/// strip_params removes the params in strip from the URL unless they are listed in keep.
+/// Kept params are left exactly as they were, so their encoding and order don't change.
+fn strip_params(url: &url::Url, strip: &TrackingParams, keep: &[String]) -> url::Url {
+    debug!(
+        "Stripping params from url {}: {:?}, keeping {:?}",
+        url, strip, keep
+    );
+    let Some(new_query) = url
+        .query()
+        .and_then(|query| strip_query(query, strip, keep))
+    else {
+        return url.clone();
+    };
+
+    let mut new_url = url.clone();
+    if new_query.is_empty() {
+        new_url.set_query(None);
+    } else {
+        new_url.set_query(Some(&new_query));
+    }
+    new_url
+}
+
+/// strip_query removes the params in strip from the raw query string unless they are listed in keep.
+/// Every pair is kept or removed as it is, so repeated keys are all removed and params without a value like
+/// `?flag` are kept as they were. Returns None if nothing was stripped.
+fn strip_query(query: &str, strip: &TrackingParams, keep: &[String]) -> Option<String> {
+    let mut stripped = false;
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|pair| {
+            let Some((key, _)) = url::form_urlencoded::parse(pair.as_bytes()).next() else {
+                return true;
+            };
+            if strip.strips(&key, keep) {
+                stripped = true;
+                return false;
+            }
+            true
+        })
+        .collect();
+    stripped.then(|| kept.join("&"))
+}
+
+/// strip_hashbang_params strips the params from the query of a `#!/route?query` hashbang route
+fn strip_hashbang_params(url: &url::Url, strip: &TrackingParams, keep: &[String]) -> url::Url {
+    let Some(route) = url
+        .fragment()
+        .and_then(|fragment| fragment.strip_prefix('!'))
+    else {
+        return url.clone();
+    };
+    let Some((path, query)) = route.split_once('?') else {
+        return url.clone();
+    };
+    let Some(new_query) = strip_query(query, strip, keep) else {
+        return url.clone();
+    };
+
+    let mut new_url = url.clone();
+    if new_query.is_empty() {
+        new_url.set_fragment(Some(&format!("!{}", path)));
+    } else {
+        new_url.set_fragment(Some(&format!("!{}?{}", path, new_query)));
+    }
+    new_url
+}
+
+/// strip_fragment strips the `:~:` fragment directive of scroll-to-text links like `#:~:text=quote`, unless
+/// keep_text is set, and the params in strip from fragments that look like a query, like `#xtor=RSS-1`. Hashbang
+/// routes are left to STRIP_HASHBANG_PARAMS. If nothing is left of the fragment, it's removed with its `#`.
+fn strip_fragment(
+    url: &url::Url,
+    strip: &TrackingParams,
+    keep: &[String],
+    keep_text: bool,
+) -> url::Url {
+    let Some(fragment) = url.fragment().filter(|fragment| !fragment.starts_with('!')) else {
+        return url.clone();
+    };
+    let (anchor, directive) = match fragment.find(":~:") {
+        Some(index) => fragment.split_at(index),
+        None => (fragment, ""),
+    };
+    let stripped_anchor = anchor
+        .contains('=')
+        .then(|| strip_query(anchor, strip, keep))
+        .flatten();
+    if stripped_anchor.is_none() && (directive.is_empty() || keep_text) {
+        return url.clone();
+    }
+    let mut new_fragment = stripped_anchor.unwrap_or_else(|| anchor.to_string());
+    if keep_text {
+        new_fragment.push_str(directive);
+    }
+    let mut new_url = url.clone();
+    if new_fragment.is_empty() {
+        new_url.set_fragment(None);
+    } else {
+        new_url.set_fragment(Some(&new_fragment));
+    }
+    new_url
+}
+
+/// cell_spans returns the byte ranges of the cells of tab or comma separated content, without the quotes of quoted
+/// cells. Quoted cells with escaped quotes are left out, since their value isn't a slice of the content.
+fn cell_spans(content: &str, delimiter: u8) -> Vec<std::ops::Range<usize>> {
+    let bytes = content.as_bytes();
+    let is_separator = |byte: u8| byte == delimiter || byte == b'\n' || byte == b'\r';
+    let mut spans = vec![];
+    let mut start = 0;
+    loop {
+        let mut end = start;
+        if bytes.get(start) == Some(&b'"') {
+            end += 1;
+            let mut escaped = false;
+            loop {
+                match bytes.get(end) {
+                    None => return spans,
+                    Some(b'"') if bytes.get(end + 1) == Some(&b'"') => {
+                        escaped = true;
+                        end += 2;
+                    }
+                    Some(b'"') => break,
+                    Some(_) => end += 1,
+                }
+            }
+            if !escaped {
+                spans.push(start + 1..end);
+            }
+            end += 1;
+        } else {
+            while end < bytes.len() && !is_separator(bytes[end]) {
+                end += 1;
+            }
+            spans.push(start..end);
+        }
+        while end < bytes.len() && !is_separator(bytes[end]) {
+            end += 1;
+        }
+        match bytes.get(end) {
+            None => return spans,
+            Some(b'\r') if bytes.get(end + 1) == Some(&b'\n') => start = end + 2,
+            Some(_) => start = end + 1,
+        }
+        if start == bytes.len() && bytes[end] != delimiter {
+            return spans;
+        }
+    }
+}
+
+/// parse_nested_url parses a param value that is a whole http(s) URL
+fn parse_nested_url(value: &str) -> Option<url::Url> {
+    let scheme = value.get(..8)?.to_ascii_lowercase();
+    if !scheme.starts_with("http://") && scheme != "https://" {
+        return None;
+    }
+    url::Url::parse(value).ok()
+}
+
+fn get_query_value(url: &url::Url, var: &str) -> Option<String> {
+    for (key, value) in url.query_pairs() {
+        if key == var {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    pub(crate) fn test_sanitizer() -> Sanitizer {
+        Sanitizer::from_config(&HashMap::from([(
+            "YOUTUBE_PREFIXES".to_string(),
+            "live,shorts".to_string(),
+        )]))
+    }
+
+    #[test]
+    fn test_recursion_limit_is_counted() {
+        let sanitizer = test_sanitizer();
+        let url = Url::parse("https://youtube.com/shorts/xxxxxxxxxx").unwrap();
+        sanitizer.strip_tracking_at_depth(&url, MAX_REWRITE_DEPTH + 1, &mut Trace::default());
+        assert_eq!(sanitizer.errors().get(ErrorCategory::RecursionLimit), 1);
+        assert_eq!(sanitizer.errors().total(), 1);
+    }
+
+    #[test]
+    fn test_strip_params() {
+        let url = Url::parse("https://example.com/path?foo=bar&baz=qux").unwrap();
+        let stripped_url = strip_params(&url, &TrackingParams::from_defaults(&["baz"]), &[]);
+        assert_eq!(
+            stripped_url.as_str(),
+            "https://example.com/path?foo=bar",
+            "Stripped URL is incorrect"
+        );
+    }
+
+    #[test]
+    fn test_strip_params_preserves_empty_and_valueless_params() {
+        let test_cases = vec![
+            (
+                "https://example.com/?foo&utm_source=x",
+                "https://example.com/?foo",
+            ),
+            (
+                "https://example.com/?utm_source=x&foo",
+                "https://example.com/?foo",
+            ),
+            (
+                "https://example.com/?foo=&bar=1&utm_source=x",
+                "https://example.com/?foo=&bar=1",
+            ),
+            (
+                "https://example.com/?print=&embed&utm_source=x&q=a+b%20c",
+                "https://example.com/?print=&embed&q=a+b%20c",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let url = Url::parse(input).unwrap();
+            let stripped_url =
+                strip_params(&url, &TrackingParams::from_defaults(&["utm_source"]), &[]);
+            assert_eq!(stripped_url.as_str(), expected, "Stripped URL is incorrect");
+        }
+    }
+
+    #[test]
+    fn test_strip_tracking_prefixes() {
+        let sanitizer = test_sanitizer();
+        let url = Url::parse(
+            "https://example.com/?utm_something_new=1&id=2&pk_campaign=x&utm=3&utm_source=y",
+        )
+        .unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://example.com/?id=2&utm=3"
+        );
+    }
+
+    #[test]
+    fn test_explicit_default_port() {
+        let sanitizer = test_sanitizer();
+        let test_cases = vec![
+            (
+                "https://example.com:443/?utm_source=x",
+                Some("https://example.com/"),
+            ),
+            (
+                "http://example.com:80/a?id=1&utm_source=x",
+                Some("http://example.com/a?id=1"),
+            ),
+            (
+                "https://example.com:8443/?utm_source=x",
+                Some("https://example.com:8443/"),
+            ),
+            ("https://example.com:443/?id=1", None),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(
+                sanitizer.sanitize_clipboard(input).as_deref(),
+                expected,
+                "{}",
+                input
+            );
+            assert_eq!(
+                sanitizer.sanitize_text(input, false),
+                expected.unwrap_or(input),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_strip_ref_prefix() {
+        let mut sanitizer = test_sanitizer();
+        let url =
+            Url::parse("https://example.com/?ref_campaign=a&ref_source=b&ref=c&refresh=1").unwrap();
+        assert_eq!(sanitizer.sanitize(&url), url);
+
+        sanitizer.params.common.add_prefix("ref_");
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://example.com/?ref=c&refresh=1"
+        );
+    }
+
+    #[test]
+    fn test_strip_marketing_automation_params() {
+        let sanitizer = test_sanitizer();
+        let test_cases = vec![
+            (
+                "https://example.com/post?_hsenc=p2AN&id=1&_kx=abc.123",
+                "https://example.com/post?id=1",
+            ),
+            (
+                "https://twitter.com/a/status/1?_hsmi=2&s=19",
+                "https://twitter.com/a/status/1",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn test_param_case_and_repeats() {
+        let mut config = HashMap::from([("KEEP_PARAMS".to_string(), "Ref".to_string())]);
+        let sanitizer = Sanitizer::from_config(&config);
+        for (input, expected) in [
+            (
+                "https://example.com/?UTM_Source=x&id=1&UTM_CAMPAIGN=y",
+                "https://example.com/?id=1",
+            ),
+            // Pairs are kept as they were, including their encoding, order and missing values
+            (
+                "https://example.com/?flag&utm_source&id=a%20b&q=c+d&e=%7e",
+                "https://example.com/?flag&id=a%20b&q=c+d&e=%7e",
+            ),
+            (
+                "https://example.com/?b=2&utm_source=x&a=1&utm_source=y&b=3",
+                "https://example.com/?b=2&a=1&b=3",
+            ),
+            // Kept params are compared regardless of case too
+            (
+                "https://example.com/?ref=a&REF=b&utm_medium=c",
+                "https://example.com/?ref=a&REF=b",
+            ),
+        ] {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+
+        config.insert("IGNORE_PARAM_CASE".to_string(), "false".to_string());
+        let sanitizer = Sanitizer::from_config(&config);
+        let url = Url::parse("https://example.com/?UTM_Source=x&utm_source=y").unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://example.com/?UTM_Source=x"
+        );
+    }
+
+    #[test]
+    fn test_strip_hashbang_params() {
+        let mut sanitizer = test_sanitizer();
+        let url = Url::parse("https://example.com/#!/route/1?utm_source=x&tab=2").unwrap();
+        assert_eq!(sanitizer.sanitize(&url), url);
+
+        sanitizer.strip_hashbang_params = true;
+        let test_cases = vec![
+            (
+                "https://example.com/#!/route/1?utm_source=x&tab=2",
+                "https://example.com/#!/route/1?tab=2",
+            ),
+            (
+                "https://example.com/?utm_medium=y#!/route?utm_source=x",
+                "https://example.com/#!/route",
+            ),
+            (
+                "https://example.com/#!/route?tab=2",
+                "https://example.com/#!/route?tab=2",
+            ),
+            // Plain fragments aren't routes
+            (
+                "https://example.com/#section?utm_source=x",
+                "https://example.com/#section?utm_source=x",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn test_strip_fragments() {
+        let mut sanitizer = test_sanitizer();
+        let url = Url::parse("https://example.com/#:~:text=quote").unwrap();
+        assert_eq!(sanitizer.sanitize(&url), url);
+
+        sanitizer.strip_fragments = true;
+        let test_cases = vec![
+            ("https://example.com/#:~:text=quote", "https://example.com/"),
+            (
+                "https://example.com/page#section:~:text=quote",
+                "https://example.com/page#section",
+            ),
+            (
+                "https://example.com/article#xtor=RSS-3208",
+                "https://example.com/article",
+            ),
+            (
+                "https://example.com/?utm_medium=y#utm_source=x&tab=2",
+                "https://example.com/#tab=2",
+            ),
+            (
+                "https://example.com/#section",
+                "https://example.com/#section",
+            ),
+            // Hashbang routes and routes with a query are left alone
+            (
+                "https://example.com/#!/route?utm_source=x",
+                "https://example.com/#!/route?utm_source=x",
+            ),
+            (
+                "https://example.com/#/route?tab=2",
+                "https://example.com/#/route?tab=2",
+            ),
+        ];
+        for (input, expected) in test_cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+
+        sanitizer.keep_text_fragments = true;
+        let url = Url::parse("https://example.com/page#xtor=RSS-1:~:text=quote").unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://example.com/page#:~:text=quote"
+        );
+    }
+
+    #[test]
+    fn test_strip_tracking_and_referral() {
+        let mut sanitizer = test_sanitizer();
+        let url = Url::parse(
+            "https://www.amazon.co.uk/dp/B0C1234567?tag=creator-21&pd_rd_r=abc&ref_=sr_1_1&th=1",
+        )
+        .unwrap();
+        let test_cases = vec![
+            (
+                true,
+                false,
+                "https://www.amazon.co.uk/dp/B0C1234567?tag=creator-21&th=1",
+            ),
+            (
+                false,
+                true,
+                "https://www.amazon.co.uk/dp/B0C1234567?pd_rd_r=abc&ref_=sr_1_1&th=1",
+            ),
+            (true, true, "https://www.amazon.co.uk/dp/B0C1234567?th=1"),
+            (false, false, url.as_str()),
+        ];
+
+        for (tracking, referral, expected) in test_cases {
+            sanitizer.params = RuleParams::new(tracking, referral);
+            assert_eq!(
+                sanitizer.sanitize(&url).as_str(),
+                expected,
+                "tracking {} referral {}",
+                tracking,
+                referral
+            );
+        }
+
+        // Generic referral codes are stripped on other domains too
+        let url = Url::parse("https://example.com/?ref=producthunt&utm_source=x").unwrap();
+        sanitizer.params = RuleParams::new(true, false);
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://example.com/?ref=producthunt"
+        );
+        sanitizer.params = RuleParams::new(false, true);
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://example.com/?utm_source=x"
+        );
+        assert!(!is_amazon("amazon.evil.example.com"));
+    }
+
+    #[test]
+    fn test_social_rules() {
+        let sanitizer = test_sanitizer();
+        let cases = [
+            (
+                "https://www.facebook.com/photo/?fbid=123&set=a.1&mibextid=abc&__cft__%5B0%5D=AZ&__cft__%5B1%5D=B&__tn__=R&utm_source=x",
+                "https://www.facebook.com/photo/?fbid=123&set=a.1",
+            ),
+            // The site's redirect links are unwrapped without UNWRAP_REDIRECTS
+            (
+                "https://l.facebook.com/l.php?u=https%3A%2F%2Fexample.com%2F%3Futm_source%3Dx%26id%3D1&h=AT0",
+                "https://example.com/?id=1",
+            ),
+            (
+                "https://www.instagram.com/reel/abc/?igsh=xyz&utm_source=ig_web_copy_link",
+                "https://www.instagram.com/reel/abc/",
+            ),
+            // img_index picks the image of a carousel the link opens
+            (
+                "https://www.instagram.com/p/abc/?img_index=2&igsh=xyz",
+                "https://www.instagram.com/p/abc/?img_index=2",
+            ),
+            (
+                "https://x.com/user/status/1?s=20&t=abc&ref_src=twsrc%5Etfw",
+                "https://x.com/user/status/1",
+            ),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+
+        let sanitizer = Sanitizer::from_config(&HashMap::from([(
+            "TWITTER_HOST".to_string(),
+            "Twitter.com".to_string(),
+        )]));
+        for input in [
+            "https://x.com/user/status/1?s=20",
+            "https://www.twitter.com/user/status/1?t=abc",
+        ] {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(
+                sanitizer.sanitize(&url).as_str(),
+                "https://twitter.com/user/status/1",
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_google_rule() {
+        let mut sanitizer = test_sanitizer();
+        let cases = [
+            (
+                "https://www.google.com/search?q=rust&sca_esv=1&ei=abc&ved=0ahUK&oq=rus&gs_lp=Egx&sclient=gws-wiz&tbm=nws",
+                "https://www.google.com/search?q=rust&tbm=nws",
+            ),
+            (
+                "https://www.google.co.uk/url?sa=t&rct=j&q=&esrc=s&url=https%3A%2F%2Fexample.com%2F%3Futm_source%3Dx%26id%3D1&ved=2ah&usg=AOv",
+                "https://example.com/?id=1",
+            ),
+            (
+                "https://google.de/url?q=https://example.com/page&sa=U&ved=2ah&usg=AOv",
+                "https://example.com/page",
+            ),
+            (
+                "https://news.google.com/rss/articles/CBMiKWh0dHBzOi8vZXhhbXBsZS5jb20vYXJ0aWNsZT91dG1fc291cmNlPWdu0gEA?oc=5",
+                "https://example.com/article",
+            ),
+            (
+                "https://news.google.com/articles/CBMijAFodHRwczovL2V4YW1wbGUuY29tL2FhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYWFhYQ",
+                "https://example.com/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            ),
+            // Newer article IDs can't be decoded, so only their params are stripped
+            (
+                "https://news.google.com/articles/AU_yqLNewFormat?hl=en-US&gl=US&utm_source=x",
+                "https://news.google.com/articles/AU_yqLNewFormat?hl=en-US&gl=US",
+            ),
+            // Other Google services keep params like client and source
+            (
+                "https://docs.google.com/document/d/abc/edit?usp=sharing&source=x",
+                "https://docs.google.com/document/d/abc/edit?usp=sharing&source=x",
+            ),
+            (
+                "https://www.google.example.com/search?ved=1",
+                "https://www.google.example.com/search?ved=1",
+            ),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+
+        // Result links count against MAX_UNWRAP_HOPS like other wrappers
+        sanitizer.max_unwrap_hops = 0;
+        let url =
+            Url::parse("https://www.google.com/url?q=https://example.com/&sa=D&usg=x").unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://www.google.com/url?q=https://example.com/"
+        );
+    }
+
+    #[test]
+    fn test_pipeline() {
+        /// AddCampaign tags every URL, to show whether the params stage ran after it
+        struct AddCampaign;
+
+        impl Transform for AddCampaign {
+            fn name(&self) -> &str {
+                "add_campaign"
+            }
+
+            fn apply(&self, url: &Url) -> Option<Url> {
+                let mut url = url.clone();
+                url.query_pairs_mut().append_pair("utm_campaign", "x");
+                Some(url)
+            }
+        }
+
+        let url = Url::parse("https://en.m.wikipedia.org/wiki/Rust?utm_source=x").unwrap();
+        let mut config = HashMap::from([("DESKTOP_HOSTS".to_string(), "true".to_string())]);
+        // Custom transforms run last unless PIPELINE lists them
+        let sanitizer = Sanitizer::from_config(&config).with_transform(AddCampaign);
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://en.wikipedia.org/wiki/Rust?utm_campaign=x"
+        );
+
+        // Stages left out of PIPELINE don't run
+        config.insert(
+            "PIPELINE".to_string(),
+            "add_campaign, shortener, params".to_string(),
+        );
+        let sanitizer = Sanitizer::from_config(&config).with_transform(AddCampaign);
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://en.m.wikipedia.org/wiki/Rust"
+        );
+        let mut trace = Trace::enabled();
+        sanitizer.strip_tracking(&url, &mut trace);
+        assert_eq!(trace.steps[0].0, Stage::Custom);
+
+        // A listed custom stage that was never added is skipped
+        let sanitizer = Sanitizer::from_config(&config);
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://en.m.wikipedia.org/wiki/Rust"
+        );
+    }
+
+    #[test]
+    fn test_host_rewrites() {
+        let mut config = HashMap::from([
+            (
+                "HOST_REWRITES.old.example.com".to_string(),
+                "new.example.com".to_string(),
+            ),
+            // An entry for a host itself keeps its mobile label
+            (
+                "HOST_REWRITES.m.example.org".to_string(),
+                "m.example.org".to_string(),
+            ),
+        ]);
+        let without_desktop_hosts = Sanitizer::from_config(&config);
+        config.insert("DESKTOP_HOSTS".to_string(), "true".to_string());
+        let sanitizer = Sanitizer::from_config(&config);
+        let cases = [
+            (
+                "https://en.m.wikipedia.org/wiki/Rust?utm_source=x",
+                "https://en.m.wikipedia.org/wiki/Rust",
+                "https://en.wikipedia.org/wiki/Rust",
+            ),
+            (
+                "https://mobile.twitter.com/user/status/1?s=20",
+                // The twitter rule only matches twitter.com itself
+                "https://mobile.twitter.com/user/status/1?s=20",
+                "https://twitter.com/user/status/1",
+            ),
+            (
+                "https://www.old.example.com/page?utm_medium=x&id=1",
+                "https://www.new.example.com/page?id=1",
+                "https://www.new.example.com/page?id=1",
+            ),
+            (
+                "https://m.example.org/",
+                "https://m.example.org/",
+                "https://m.example.org/",
+            ),
+            (
+                "https://mobile.de/auto",
+                "https://mobile.de/auto",
+                "https://mobile.de/auto",
+            ),
+        ];
+        for (input, without_desktop, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+            assert_eq!(
+                without_desktop_hosts.sanitize(&url).as_str(),
+                without_desktop,
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            desktop_host("www.m.example.co"),
+            Some("www.example.co".to_string())
+        );
+        assert_eq!(desktop_host("m.co"), None);
+    }
+
+    #[test]
+    fn test_amazon_canonical() {
+        let mut sanitizer = test_sanitizer();
+        let product = "https://www.amazon.de/Some-Long-Product-Name/dp/b0c1234567/ref=sr_1_1?keywords=x&psc=1&tag=creator-21&pd_rd_r=abc";
+        let url = Url::parse(product).unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://www.amazon.de/Some-Long-Product-Name/dp/b0c1234567/ref=sr_1_1?keywords=x&psc=1&tag=creator-21"
+        );
+
+        sanitizer.amazon_canonical = true;
+        let cases = [
+            (
+                product,
+                "https://www.amazon.de/dp/B0C1234567?tag=creator-21",
+            ),
+            (
+                "https://amazon.co.jp/gp/product/B0C1234567?th=1",
+                "https://www.amazon.co.jp/dp/B0C1234567",
+            ),
+            (
+                "https://www.amazon.com/gp/aw/d/B0C1234567/?linkCode=ll1",
+                "https://www.amazon.com/dp/B0C1234567?linkCode=ll1",
+            ),
+            (
+                "https://www.amazon.com/exec/obidos/ASIN/0123456789/ref=nosim",
+                "https://www.amazon.com/dp/0123456789",
+            ),
+            // Pages other than products only get the amazon rule
+            (
+                "https://www.amazon.com/s?k=book&crid=abc",
+                "https://www.amazon.com/s?k=book",
+            ),
+            (
+                "https://www.amazon.com/dp/short?crid=abc",
+                "https://www.amazon.com/dp/short",
+            ),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+
+        sanitizer.params = RuleParams::new(true, true);
+        let url = Url::parse(product).unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://www.amazon.de/dp/B0C1234567"
+        );
+    }
+
+    #[test]
+    fn test_shortener_action() {
+        let mut sanitizer = test_sanitizer();
+        let url = Url::parse("https://bit.ly/abc?utm_source=x&id=1").unwrap();
+        let test_cases = vec![
+            ("strip_params", "https://bit.ly/abc?id=1"),
+            ("warn", "https://bit.ly/abc?utm_source=x&id=1"),
+            ("ignore", "https://bit.ly/abc?utm_source=x&id=1"),
+            ("", "https://bit.ly/abc?id=1"),
+        ];
+
+        for (action, expected) in test_cases {
+            sanitizer.shortener_action = ShortenerAction::from_setting(Some(action.to_string()));
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", action);
+        }
+
+        // Other domains aren't affected
+        sanitizer.shortener_action = ShortenerAction::Ignore;
+        let url = Url::parse("https://example.com/?utm_source=x").unwrap();
+        assert_eq!(sanitizer.sanitize(&url).as_str(), "https://example.com/");
+    }
+
+    /// wait_for_expansions waits until the sanitizer's worker has finished expanding a short link
+    fn wait_for_expansions(sanitizer: &Sanitizer) {
+        let started = Instant::now();
+        while !sanitizer.expansions_finished() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "expansion never finished"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_expand_shorteners() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        assert!(
+            std::process::Command::new("curl")
+                .arg("--version")
+                .output()
+                .is_ok(),
+            "curl is needed to test expanding short links"
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let response = if request.starts_with("HEAD /short?") {
+                    format!(
+                        "HTTP/1.1 301 Moved Permanently\r\nLocation: http://localhost:{}/article?utm_source=x&id=1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        port
+                    )
+                } else {
+                    "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let sanitizer = Sanitizer::from_config(&HashMap::from([
+            ("SHORTENER_ACTION".to_string(), "expand".to_string()),
+            (
+                "EXTRA_SHORTENER_DOMAINS".to_string(),
+                "localhost".to_string(),
+            ),
+            ("EXPAND_TIMEOUT_MS".to_string(), "5000".to_string()),
+        ]));
+        // The params are stripped right away, and the destination is used once the link has been expanded
+        let url = Url::parse(&format!("http://localhost:{}/short?utm_source=y", port)).unwrap();
+        let expected = format!("http://localhost:{}/short", port);
+        assert_eq!(sanitizer.sanitize(&url).as_str(), expected);
+        wait_for_expansions(&sanitizer);
+        let expected = format!("http://localhost:{}/article?id=1", port);
+        assert_eq!(sanitizer.sanitize(&url).as_str(), expected);
+        assert_eq!(
+            sanitizer.sanitize_expanded(&format!("see {}", url)),
+            Some(format!("see {}", expected))
+        );
+        server.join().unwrap();
+
+        // Nothing listens on the port anymore, so expanding fails and the short link's params stay stripped
+        let url = Url::parse(&format!(
+            "http://localhost:{}/short?utm_source=y&id=2",
+            port
+        ))
+        .unwrap();
+        let expected = format!("http://localhost:{}/short?id=2", port);
+        assert_eq!(sanitizer.sanitize(&url).as_str(), expected);
+        wait_for_expansions(&sanitizer);
+        assert_eq!(sanitizer.sanitize(&url).as_str(), expected);
+        assert_eq!(sanitizer.errors().total(), 0);
+
+        // A server that never answers times out, without holding up sanitizing
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sanitizer = Sanitizer::from_config(&HashMap::from([
+            ("SHORTENER_ACTION".to_string(), "expand".to_string()),
+            (
+                "EXTRA_SHORTENER_DOMAINS".to_string(),
+                "localhost".to_string(),
+            ),
+            ("EXPAND_TIMEOUT_MS".to_string(), "200".to_string()),
+        ]));
+        let url = Url::parse(&format!("http://localhost:{}/short", port)).unwrap();
+        let started = Instant::now();
+        assert_eq!(sanitizer.sanitize(&url), url);
+        assert!(started.elapsed() < Duration::from_millis(100));
+        wait_for_expansions(&sanitizer);
+        assert_eq!(sanitizer.sanitize(&url), url);
+        assert_eq!(sanitizer.errors().get(ErrorCategory::NetworkTimeout), 1);
+        drop(listener);
+    }
+
+    #[test]
+    fn test_mixed_case_config_domains() {
+        let mut sanitizer = test_sanitizer();
+        sanitizer.keep_params =
+            parse_domain_params(Some("YouTube.com:si; Example.COM:utm_source".to_string()));
+        let test_cases = vec![
+            (
+                "https://WWW.YOUTUBE.COM/watch?v=1234&si=x&feature=y",
+                "https://youtu.be/1234?si=x",
+            ),
+            (
+                "https://Sub.Example.com/?utm_source=x&utm_medium=y",
+                "https://sub.example.com/?utm_source=x",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn test_keep_params_beat_domain_strip() {
+        let mut sanitizer = test_sanitizer();
+        sanitizer.keep_params = parse_domain_params(Some(
+            "example.com:utm_campaign; twitter.com:t ;news.example.com:utm_source".to_string(),
+        ));
+
+        let test_cases = vec![
+            (
+                "https://example.com/?utm_source=a&utm_campaign=b&utm_medium=c",
+                "https://example.com/?utm_campaign=b",
+            ),
+            (
+                "https://shop.example.com/?utm_source=a&utm_campaign=b",
+                "https://shop.example.com/?utm_campaign=b",
+            ),
+            // The most specific domain wins, so utm_campaign isn't protected here
+            (
+                "https://news.example.com/?utm_source=a&utm_campaign=b",
+                "https://news.example.com/?utm_source=a",
+            ),
+            (
+                "https://twitter.com/discord/status/1702362670836621351?t=kept&s=19",
+                "https://twitter.com/discord/status/1702362670836621351?t=kept",
+            ),
+            (
+                "https://example.org/?utm_source=a&utm_campaign=b",
+                "https://example.org/",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let url = Url::parse(input).unwrap();
+            let stripped_url = sanitizer.strip_tracking(&url, &mut Trace::default());
+            assert_eq!(stripped_url.as_str(), expected, "Stripped URL is incorrect");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_post_process() {
+        let url = Url::parse("https://example.com/?utm_source=foo").unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let mut sanitizer = test_sanitizer();
+        sanitizer.post_process_cmd = Some("cat".to_string());
+        sanitizer.post_process_timeout = timeout;
+        assert_eq!(sanitizer.sanitize(&url).as_str(), "https://example.com/");
+
+        // URLs without tracking aren't passed to the command
+        sanitizer.post_process_cmd = Some("echo https://example.net/".to_string());
+        let clean_url = Url::parse("https://example.com/?id=1").unwrap();
+        assert_eq!(sanitizer.sanitize(&clean_url), clean_url);
+        assert_eq!(sanitizer.sanitize(&url).as_str(), "https://example.net/");
+
+        let test_cases = vec![
+            ("sed 's/example.com/example.org/'", "https://example.org/"),
+            ("echo https://example.net/", "https://example.net/"),
+            // Failures fall back to the URL from before the command
+            ("exit 1", "https://example.com/"),
+            ("echo not a url", "https://example.com/"),
+            ("true", "https://example.com/"),
+        ];
+        let stripped_url = Url::parse("https://example.com/").unwrap();
+        let errors = ErrorMetrics::default();
+        for (cmd, expected) in test_cases {
+            assert_eq!(
+                post_process(cmd, &stripped_url, timeout, &errors).as_str(),
+                expected,
+                "Post-processed URL is incorrect for {}",
+                cmd
+            );
+        }
+
+        let started = Instant::now();
+        let result = post_process(
+            "sleep 10",
+            &stripped_url,
+            Duration::from_millis(100),
+            &errors,
+        );
+        assert_eq!(result, stripped_url);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_deamp() {
+        let sanitizer =
+            Sanitizer::from_config(&HashMap::from([("DEAMP".to_string(), "true".to_string())]));
+        let cases = [
+            (
+                "https://example-com.cdn.ampproject.org/c/s/example.com/news/article?utm_source=x&id=1",
+                "https://example.com/news/article?id=1",
+            ),
+            (
+                "https://example-com.cdn.ampproject.org/v/example.com/article",
+                "http://example.com/article",
+            ),
+            (
+                "https://www.google.com/amp/s/www.example.com/article/amp?utm_medium=y",
+                "https://www.example.com/article",
+            ),
+            ("https://example.com/article/amp/", "https://example.com/article"),
+            // Nothing to work the page out from, so only the params are stripped
+            (
+                "https://example-com.cdn.ampproject.org/?utm_source=x",
+                "https://example-com.cdn.ampproject.org/",
+            ),
+            (
+                "https://www.google.com/amp/s/?utm_source=x",
+                "https://www.google.com/amp/s/",
+            ),
+            ("https://example.com/amp?utm_source=x", "https://example.com/amp"),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+
+        let url = Url::parse("https://example.com/article/amp").unwrap();
+        assert_eq!(
+            test_sanitizer().sanitize(&url).as_str(),
+            "https://example.com/article/amp"
+        );
+    }
+
+    #[test]
+    fn test_clearurls_file() {
+        let path = std::env::temp_dir().join(format!(
+            "clipboard-sanitizer-clearurls-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"{"providers": {
+                "youtube": {"urlPattern": "^https?://(?:[a-z0-9-]+\\.)*?(?:youtube\\.com|youtu\\.be)", "rules": ["pp"]},
+                "out": {"urlPattern": "^https?://out\\.example\\.com", "redirections": ["^https?://out\\.example\\.com/\\?to=([^&]*)"]}
+            }}"#,
+        )
+        .unwrap();
+        let sanitizer = Sanitizer::from_config(&HashMap::from([(
+            "CLEARURLS_FILE".to_string(),
+            path.to_string_lossy().to_string(),
+        )]));
+        std::fs::remove_file(&path).unwrap();
+
+        let cases = [
+            // The ClearURLs rules apply after the built-in rule
+            (
+                "https://www.youtube.com/watch?v=1234&pp=x&si=y",
+                "https://youtu.be/1234",
+            ),
+            (
+                "https://www.youtube.com/results?search_query=a&pp=x",
+                "https://www.youtube.com/results?search_query=a",
+            ),
+            (
+                "https://out.example.com/?to=https%3A%2F%2Fexample.org%2F%3Futm_source%3Dx",
+                "https://example.org/",
+            ),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_rules_table() {
+        let sanitizer = Sanitizer::from_config(&HashMap::from([
+            ("RULES.Example.com.params".to_string(), "ref".to_string()),
+            ("DOMAINS.example.com".to_string(), "id".to_string()),
+            ("RULES.*.example.org.params".to_string(), "ref".to_string()),
+            ("RULES.*.example.org.common".to_string(), "true".to_string()),
+            ("RULES.youtube.com.params".to_string(), "t".to_string()),
+            ("RULES.youtube.com.extend".to_string(), "true".to_string()),
+            (
+                "RULES.example.net.patterns".to_string(),
+                "^mc_.*,^vero_,igshid".to_string(),
+            ),
+            ("RULES.example.net.params".to_string(), "fbclid".to_string()),
+        ]));
+        let cases = [
+            // The RULES rule replaces both the common params and the DOMAINS rule for the same domain
+            (
+                "https://www.example.com/?ref=a&utm_source=b&id=1",
+                "https://www.example.com/?utm_source=b&id=1",
+            ),
+            // *. only matches subdomains, and common adds the common params
+            (
+                "https://app.example.org/?ref=a&utm_source=b&id=1",
+                "https://app.example.org/?id=1",
+            ),
+            (
+                "https://example.org/?ref=a&utm_source=b&id=1",
+                "https://example.org/?ref=a&id=1",
+            ),
+            // patterns are regexes, except for plain names that only match the param itself
+            (
+                "https://example.net/?mc_eid=1&vero_conv=2&igshid=3&igshid_x=4&fbclid=5&id=6",
+                "https://example.net/?igshid_x=4&id=6",
+            ),
+            // extend strips the params after the built-in YouTube rule
+            (
+                "https://www.youtube.com/watch?v=abc&si=x&t=10",
+                "https://youtu.be/abc",
+            ),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_only_and_skip_domains() {
+        let url = |text: &str| Url::parse(text).unwrap();
+        let skip = Sanitizer::from_config(&HashMap::from([(
+            "SKIP_DOMAINS".to_string(),
+            "Intranet.example.com".to_string(),
+        )]));
+        for input in [
+            "https://intranet.example.com/?utm_source=x",
+            "https://app.intranet.example.com/?utm_source=x",
+        ] {
+            assert_eq!(skip.sanitize(&url(input)).as_str(), input);
+        }
+        assert_eq!(
+            skip.sanitize(&url("https://example.com/?utm_source=x"))
+                .as_str(),
+            "https://example.com/"
+        );
+        assert_eq!(
+            skip.sanitize(&url("https://notintranet.example.com/?utm_source=x"))
+                .as_str(),
+            "https://notintranet.example.com/"
+        );
+
+        let only = Sanitizer::from_config(&HashMap::from([
+            (
+                "ONLY_DOMAINS".to_string(),
+                "youtube.com,example.com".to_string(),
+            ),
+            (
+                "SKIP_DOMAINS".to_string(),
+                "intranet.example.com".to_string(),
+            ),
+        ]));
+        let cases = [
+            ("https://example.com/?utm_source=x", "https://example.com/"),
+            (
+                "https://www.example.com/?utm_source=x",
+                "https://www.example.com/",
+            ),
+            (
+                "https://intranet.example.com/?utm_source=x",
+                "https://intranet.example.com/?utm_source=x",
+            ),
+            (
+                "https://example.org/?utm_source=x",
+                "https://example.org/?utm_source=x",
+            ),
+            // The youtu.be URL a youtube.com URL is rewritten to is still cleaned
+            (
+                "https://www.youtube.com/watch?v=abc&si=x",
+                "https://youtu.be/abc",
+            ),
+            ("https://youtu.be/abc?si=x", "https://youtu.be/abc?si=x"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(only.sanitize(&url(input)).as_str(), expected, "{}", input);
+        }
+
+        // Destinations that a rule unwraps or rewrites to are left alone on skipped domains
+        let skip = Sanitizer::from_config(&HashMap::from([
+            ("SKIP_DOMAINS".to_string(), "skipped.example".to_string()),
+            (
+                "HOST_REWRITES.old.example".to_string(),
+                "skipped.example".to_string(),
+            ),
+        ]));
+        let cases = [
+            (
+                "https://www.google.com/url?q=https://skipped.example/?utm_source%3Dx",
+                "https://skipped.example/?utm_source=x",
+            ),
+            (
+                "https://l.facebook.com/l.php?u=https%3A%2F%2Fskipped.example%2F%3Futm_source%3Dx&h=AT0",
+                "https://skipped.example/?utm_source=x",
+            ),
+            (
+                "https://old.example/?utm_source=x",
+                "https://skipped.example/?utm_source=x",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(skip.sanitize(&url(input)).as_str(), expected, "{}", input);
+        }
+
+        let patterns = Sanitizer::from_config(&HashMap::from([(
+            "SKIP_DOMAINS".to_string(),
+            "*.corp.example,intranet.*".to_string(),
+        )]));
+        let cases = [
+            (
+                "https://corp.example/?utm_source=x",
+                "https://corp.example/",
+            ),
+            (
+                "https://wiki.corp.example/?utm_source=x",
+                "https://wiki.corp.example/?utm_source=x",
+            ),
+            (
+                "https://intranet.example.org/?utm_source=x",
+                "https://intranet.example.org/?utm_source=x",
+            ),
+            (
+                "https://www.intranet.example.org/?utm_source=x",
+                "https://www.intranet.example.org/",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                patterns.sanitize(&url(input)).as_str(),
+                expected,
+                "{}",
+                input
+            );
+        }
+        assert!(glob_matches("a.b.c", "*.c"));
+        assert!(!glob_matches("c", "*c"));
+        assert!(glob_matches("ab", "a*"));
+    }
+
+    #[test]
+    fn test_keep_params_everywhere() {
+        let sanitizer = Sanitizer::from_config(&HashMap::from([
+            ("KEEP_PARAMS".to_string(), "utm_campaign".to_string()),
+            (
+                "DOMAIN_KEEP_PARAMS".to_string(),
+                "*.example.com:utm_source".to_string(),
+            ),
+        ]));
+        let cases = [
+            (
+                "https://example.org/?utm_campaign=a&utm_source=b",
+                "https://example.org/?utm_campaign=a",
+            ),
+            (
+                "https://example.com/?utm_campaign=a&utm_source=b",
+                "https://example.com/?utm_campaign=a",
+            ),
+            (
+                "https://www.example.com/?utm_campaign=a&utm_source=b&utm_medium=c",
+                "https://www.example.com/?utm_campaign=a&utm_source=b",
+            ),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_overrides_learn_only_removed_params() {
+        let path = std::env::temp_dir().join(format!(
+            "clipboard-sanitizer-learned-removed-{}.txt",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let sanitizer = Sanitizer::from_config(&HashMap::from([
+            ("LEARN_EXCEPTIONS".to_string(), "true".to_string()),
+            (
+                "LEARNED_EXCEPTIONS_FILE".to_string(),
+                path.to_string_lossy().to_string(),
+            ),
+        ]));
+        // v is moved into the youtu.be path and the google.com wrapper is unwrapped, none of its params were stripped
+        for _ in 0..2 {
+            sanitizer.record_override("https://www.youtube.com/watch?v=abc&si=x");
+            sanitizer.record_override(
+                "https://www.google.com/url?q=https://example.com/?utm_source%3Dx&sa=D",
+            );
+        }
+        let learner = sanitizer.learner.as_ref().unwrap().lock().unwrap();
+        let learned: Vec<&String> = learner.keep_params("www.youtube.com").collect();
+        assert_eq!(learned, vec!["si"]);
+        assert_eq!(learner.keep_params("www.google.com").count(), 0);
+        assert_eq!(learner.keep_params("example.com").count(), 0);
+        drop(learner);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_disabled_rules() {
+        let mut sanitizer = test_sanitizer();
+        sanitizer.disabled_rules = parse_disabled_rules(Some("YouTube, unknown".to_string()));
+        assert_eq!(sanitizer.disabled_rules, vec!["youtube"]);
+
+        // YouTube links only get the common params stripped
+        let cases = [
+            (
+                "https://www.youtube.com/watch?v=abc&si=x&utm_source=y",
+                "https://www.youtube.com/watch?v=abc&si=x",
+            ),
+            (
+                "https://youtu.be/abc?feature=shared&utm_medium=z",
+                "https://youtu.be/abc?feature=shared",
+            ),
+            (
+                "https://www.youtube.com/shorts/abc?utm_source=y",
+                "https://www.youtube.com/shorts/abc",
+            ),
+            (
+                "https://x.com/user/status/1?s=20",
+                "https://x.com/user/status/1",
+            ),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+
+        sanitizer.disabled_rules.push("common".to_string());
+        let url = Url::parse("https://www.youtube.com/watch?v=abc&utm_source=y").unwrap();
+        assert_eq!(sanitizer.sanitize(&url), url);
+    }
+
+    #[test]
+    fn test_strip_calendar_and_zoom() {
+        let sanitizer = test_sanitizer();
+        let cases = [
+            (
+                "https://calendar.google.com/calendar/event?eid=NWZ0cXQ5&ctz=Europe/Helsinki&utm_source=newsletter&gclid=abc",
+                "https://calendar.google.com/calendar/event?eid=NWZ0cXQ5&ctz=Europe/Helsinki",
+            ),
+            (
+                "https://calendar.google.com/calendar/render?action=TEMPLATE&text=Launch&dates=20240101T100000Z/20240101T110000Z&src=team&utm_campaign=x",
+                "https://calendar.google.com/calendar/render?action=TEMPLATE&text=Launch&dates=20240101T100000Z/20240101T110000Z&src=team",
+            ),
+            // Marketing and pk_ params are left alone here, only utm_ and gclid are stripped
+            (
+                "https://calendar.google.com/calendar/event?eid=abc&_hsenc=x&pk_campaign=y",
+                "https://calendar.google.com/calendar/event?eid=abc&_hsenc=x&pk_campaign=y",
+            ),
+            (
+                "https://us02web.zoom.us/webinar/register/WN_abc123?tk=regtoken&utm_medium=email&gclid=x",
+                "https://us02web.zoom.us/webinar/register/WN_abc123?tk=regtoken",
+            ),
+            (
+                "https://zoom.us/j/123456789?pwd=secret&uname=Guest&utm_source=calendar",
+                "https://zoom.us/j/123456789?pwd=secret&uname=Guest",
+            ),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+        assert!(!is_zoom("notzoom.us"));
+    }
+
+    #[test]
+    fn test_protected_params_beat_extra_tracking_params() {
+        let sanitizer = Sanitizer::from_config(&HashMap::from([(
+            "EXTRA_TRACKING_PARAMS".to_string(),
+            "fbclid,eid,tk".to_string(),
+        )]));
+        let url =
+            Url::parse("https://calendar.google.com/calendar/event?eid=abc&fbclid=x").unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://calendar.google.com/calendar/event?eid=abc"
+        );
+        let url =
+            Url::parse("https://zoom.us/webinar/register/WN_abc?tk=regtoken&fbclid=x").unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://zoom.us/webinar/register/WN_abc?tk=regtoken"
+        );
+    }
+
+    #[test]
+    fn test_extra_tracking_params_remove_prefixed_params() {
+        let sanitizer = Sanitizer::from_config(&HashMap::from([(
+            "EXTRA_TRACKING_PARAMS".to_string(),
+            "gclid,fbclid,-utm_term".to_string(),
+        )]));
+        let url = Url::parse("https://example.com/?utm_term=a&utm_source=b&gclid=c").unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://example.com/?utm_term=a"
+        );
+    }
+
+    #[test]
+    fn test_tracking_score() {
+        let sanitizer = test_sanitizer();
+        let url =
+            Url::parse("https://example.com/?utm_source=x&utm_medium=y&_hsenc=z&id=1").unwrap();
+        sanitizer.sanitize_clipboard(url.as_str());
+        // Only clipboard content is recorded
+        sanitizer.sanitize(&url);
+        sanitizer.explain(url.as_str());
+        // The video ID moves into the path, and the keys of unwrapped redirects aren't tracking
+        sanitizer.sanitize_clipboard("https://www.youtube.com/watch?v=abc&si=x");
+        sanitizer.sanitize_clipboard("https://www.google.com/url?q=https://example.org/&sa=D");
+
+        let stats = sanitizer.tracking.to_string();
+        assert!(
+            stats.starts_with("stripped_tracking 3\nstripped_marketing 1\nstripped_referral 0\n")
+        );
+        assert!(stats.ends_with("score_example.com 4\nscore_www.youtube.com 1\n"));
+    }
+
+    #[test]
+    fn test_protocol_relative() {
+        let mut sanitizer = test_sanitizer();
+        let url = "//example.com/path?utm_source=x&id=1";
+        assert_eq!(sanitizer.sanitize_clipboard(url), None);
+
+        sanitizer.process_protocol_relative = true;
+        assert_eq!(
+            sanitizer.sanitize_clipboard(url).as_deref(),
+            Some("//example.com/path?id=1")
+        );
+        assert_eq!(
+            sanitizer
+                .sanitize_clipboard(&format!("link: {}.", url))
+                .as_deref(),
+            Some("link: //example.com/path?id=1.")
+        );
+        assert_eq!(
+            sanitizer
+                .sanitize_clipboard("\"//example.com/?utm_medium=y\"")
+                .as_deref(),
+            Some("\"//example.com/\"")
+        );
+        assert_eq!(
+            sanitizer.sanitize_clipboard("//example.com/path?id=1"),
+            None
+        );
+        assert_eq!(sanitizer.sanitize_clipboard("// a comment"), None);
+    }
+
+    #[test]
+    fn test_unwrap_redirects() {
+        let mut sanitizer = test_sanitizer();
+        let wrapped = "https://nam12.safelinks.protection.outlook.com/?url=https%3A%2F%2Fwww.google.com%2Furl%3Fq%3Dhttps%253A%252F%252Fexample.com%252F%253Futm_source%253Dx%2526id%253D1&data=abc";
+        let url = Url::parse(wrapped).unwrap();
+        assert_eq!(sanitizer.sanitize(&url), url);
+
+        sanitizer.unwrap_redirects = true;
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://example.com/?id=1"
+        );
+        sanitizer.max_unwrap_hops = 1;
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://www.google.com/url?q=https%3A%2F%2Fexample.com%2F%3Futm_source%3Dx%26id%3D1"
+        );
+
+        sanitizer.max_unwrap_hops = 5;
+        for wrapped in [
+            "https://lm.facebook.com/l.php?u=https%3A%2F%2Fexample.com%2F%3Futm_source%3Dfb&h=AT0",
+            "https://l.instagram.com/?u=https%3A%2F%2Fexample.com%2F&e=AT1",
+            "https://www.youtube.com/redirect?event=video_description&q=https%3A%2F%2Fexample.com%2F",
+            "https://duckduckgo.com/l/?uddg=https%3A%2F%2Fexample.com%2F%3Futm_medium%3Dx&rut=abc",
+            "https://slack-redir.net/link?url=https%3A%2F%2Fexample.com%2F",
+        ] {
+            let url = Url::parse(wrapped).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), "https://example.com/");
+        }
+    }
+
+    #[test]
+    fn test_nested_urls() {
+        let mut sanitizer = test_sanitizer();
+        let wrapped = "https://accounts.example.com/login?continue=https%3A%2F%2Fexample.com%2Fpage%3Futm_source%3Dx%26id%3D1&hl=en&utm_medium=email";
+        let url = Url::parse(wrapped).unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://accounts.example.com/login?continue=https%3A%2F%2Fexample.com%2Fpage%3Futm_source%3Dx%26id%3D1&hl=en"
+        );
+
+        sanitizer.strip_nested_urls = true;
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://accounts.example.com/login?continue=https%3A%2F%2Fexample.com%2Fpage%3Fid%3D1&hl=en"
+        );
+        // Nested URLs on skipped domains are left alone
+        sanitizer.skip_domains = vec!["skipped.example".to_string()];
+        let input =
+            "https://example.com/?u=https%3A%2F%2Fskipped.example%2F%3Futm_source%3Dx&utm_source=y";
+        assert_eq!(
+            sanitizer.sanitize(&Url::parse(input).unwrap()).as_str(),
+            "https://example.com/?u=https%3A%2F%2Fskipped.example%2F%3Futm_source%3Dx"
+        );
+        sanitizer.skip_domains.clear();
+
+        // Values that only mention a URL, or aren't encoded, are left alone
+        for input in [
+            "https://example.com/?q=see+https%3A%2F%2Fexample.org%2F%3Futm_source%3Dx",
+            "https://example.com/?next=/page%3Futm_source%3Dx",
+        ] {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), input);
+        }
+
+        // Nesting deeper than MAX_REWRITE_DEPTH is left alone without an error
+        let nest = |levels: usize| {
+            let mut nested = "https://example.com/?utm_source=x".to_string();
+            for _ in 0..levels {
+                nested = format!(
+                    "https://example.com/?url={}",
+                    url::form_urlencoded::byte_serialize(nested.as_bytes()).collect::<String>()
+                );
+            }
+            Url::parse(&nested).unwrap()
+        };
+        assert_eq!(
+            sanitizer.sanitize(&nest(2)).as_str(),
+            "https://example.com/?url=https%3A%2F%2Fexample.com%2F%3Furl%3Dhttps%253A%252F%252Fexample.com%252F"
+        );
+        let url = nest(MAX_REWRITE_DEPTH + 2);
+        assert_eq!(sanitizer.sanitize(&url), url);
+        assert_eq!(sanitizer.errors().get(ErrorCategory::RecursionLimit), 0);
+    }
+
+    #[test]
+    fn test_unwrap_chain_terminates_on_loops() {
+        let a = Url::parse("https://a.example/").unwrap();
+        let b = Url::parse("https://b.example/").unwrap();
+        let c = Url::parse("https://c.example/").unwrap();
+        let redirects = [
+            (a.clone(), b.clone()),
+            (b.clone(), c.clone()),
+            (c.clone(), a.clone()),
+        ];
+        let step = |url: &Url| {
+            redirects
+                .iter()
+                .find(|(from, _)| from == url)
+                .map(|(_, to)| to.clone())
+        };
+
+        // The loop back to a is detected and the last URL before it is returned
+        assert_eq!(unwrap_chain(&a, 10, step, &mut Trace::default()), c);
+        assert_eq!(unwrap_chain(&a, 1, step, &mut Trace::default()), b);
+        assert_eq!(unwrap_chain(&a, 0, step, &mut Trace::default()), a);
+    }
+
+    #[test]
+    fn test_strip_mailto() {
+        let mut sanitizer = test_sanitizer();
+        let link = "mailto:x@y.com?subject=Read%20this&body=Hi%2C%0Ahttps%3A%2F%2Fexample.com%2F%3Futm_source%3Dshare%26id%3D1%20a+b&cc=z@y.com";
+        assert!(sanitizer.parse_text(link).is_none());
+
+        sanitizer.process_mailto = true;
+        let url = sanitizer.parse_text(link).unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "mailto:x@y.com?subject=Read%20this&body=Hi%2C%0Ahttps%3A%2F%2Fexample.com%2F%3Fid%3D1%20a%2Bb&cc=z@y.com"
+        );
+
+        let url = Url::parse("mailto:x@y.com?subject=https://example.com/?id=1").unwrap();
+        assert_eq!(sanitizer.sanitize(&url), url);
+    }
+
+    #[test]
+    fn test_strip_magnet() {
+        let magnet = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Example%20File\
+                      &tr=udp%3A%2F%2Ftracker.example.org%3A1337&tr=udp%3A%2F%2Ftracker2.example.org%3A6969";
+
+        let mut sanitizer = test_sanitizer();
+        assert!(sanitizer.parse_text(magnet).is_none());
+
+        sanitizer.process_magnet = true;
+        sanitizer.magnet_strip_params = TrackingParams::from_defaults(&["tr"]);
+        let url = sanitizer.parse_text(magnet).unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Example%20File"
+        );
+        assert_eq!(
+            sanitizer.sanitize_clipboard(magnet).unwrap(),
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Example%20File"
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut sanitizer = test_sanitizer();
+        sanitizer.keep_params = parse_domain_params(Some("example.org:utm_source".to_string()));
+
+        let test_cases = vec![
+            ("data:text/plain,hello", vec![Stage::Exclusion]),
+            ("not a url", vec![Stage::Exclusion]),
+            (
+                "https://example.com/?utm_source=foo",
+                vec![Stage::CommonParams],
+            ),
+            (
+                "https://example.org/?utm_source=foo",
+                vec![Stage::CommonParams, Stage::KeepParams],
+            ),
+            (
+                "https://www.youtube.com/watch?v=1234&si=foo",
+                vec![Stage::DomainRule, Stage::DomainRule],
+            ),
+            (
+                "https://youtube.com/shorts/xxxxxxxxxx?feature=share",
+                vec![Stage::YoutubePrefix, Stage::DomainRule],
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let trace = sanitizer.explain(input);
+            let stages: Vec<Stage> = trace.steps.iter().map(|(stage, _)| *stage).collect();
+            assert_eq!(stages, expected, "Explanation is incorrect for {}", input);
+        }
+
+        let trace = sanitizer.explain("https://twitter.com/discord/status/1?t=foo&s=19");
+        assert_eq!(trace.steps[0].1, "twitter rule removed [\"t\", \"s\"]");
+        assert_eq!(
+            trace.to_string(),
+            "DomainRule: twitter rule removed [\"t\", \"s\"]\nResult: https://twitter.com/discord/status/1\n"
+        );
+        assert_eq!(
+            trace.result.unwrap().as_str(),
+            "https://twitter.com/discord/status/1"
+        );
+    }
+
+    #[test]
+    fn test_sanitize_text() {
+        let sanitizer = test_sanitizer();
+        let test_cases = vec![
+            (
+                "2023-09-14 shared https://example.com/?utm_source=foo&id=1 with\tbob  ",
+                "2023-09-14 shared https://example.com/?id=1 with\tbob  ",
+            ),
+            (
+                "https://youtu.be/1234?si=x https://twitter.com/a/status/1?s=19",
+                "https://youtu.be/1234 https://twitter.com/a/status/1",
+            ),
+            ("no urls here", "no urls here"),
+            ("", ""),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(sanitizer.sanitize_text(input, false), expected);
+        }
+    }
+
+    #[test]
+    fn test_quoted_urls() {
+        let sanitizer = test_sanitizer();
+        let test_cases = vec![
+            (
+                "\"https://example.com/?utm_source=x&id=1\"",
+                Some("\"https://example.com/?id=1\""),
+            ),
+            (
+                "'https://example.com/?utm_source=x'",
+                Some("'https://example.com/'"),
+            ),
+            ("\"https://example.com/?id=1\"", None),
+            // Mismatched quotes aren't a string literal
+            ("\"https://example.com/?utm_source=x'", None),
+            ("\"", None),
+            (
+                "url = \"https://example.com/?utm_source=x\",",
+                Some("url = \"https://example.com/\","),
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(
+                sanitizer.sanitize_clipboard(input).as_deref(),
+                expected,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_trim_trailing_chars() {
+        let mut sanitizer = test_sanitizer();
+        for trailing in DEFAULT_TRIM_TRAILING_CHARS.chars() {
+            let text = format!("(see https://example.com/?id=1&utm_source=x{}", trailing);
+            assert_eq!(
+                sanitizer.sanitize_text(&text, false),
+                format!("(see https://example.com/?id=1{}", trailing)
+            );
+        }
+        assert_eq!(
+            sanitizer.sanitize_text("(see https://example.com/?utm_source=x).", false),
+            "(see https://example.com/)."
+        );
+
+        sanitizer.trim_trailing_chars = vec!['|'];
+        assert_eq!(
+            sanitizer.sanitize_text("https://example.com/?utm_source=x|", false),
+            "https://example.com/|"
+        );
+        // Without trimming, the full stop ends up in the param value
+        assert_eq!(
+            sanitizer.sanitize_text("https://example.com/?id=1&utm_source=x.", false),
+            "https://example.com/?id=1"
+        );
+    }
+
+    #[test]
+    fn test_multi_url_mode() {
+        let mut sanitizer = test_sanitizer();
+        let text = "see https://example.com/?utm_source=a and https://example.org/?utm_source=b";
+        let test_cases = vec![
+            (
+                "all",
+                Some("see https://example.com/ and https://example.org/"),
+            ),
+            (
+                "first",
+                Some("see https://example.com/ and https://example.org/?utm_source=b"),
+            ),
+            ("off", None),
+        ];
+
+        for (mode, expected) in test_cases {
+            sanitizer.multi_url_mode = MultiUrlMode::from_setting(Some(mode.to_string()));
+            assert_eq!(
+                sanitizer.sanitize_clipboard(text).as_deref(),
+                expected,
+                "{}",
+                mode
+            );
+        }
+
+        // The first URL counts even if it has nothing to strip
+        sanitizer.multi_url_mode = MultiUrlMode::First;
+        assert_eq!(
+            sanitizer.sanitize_clipboard("https://example.com/ https://example.org/?utm_source=b"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_strip_ip_hosts() {
+        let mut sanitizer = test_sanitizer();
+        let test_cases = vec![
+            (
+                "http://[::1]:8080/?utm_source=x&id=1",
+                "http://[::1]:8080/?id=1",
+            ),
+            (
+                "https://[2001:db8::1]/page?utm_medium=y",
+                "https://[2001:db8::1]/page",
+            ),
+            ("http://192.168.1.1/?utm_source=x", "http://192.168.1.1/"),
+        ];
+
+        for (input, _) in &test_cases {
+            assert!(sanitizer.parse_text(input).is_none());
+        }
+        sanitizer.process_ip_hosts = true;
+        for (input, expected) in test_cases {
+            let url = sanitizer.parse_text(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected);
+        }
+
+        sanitizer.keep_params = parse_domain_params(Some("[::1]:utm_source".to_string()));
+        let url = sanitizer.parse_text("http://[::1]/?utm_source=x").unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "http://[::1]/?utm_source=x"
+        );
+    }
+
+    #[test]
+    fn test_parse_text_excluded_schemes() {
+        let sanitizer = test_sanitizer();
+        let test_cases = vec![
+            "data:text/html,<a href=\"https://example.com/?utm_source=foo\">x</a>",
+            "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==",
+            "DATA:text/plain,https://www.youtube.com/watch?v=1234&si=stripped",
+            "blob:https://example.com/550e8400-e29b-41d4-a716-446655440000",
+            "javascript:void(location='https://example.com/?utm_source=foo')",
+        ];
+
+        for input in test_cases {
+            assert!(
+                sanitizer.parse_text(input).is_none(),
+                "Excluded URI was parsed: {}",
+                input
+            );
+        }
+
+        assert!(sanitizer
+            .parse_text("https://example.com/path?utm_source=foo")
+            .is_some());
+    }
+
+    #[test]
+    fn test_canonical_encode() {
+        let equivalent_inputs = vec![
+            "HTTPS://Example.COM/a%7eb/%c3%a4?q=%2f#%7Efrag",
+            "https://example.com/a~b/%C3%A4?q=%2F#~frag",
+            "https://example.com/%61%7E%62/%C3%a4?q=%2F#%7efrag",
+        ];
+
+        for input in equivalent_inputs {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(
+                canonical_encode(&url).as_str(),
+                "https://example.com/a~b/%C3%A4?q=%2F#~frag",
+                "Canonical URL is incorrect"
+            );
+        }
+
+        // Malformed escapes are left alone
+        for input in ["https://example.com/100%zz", "https://example.com/100%"] {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(canonical_encode(&url), url, "Canonical URL is incorrect");
+        }
+    }
+
+    #[test]
+    fn test_strip_tracking_is_idempotent() {
+        let mut sanitizer = test_sanitizer();
+        sanitizer.process_magnet = true;
+        sanitizer.magnet_strip_params = TrackingParams::from_defaults(&["tr"]);
+        sanitizer.params = RuleParams::new(true, true);
+        sanitizer.params.common.add_prefix("ref_");
+
+        let corpus = include_str!("../corpus.txt");
+        for line in corpus.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            for text in line.split_whitespace() {
+                let url = sanitizer.parse_text(text).unwrap();
+                let stripped_url = sanitizer.strip_tracking(&url, &mut Trace::default());
+                assert!(sanitizer.check_idempotent(&url, &stripped_url), "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sanitize_html() {
+        let sanitizer = test_sanitizer();
+        let cases = [
+            (
+                "<a href=\"https://example.com/?utm_source=x&amp;id=1\">https://example.com/?utm_source=x&amp;id=1</a>",
+                "<a href=\"https://example.com/?id=1\">https://example.com/?id=1</a>",
+            ),
+            (
+                "<meta charset='utf-8'><A HREF='https://www.youtube.com/watch?v=abc&amp;si=x'>Video</A>",
+                "<meta charset='utf-8'><A HREF='https://youtu.be/abc'>Video</A>",
+            ),
+            (
+                "<p>Read https://example.com/?utm_source=x and more</p>",
+                "<p>Read https://example.com/ and more</p>",
+            ),
+            // Other character references can't be cleaned without decoding them
+            (
+                "<a href=\"https://example.com/?utm_source=x&amp;q=a&lt;b\">Search</a>",
+                "<a href=\"https://example.com/?utm_source=x&amp;q=a&lt;b\">Search</a>",
+            ),
+            ("<b>Tom & Jerry</b>", "<b>Tom & Jerry</b>"),
+            (
+                "<a href=https://example.com/?utm_source=x>unquoted</a>",
+                "<a href=https://example.com/>unquoted</a>",
+            ),
+            // Only href attributes are cleaned, however many there are, and > in a quoted value doesn't end the tag
+            (
+                "<a data-href=\"https://example.com/?utm_source=x\" title='a > b' href = \"https://example.com/?utm_source=y\">",
+                "<a data-href=\"https://example.com/?utm_source=x\" title='a > b' href = \"https://example.com/\">",
+            ),
+            (
+                "<link href='https://example.com/a?utm_source=x' hreflang=en><area HREF=\"https://example.com/b?utm_source=x\" href=\"https://example.com/c?utm_source=x\"/>",
+                "<link href='https://example.com/a' hreflang=en><area HREF=\"https://example.com/b\" href=\"https://example.com/c\"/>",
+            ),
+            (
+                "<!-- <a href=\"https://example.com/?utm_source=x\"> -->",
+                "<!-- <a href=\"https://example.com/?utm_source=x\"> -->",
+            ),
+            ("<a href=\"https://example.com/?utm_source=x", "<a href=\"https://example.com/"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(sanitizer.sanitize_html(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_sanitize_tabular() {
+        let sanitizer = test_sanitizer();
+
+        let tsv = "name\tlink\tnote\n\
+                   foo\thttps://example.com/a?utm_source=x&id=1\tkeep me\n\
+                   bar\thttps://example.com/b\t\"quoted\ttab\"\n\
+                   baz\thttps://www.youtube.com/watch?v=1234&si=x\t\n";
+        assert_eq!(
+            sanitizer.sanitize_tabular(tsv, true).unwrap(),
+            "name\tlink\tnote\n\
+             foo\thttps://example.com/a?id=1\tkeep me\n\
+             bar\thttps://example.com/b\t\"quoted\ttab\"\n\
+             baz\thttps://youtu.be/1234\t\n"
+        );
+
+        let csv = "\"https://example.com/?utm_medium=y\",\"a, b\"";
+        assert_eq!(
+            sanitizer.sanitize_tabular(csv, true).unwrap(),
+            "\"https://example.com/\",\"a, b\""
+        );
+
+        // Spacing and escaped quotes in other cells are copied as they were
+        let csv = "\"say \"\"hi\"\"\",  https://example.com/?utm_source=x ,b\r\nc,d,e\r\n";
+        assert_eq!(
+            sanitizer.sanitize_tabular(csv, true).unwrap(),
+            "\"say \"\"hi\"\"\",  https://example.com/ ,b\r\nc,d,e\r\n"
+        );
+
+        assert!(sanitizer
+            .sanitize_tabular("a\tb\nhttps://example.com/\tc", true)
+            .is_none());
+        assert!(sanitizer
+            .sanitize_tabular("just some text, nothing else", true)
+            .is_none());
+    }
+
+    #[test]
+    fn test_default_sanitize_functions() {
+        let url = Url::parse("https://www.youtube.com/watch?v=1234&si=x").unwrap();
+        assert_eq!(sanitize_url(&url).as_str(), "https://youtu.be/1234");
+        assert_eq!(
+            sanitize_text("read https://example.com/?utm_source=x&id=1."),
+            "read https://example.com/?id=1."
+        );
+    }
+
+    #[test]
+    fn test_embedded_urls() {
+        let sanitizer = test_sanitizer();
+        let cases = [
+            (
+                "check this out https://example.com/?utm_source=x cool right?",
+                "check this out https://example.com/ cool right?",
+            ),
+            (
+                "(see https://example.com/a?utm_medium=y&id=1), and\thttps://x.com/u/status/1?s=20.",
+                "(see https://example.com/a?id=1), and\thttps://x.com/u/status/1.",
+            ),
+            (
+                "links:(https://example.com/?utm_source=x) link:https://example.com/b?utm_source=x",
+                "links:(https://example.com/) link:https://example.com/b",
+            ),
+            // Only text ending in punctuation is split from the URL
+            (
+                "see:https://example.com/?utm_source=x 1https://example.com/?utm_source=x",
+                "see:https://example.com/ 1https://example.com/?utm_source=x",
+            ),
+            ("no links here (really).", "no links here (really)."),
+            // Text after the URL in the same word is kept
+            (
+                "“https://example.com/?utm_source=x” a|https://example.com/?utm_source=x|b",
+                "“https://example.com/” a|https://example.com/|b",
+            ),
+            (
+                "foo https://example.com/?utm_source=x)bar 「https://example.com/?utm_source=x」",
+                "foo https://example.com/)bar 「https://example.com/」",
+            ),
+            (
+                "https://en.wikipedia.org/wiki/Rust_(programming_language)?utm_source=x).",
+                "https://en.wikipedia.org/wiki/Rust_(programming_language)).",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(sanitizer.sanitize_text(input, false), expected);
+        }
+        assert_eq!(embedded_url_start("(HTTPS://example.com"), Some(1));
+        assert_eq!(embedded_url_start("https://example.com"), None);
+        assert_eq!(url_end("https://example.com/a_(b)]"), 25);
+        assert_eq!(url_end("https://example.com/?a=b"), 24);
+    }
+
+    #[test]
+    fn test_strip_tracking() {
+        let sanitizer = test_sanitizer();
+
+        let test_cases = vec![
+            (
+                "https://www.youtube.com/watch?v=1234&si=stripped&feature=share",
+                "https://youtu.be/1234",
+            ),
+            (
+                "https://music.youtube.com/watch?v=5678&si=stripped&feature=share",
+                "https://music.youtube.com/watch?v=5678",
+            ),
+            (
+                "https://example.com/path?utm_source=foo&utm_medium=bar",
+                "https://example.com/path",
+            ),
+            (
+                "https://youtu.be/1234?si=stripped&t=123",
+                "https://youtu.be/1234?t=123",
+            ),
+            (
+                "https://youtube.com/live/xxxxxxxxxx?feature=share",
+                "https://youtu.be/xxxxxxxxxx",
+            ),
+            (
+                "https://youtube.com/shorts/xxxxxxxxxx?feature=share",
+                "https://youtu.be/xxxxxxxxxx",
+            ),
+            (
+                "https://twitter.com/discord/status/1702362670836621351?t=stripped&s=19",
+                "https://twitter.com/discord/status/1702362670836621351",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let url = Url::parse(input).unwrap();
+            let stripped_url = sanitizer.strip_tracking(&url, &mut Trace::default());
+            assert_eq!(stripped_url.as_str(), expected, "Stripped URL is incorrect");
+        }
+    }
+}
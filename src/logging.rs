@@ -0,0 +1,161 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io::Write;
+
+use clipboard_sanitizer::metrics::TrackingStats;
+use log::info;
+use serde_json::{json, Map, Value};
+use url::Url;
+
+/// Events are logged with this target, and their message is a JSON object that the json format merges into the line
+const EVENT_TARGET: &str = "clipboard_sanitizer::event";
+
+/// LogFormat is how log lines are written to stderr
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// env_logger's usual lines for reading
+    Text,
+    /// A JSON object per line with timestamp, level, target and message, and the fields of events like stripped
+    Json,
+}
+
+/// init sets up the logger with the filter and format. The json format also logs events.
+pub fn init(filter: &str, format: LogFormat) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter));
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json_line(
+                &buf.timestamp_millis().to_string(),
+                record.level().as_str(),
+                record.target(),
+                &record.args().to_string(),
+            );
+            writeln!(buf, "{}", line)
+        });
+    } else {
+        // Events repeat what the text lines already say
+        builder.filter_module(EVENT_TARGET, log::LevelFilter::Off);
+    }
+    builder.init();
+}
+
+/// json_line formats a log record as a JSON object. The message of an event is its fields instead.
+fn json_line(timestamp: &str, level: &str, target: &str, message: &str) -> String {
+    let mut fields = match serde_json::from_str(message) {
+        Ok(Value::Object(fields)) if target == EVENT_TARGET => fields,
+        _ => Map::from_iter([
+            ("target".to_string(), json!(target)),
+            ("message".to_string(), json!(message)),
+        ]),
+    };
+    fields.insert("timestamp".to_string(), json!(timestamp));
+    fields.insert("level".to_string(), json!(level));
+    Value::Object(fields).to_string()
+}
+
+fn event(fields: Value) {
+    info!(target: EVENT_TARGET, "{}", fields);
+}
+
+/// stripped logs a stripped event for the clipboard content. Content that's a single URL also gets its host, path
+/// and the names of the params that were removed from it. The content itself isn't logged, since the event may end
+/// up in a log file and the original has the tracking tokens in it.
+pub fn stripped(original: &str, cleaned: &str) {
+    event(stripped_fields(original, cleaned));
+}
+
+fn stripped_fields(original: &str, cleaned: &str) -> Value {
+    let mut fields = json!({ "event": "stripped" });
+    if let (Ok(original), Ok(cleaned)) = (Url::parse(original.trim()), Url::parse(cleaned.trim())) {
+        let kept: Vec<String> = cleaned.query_pairs().map(|(key, _)| key.into()).collect();
+        let removed: Vec<String> = original
+            .query_pairs()
+            .map(|(key, _)| key.into_owned())
+            .filter(|key| !kept.contains(key))
+            .collect();
+        fields["host"] = json!(original.host_str().unwrap_or_default());
+        fields["path"] = json!(cleaned.path());
+        fields["removed"] = json!(removed);
+    }
+    fields
+}
+
+/// summary logs how many params were stripped so far and the tracking score of each domain
+pub fn summary(tracking: &TrackingStats) {
+    let stripped: Map<String, Value> = tracking
+        .categories()
+        .into_iter()
+        .map(|(category, count)| (category.to_string(), json!(count)))
+        .collect();
+    let scores: Map<String, Value> = tracking
+        .scores()
+        .into_iter()
+        .map(|(domain, score)| (domain, json!(score)))
+        .collect();
+    info!(
+        "Stripped {} params so far, {} domains with tracking",
+        tracking.total(),
+        scores.len()
+    );
+    event(json!({
+        "event": "summary",
+        "stripped_total": tracking.total(),
+        "stripped": stripped,
+        "scores": scores,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_lines() {
+        assert_eq!(
+            json_line(
+                "2026-01-02T03:04:05.678Z",
+                "INFO",
+                "clipboard_sanitizer",
+                "Using \"arboard\""
+            ),
+            r#"{"level":"INFO","message":"Using \"arboard\"","target":"clipboard_sanitizer","timestamp":"2026-01-02T03:04:05.678Z"}"#
+        );
+
+        let fields = stripped_fields(
+            "https://example.com/?utm_source=x&id=1&fbclid=2",
+            "https://example.com/?id=1",
+        );
+        let line = json_line("t", "INFO", EVENT_TARGET, &fields.to_string());
+        assert_eq!(
+            line,
+            r#"{"event":"stripped","host":"example.com","level":"INFO","path":"/","removed":["utm_source","fbclid"],"timestamp":"t"}"#
+        );
+
+        // Text with URLs in it has no single host
+        let fields = stripped_fields(
+            "see https://example.com/?utm_source=x",
+            "see https://example.com/",
+        );
+        assert_eq!(fields.get("host"), None);
+        assert!(!fields.to_string().contains("utm_source"));
+        // Only events have their message merged
+        assert!(json_line("t", "INFO", "other", "{\"a\":1}").contains(r#""message":"{\"a\":1}""#));
+    }
+}
@@ -17,287 +17,2856 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant};
 
-use arboard::Clipboard;
 use clap::Parser;
+use clipboard_sanitizer::metrics::ErrorCategory;
+use clipboard_sanitizer::settings::{self, Settings};
+use clipboard_sanitizer::{batch, clearurls, Sanitizer};
 use config::Config;
 use dirs::config_dir;
-use log::{debug, error, info};
-use url::Url;
-
-const YOUTUBE_TRACKING_PARAMS: [&str; 2] = ["si", "feature"];
-const TWITTER_TRACKING_PARAMS: [&str; 2] = ["s", "t"];
-const COMMON_TRACKING_PARAMS: [&str; 5] = [
-    "utm_source",
-    "utm_medium",
-    "utm_campaign",
-    "utm_term",
-    "utm_content",
-];
+use log::{debug, error, info, warn};
+
+mod clipboard;
+#[cfg(unix)]
+mod control;
+mod history;
+mod logging;
+mod notify;
+mod service;
+mod shutdown;
+mod tail;
+#[cfg(feature = "tray")]
+mod tray;
+mod watch;
+
+use clipboard::{ClipboardBackend, ClipboardError, CopySource, Selection};
+
 const DEFAULT_LOG_LEVEL: &str = "info";
+/// Modules of the binary and the library that can be named without the crate prefix in LOG_FILTER
+const LOG_MODULES: [&str; 18] = [
+    "batch",
+    "clearurls",
+    "clipboard",
+    "control",
+    "expand",
+    "history",
+    "learn",
+    "logging",
+    "metrics",
+    "notify",
+    "params",
+    "pipeline",
+    "service",
+    "settings",
+    "shutdown",
+    "tail",
+    "tray",
+    "watch",
+];
+/// How often HOT_RELOAD checks the config file for changes
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the age of the ClearURLs rules is checked against CLEARURLS_REFRESH_HOURS, which is also how
+/// long a failed download waits before it's tried again
+const CLEARURLS_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const CLEARURLS_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many clipboard reads in a row can fail before the backend is opened again, e.g. after the compositor restarted
+const REOPEN_AFTER_FAILED_READS: u32 = 10;
+/// The longest wait between attempts to open the clipboard at startup
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How many replacements the undo and history commands remember without HISTORY_SIZE
+const DEFAULT_HISTORY_SIZE: usize = 20;
+/// How long --watch-mode event waits for a clipboard change before handling the control socket, hot reload,
+/// notifications and shutdown
+const EVENT_WAKE_INTERVAL: Duration = Duration::from_millis(250);
 
 #[derive(Parser, Debug)]
 #[command(name = "clipboard-sanitizer", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = env!("CARGO_PKG_DESCRIPTION"))]
 struct Args {
-    #[arg(short = 'v', long = "verbose", default_value_t = DEFAULT_LOG_LEVEL.to_string())]
-    verbosity: String,
+    /// Log level for everything without its own level in LOG_FILTER [default: info]
+    #[arg(short = 'v', long = "verbose")]
+    verbosity: Option<String>,
+
+    /// How to write log lines: text, or json for a JSON object per line that also has an event with the host and
+    /// removed params of every stripped URL, and the summaries of STATS_LOG_INTERVAL_MINS
+    #[arg(long = "log-format", value_enum, default_value_t = logging::LogFormat::Text)]
+    log_format: logging::LogFormat,
+
+    /// Show how the URL would be sanitized and which rules matched, then exit
+    #[arg(long = "explain", value_name = "URL")]
+    explain: Option<String>,
+
+    /// Follow the file like `tail -f` and print every appended line with tracking stripped from its URLs
+    #[arg(long = "tail", value_name = "FILE")]
+    tail: Option<std::path::PathBuf>,
+
+    /// Append the cleaned lines from --tail to this file instead of printing them
+    #[arg(long = "tail-output", value_name = "FILE", requires = "tail")]
+    tail_output: Option<std::path::PathBuf>,
+
+    /// Strip tracking from every URL in the file and print the result
+    #[arg(long = "file", value_name = "FILE")]
+    file: Option<std::path::PathBuf>,
+
+    /// Write the result of --file back to the file instead of printing it
+    #[arg(long = "in-place", requires = "file")]
+    in_place: bool,
+
+    /// Print how many URLs in --file were found and changed per domain. Nothing is printed or written
+    /// for the file itself unless --in-place is also given.
+    #[arg(long = "summary", requires = "file")]
+    summary: bool,
+
+    /// Clean the clipboard once, print the result and exit instead of monitoring it.
+    /// Exits nonzero if the clipboard couldn't be read or written.
+    #[arg(short = '1', long = "once")]
+    once: bool,
+
+    /// Also append the cleaned clipboard content from --once to this file, e.g. to collect links with a hotkey.
+    /// Nothing is appended and the exit code is nonzero if the clipboard had no URL.
+    #[arg(long = "save", value_name = "FILE", requires = "once")]
+    save: Option<std::path::PathBuf>,
+
+    /// Log what would be stripped from the clipboard without changing it. With NOTIFICATIONS, the notifications
+    /// show what would be stripped.
+    #[arg(long = "dry-run")]
+    dry_run: bool,
+
+    /// Strip tracking from the URLs in every line read from stdin and print the lines, without touching the clipboard
+    #[arg(long = "stdin", conflicts_with = "once")]
+    stdin: bool,
+
+    /// How to notice clipboard changes: event waits for the platform to report them, where it can (Windows, and
+    /// Wayland with wl-paste), and polls elsewhere. poll checks the clipboard every POLL_INTERVAL_MS.
+    #[arg(long = "watch-mode", value_enum, default_value_t = WatchMode::Poll)]
+    watch_mode: WatchMode,
+
+    /// Check the clipboard this often after it changes, like POLL_INTERVAL_MS in the config
+    #[arg(long = "poll-interval-ms", value_name = "MS")]
+    poll_interval_ms: Option<u64>,
+
+    /// Check the clipboard at least this often when it stays unchanged, like POLL_MAX_INTERVAL_MS in the config
+    #[arg(long = "poll-max-interval-ms", value_name = "MS")]
+    poll_max_interval_ms: Option<u64>,
+
+    /// Comma-separated selections to clean, each on its own: clipboard, and on Linux primary, the selection that
+    /// middle-click pastes. Content is only ever written back to the selection it was read from.
+    #[arg(
+        long = "selections",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "clipboard"
+    )]
+    selections: Vec<Selection>,
+
+    /// Leave links to the domain and its subdomains alone, like adding it to SKIP_DOMAINS. Can be given more than once.
+    #[arg(long = "no-touch", value_name = "DOMAIN")]
+    no_touch: Vec<String>,
+
+    /// Show an icon in the system tray with a menu to pause cleaning, copy one of the last cleaned URLs again
+    /// and quit. Needs a StatusNotifierItem host on Linux, like KDE or GNOME's AppIndicator extension.
+    #[arg(long = "tray")]
+    tray: bool,
+
+    /// Keep monitoring the clipboard in the background, detached from the terminal. The log goes to
+    /// clipboard-sanitizer.log in the state directory, like ~/.local/state/clipboard-sanitizer. Unix only.
+    #[arg(long = "daemon", conflicts_with_all = ["once", "stdin"])]
+    daemon: bool,
+
+    /// Print the error counters and tracking stats from the metrics file and exit
+    #[arg(long = "stats")]
+    stats: bool,
+
+    /// Check every `input expected` URL pair in the file against the current config and exit
+    #[arg(long = "test-corpus", value_name = "FILE")]
+    test_corpus: Option<std::path::PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Print every argument with tracking stripped from its URLs, like clipboard content, and exit
+    Clean {
+        #[arg(required = true, value_name = "TEXT")]
+        texts: Vec<String>,
+    },
+    /// Put the original of the last content the running instance cleaned back on the clipboard. Needs CONTROL_SOCKET.
+    Undo,
+    /// Print the last contents the running instance cleaned, newest first. Needs CONTROL_SOCKET.
+    History,
+    /// Print the error counters and tracking stats of the running instance. Needs CONTROL_SOCKET.
+    Stats,
+    /// Check every case in the fixture file against the current config and exit, like --test-corpus. The file has
+    /// `input expected` URL pairs, or [[cases]] tables with input and expected in .toml, or an array of objects
+    /// with input and expected in .json.
+    TestRules {
+        #[arg(value_name = "FILE")]
+        file: std::path::PathBuf,
+    },
+    /// Start clipboard-sanitizer at login with the given arguments: a systemd user unit on Linux, a launchd agent
+    /// on macOS or a startup registration on Windows. Starts it right away, except on Windows.
+    InstallService {
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            value_name = "ARGS"
+        )]
+        args: Vec<String>,
+    },
+    /// Stop and remove what install-service set up
+    UninstallService,
 }
 
-static mut APP_CONFIG: Option<HashMap<String, String>> = None;
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum WatchMode {
+    Event,
+    Poll,
+}
+
+/// APP_CONFIG holds the settings read from the config file. It's a lock rather than a OnceLock because the
+/// control socket's reload command replaces the settings while running.
+static APP_CONFIG: RwLock<Option<Settings>> = RwLock::new(None);
+/// SETTINGS_REVISION counts how often the settings were replaced, so readers can tell when to read them again
+static SETTINGS_REVISION: AtomicU32 = AtomicU32::new(0);
+/// NO_TOUCH_DOMAINS are the --no-touch domains, added to SKIP_DOMAINS whenever the settings are replaced
+static NO_TOUCH_DOMAINS: OnceLock<Vec<String>> = OnceLock::new();
+/// CLI_SETTINGS are the settings given as options, like --poll-interval-ms, which replace the config's whenever
+/// the settings are replaced
+static CLI_SETTINGS: OnceLock<Settings> = OnceLock::new();
 
 fn main() {
     let args = Args::parse();
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(args.verbosity))
-        .init();
+    // The logger is set up before init_settings so its messages are logged, so LOG_FILTER is read on its own
+    let config_filter = load_settings(&config_file())
+        .ok()
+        .and_then(|map| map.get("LOG_FILTER").cloned());
+    let filter = log_filter(args.verbosity.as_deref(), config_filter.as_deref());
+    logging::init(&filter, args.log_format);
 
+    let _ = NO_TOUCH_DOMAINS.set(args.no_touch.clone());
+    let _ = CLI_SETTINGS.set(cli_settings(&args));
     init_settings();
 
-    let mut clipboard = Clipboard::new().unwrap();
+    match args.command {
+        Some(Command::Clean { texts }) => {
+            let sanitizer = sanitizer_from_settings();
+            if let Err(e) = clean_texts(&sanitizer, texts, std::io::stdout()) {
+                error!("Failed to print cleaned text: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Undo) => std::process::exit(send_control_command("undo")),
+        Some(Command::History) => std::process::exit(send_control_command("history")),
+        Some(Command::Stats) => std::process::exit(send_control_command("stats")),
+        Some(Command::TestRules { file }) => std::process::exit(test_corpus(&file)),
+        Some(Command::InstallService { args }) => service_command(service::install(&args)),
+        Some(Command::UninstallService) => service_command(service::uninstall()),
+        None => {}
+    }
+
+    if let Some(url) = args.explain {
+        print!("{}", sanitizer_from_settings().explain(&url));
+        return;
+    }
 
-    loop {
-        debug!("Checking clipboard...");
-        let url = parse_url(&mut clipboard);
-        if let Some(url) = url {
-            let stripped_url = strip_tracking(&url);
-            if stripped_url != url {
-                if let Err(e) = clipboard.set_text(stripped_url.as_str().to_string()) {
-                    error!("Failed to set clipboard: {}", e);
+    let metrics_file = read_setting("METRICS_FILE").filter(|path| !path.is_empty());
+    if args.stats {
+        match &metrics_file {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(metrics) => print!("{}", metrics),
+                Err(e) => {
+                    error!("Failed to read metrics file {}: {}", path, e);
+                    std::process::exit(1);
                 }
-                info!("Stripped tracking from URL: {}", stripped_url);
+            },
+            None => {
+                error!("Set METRICS_FILE in the config to collect metrics");
+                std::process::exit(1);
             }
         }
+        return;
+    }
 
-        std::thread::sleep(std::time::Duration::from_millis(50));
+    if let Some(path) = args.test_corpus {
+        std::process::exit(test_corpus(&path));
     }
-}
 
-fn init_settings() {
-    // Use of unsafe in this function is justified because we are writing the config once before reading it
+    if let Some(path) = args.file {
+        std::process::exit(process_file(&path, args.in_place, args.summary));
+    }
 
-    let mut config_path = config_dir().unwrap();
-    config_path.push("clipboard-sanitizer");
+    if args.stdin {
+        let sanitizer = sanitizer_from_settings();
+        if let Err(e) = filter_lines(&sanitizer, std::io::stdin().lock(), std::io::stdout()) {
+            error!("Failed to filter stdin: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let default_config: HashMap<String, String> = HashMap::new();
+    if let Some(path) = args.tail {
+        std::process::exit(tail_file(&path, args.tail_output.as_deref()));
+    }
 
-    // path doesn't exist
-    if config_path.is_dir() == false {
-        let res = std::fs::create_dir_all(&config_path);
-        if let Err(e) = res {
-            error!(
-                "Failed to create config directory {:?}: {:?}",
-                config_path, e
-            );
-            unsafe { APP_CONFIG = Some(default_config) };
-            return;
-        }
-        let res = std::fs::write(config_path.join("config.toml"), "");
-        if let Err(e) = res {
-            error!(
-                "Failed to create config file {:?}: {:?}",
-                config_path.join("config.toml"),
-                e
-            );
-            unsafe { APP_CONFIG = Some(default_config) };
-            return;
+    // Reloading the config, over the control socket, with HOT_RELOAD or SIGHUP, replaces the sanitizer
+    let mut sanitizer = sanitizer_from_settings();
+    let mut monitor = Monitor::from_settings();
+    monitor.dry_run = args.dry_run;
+    let backend = read_setting("CLIPBOARD_BACKEND").unwrap_or_default();
+    // Held until exiting. It's taken before detaching, so a second instance fails where it's seen.
+    let mut instance =
+        (!args.once).then(
+            || match service::InstanceLock::acquire(&service::lock_file()) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    error!("Not monitoring the clipboard, another instance is {}", e);
+                    std::process::exit(1);
+                }
+            },
+        );
+    // One-shot runs fail right away, the monitor waits for the clipboard to become available
+    let clipboard = if args.once {
+        clipboard::open_backend(&backend).map_err(|e| error!("Failed to open clipboard: {}", e))
+    } else {
+        if let (true, Some(instance)) = (args.daemon, &mut instance) {
+            daemonize(instance);
         }
+        shutdown::install();
+        open_clipboard_with_retry(&backend).ok_or(())
+    };
+    let Ok(mut clipboard) = clipboard else {
+        std::process::exit(1);
+    };
+
+    if args.once {
+        std::process::exit(run_once(
+            clipboard.as_mut(),
+            &sanitizer,
+            args.save.as_deref(),
+            args.dry_run,
+        ));
     }
 
-    let cfg = Config::builder()
-        .add_source(config::File::from(config_path.join("config.toml")))
-        .add_source(config::Environment::with_prefix("CLIPBOARD_SANITIZER"))
-        .build()
-        .unwrap();
+    let mut notifier = notifier_from_settings(args.dry_run);
+
+    // The clipboard is always open for the tray and undo, even if only the primary selection is cleaned
+    let watch_clipboard = args.selections.contains(&Selection::Clipboard);
+    // The primary selection is opened again later like an unreadable clipboard if it can't be opened yet
+    let mut primary = args.selections.contains(&Selection::Primary).then(|| {
+        let mut monitor = Monitor::for_selection(Selection::Primary);
+        monitor.dry_run = args.dry_run;
+        let selection = match clipboard::open_selection(&backend, Selection::Primary) {
+            Ok(selection) => Some(selection),
+            Err(e) => {
+                warn!("Failed to open the primary selection, trying again: {}", e);
+                monitor.failed_reads = REOPEN_AFTER_FAILED_READS;
+                None
+            }
+        };
+        (selection, monitor)
+    });
 
-    let map = cfg.try_deserialize::<HashMap<String, String>>().unwrap();
-    info!("Using config: {:?}", map);
+    #[cfg(unix)]
+    let control_socket = read_setting("CONTROL_SOCKET")
+        .filter(|path| !path.is_empty())
+        .and_then(
+            |path| match control::ControlSocket::bind(std::path::Path::new(&path)) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    error!("Failed to open control socket {}: {}", path, e);
+                    None
+                }
+            },
+        );
+    #[cfg(not(unix))]
+    if read_setting("CONTROL_SOCKET").is_some_and(|path| !path.is_empty()) {
+        error!("CONTROL_SOCKET is only supported on Unix");
+    }
+
+    // Changes to HOT_RELOAD itself need a restart, like LOG_FILTER
+    let mut config_watcher = read_bool_setting("HOT_RELOAD", false)
+        .then(|| watch::ConfigWatcher::new(&config_file(), CONFIG_CHECK_INTERVAL));
 
-    unsafe {
-        APP_CONFIG = Some(map);
+    // The rules are downloaded in the background and the sanitizer is rebuilt when the file changes.
+    // Like HOT_RELOAD, changes to where the rules are downloaded from and saved need a restart.
+    let clearurls_file = with_settings(clearurls::file);
+    if let (Some(url), Some(path)) = (
+        read_setting("CLEARURLS_URL").filter(|url| !url.is_empty()),
+        &clearurls_file,
+    ) {
+        let refresh =
+            Duration::from_secs(read_u64_setting("CLEARURLS_REFRESH_HOURS", 24) * 60 * 60);
+        refresh_clearurls(url, path.clone(), refresh);
+    }
+    let mut clearurls_watcher =
+        clearurls_file.map(|path| watch::ConfigWatcher::new(&path, CONFIG_CHECK_INTERVAL));
+
+    let mut change_events = match args.watch_mode {
+        WatchMode::Event => match clipboard::watch_changes() {
+            Ok(events) => Some(events),
+            Err(e) => {
+                warn!(
+                    "Polling the clipboard, change events aren't available: {}",
+                    e
+                );
+                None
+            }
+        },
+        WatchMode::Poll => None,
     };
-}
 
-fn read_setting(key: &str) -> Option<String> {
-    // This is safe because we are only reading
-    unsafe {
-        if let Some(map) = &APP_CONFIG {
-            if let Some(value) = map.get(key) {
-                return Some(value.to_string());
+    #[cfg(feature = "tray")]
+    let tray = if args.tray {
+        match tray::Tray::start() {
+            Ok(tray) => Some(tray),
+            Err(e) => {
+                error!("Failed to show the tray icon: {}", e);
+                None
             }
         }
+    } else {
+        None
+    };
+    #[cfg(not(feature = "tray"))]
+    if args.tray {
+        error!(
+            "Failed to show the tray icon: clipboard-sanitizer was built without the tray feature"
+        );
     }
-    None
-}
 
-/// parse_url guarantees that clipboard contains a URL with a domain name and returns the URL instance
-fn parse_url(clipboard: &mut Clipboard) -> Option<url::Url> {
-    let content = clipboard.get_text();
-    if let Ok(potential_url) = content {
-        if let Ok(url) = Url::parse(&potential_url) {
-            debug!("Found URL: {}", url);
-            if let Some(_) = url.domain() {
-                return Some(url);
+    // Like HOT_RELOAD, changes to the interval need a restart
+    let stats_log_interval =
+        Duration::from_secs(read_u64_setting("STATS_LOG_INTERVAL_MINS", 0) * 60);
+    let mut last_stats_log = Instant::now();
+
+    let mut reported_counts = (0, 0);
+    let mut changed = true;
+    while !shutdown::requested() {
+        if let Some(watcher) = &mut config_watcher {
+            if watcher.changed(Instant::now()) {
+                // A broken config is logged by reload and the previous settings stay in effect
+                let _ = reload(&config_file(), &mut sanitizer, &mut monitor, &mut notifier);
+            }
+        }
+
+        if shutdown::take_reload_request() {
+            info!("Received SIGHUP, reloading config");
+            let _ = reload(&config_file(), &mut sanitizer, &mut monitor, &mut notifier);
+        }
+
+        if let Some(watcher) = &mut clearurls_watcher {
+            if watcher.changed(Instant::now()) {
+                info!("ClearURLs rules changed, reloading them");
+                sanitizer = sanitizer_from_settings().with_stats_of(&sanitizer);
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(control_socket) = &control_socket {
+            control_socket.poll(|command| {
+                handle_control_command(
+                    command,
+                    &config_file(),
+                    clipboard.as_mut(),
+                    &mut sanitizer,
+                    &mut monitor,
+                    &mut notifier,
+                )
+            });
+        }
+
+        #[cfg(feature = "tray")]
+        if let Some(tray) = &tray {
+            monitor.paused = tray.paused();
+            if let Some(content) = tray.take_copy() {
+                match clipboard.set_text(content.clone()) {
+                    Ok(()) => monitor.wrote(content),
+                    Err(e) => {
+                        sanitizer.errors().increment(ErrorCategory::ClipboardWrite);
+                        error!("{}", e);
+                    }
+                }
+            }
+        }
+
+        if let Some((_, primary_monitor)) = &mut primary {
+            primary_monitor.refresh_settings();
+            primary_monitor.paused = monitor.paused;
+        }
+
+        if let Some(reopened) = reopen(
+            || clipboard::open_backend(&backend),
+            &mut monitor,
+            Instant::now(),
+        ) {
+            clipboard = reopened;
+        }
+        if let Some((selection, primary_monitor)) = &mut primary {
+            let open = || clipboard::open_selection(&backend, Selection::Primary);
+            if let Some(reopened) = reopen(open, primary_monitor, Instant::now()) {
+                *selection = Some(reopened);
+            }
+        }
+
+        let expanded = if sanitizer.expansions_finished() {
+            let expanded = check_expanded(clipboard.as_mut(), &mut monitor, &sanitizer);
+            match &mut primary {
+                Some((Some(selection), primary_monitor)) => expanded
+                    .or_else(|| check_expanded(selection.as_mut(), primary_monitor, &sanitizer)),
+                _ => expanded,
             }
         } else {
-            debug!("Clipboard content is not a URL: {}", potential_url);
+            None
+        };
+
+        let cleaned = if changed && watch_clipboard {
+            check_clipboard(clipboard.as_mut(), &mut monitor, &sanitizer)
+        } else {
+            None
+        };
+        // Change events are only for the clipboard, so the primary selection is checked on every wakeup.
+        // If the clipboard was just cleaned, it's checked on the next one.
+        let cleaned = cleaned.or_else(|| {
+            let (Some(selection), primary_monitor) = primary.as_mut()? else {
+                return None;
+            };
+            check_clipboard(selection.as_mut(), primary_monitor, &sanitizer)
+        });
+        let cleaned = cleaned.or(expanded);
+        // Nothing was written in a dry run, so there's nothing to copy again from the tray
+        #[cfg(feature = "tray")]
+        if let (Some(tray), Some(cleaned), false) = (&tray, &cleaned, args.dry_run) {
+            tray.cleaned(cleaned);
         }
-    } else {
-        debug!("Failed to get clipboard: {}", content.unwrap_err());
+        if let Some(notifier) = &mut notifier {
+            let now = Instant::now();
+            let message = match &cleaned {
+                Some(cleaned) => notifier.cleaned(cleaned, now),
+                None => notifier.flush(now),
+            };
+            if let Some(message) = message {
+                notify::show(&message);
+            }
+        }
+
+        if !stats_log_interval.is_zero() && last_stats_log.elapsed() >= stats_log_interval {
+            last_stats_log = Instant::now();
+            logging::summary(sanitizer.tracking());
+        }
+
+        if let Some(path) = &metrics_file {
+            let counts = (sanitizer.errors().total(), sanitizer.tracking().total());
+            if counts != reported_counts {
+                reported_counts = counts;
+                let metrics = format!("{}{}", sanitizer.errors(), sanitizer.tracking());
+                if let Err(e) = std::fs::write(path, metrics) {
+                    error!("Failed to write metrics file {}: {}", path, e);
+                }
+            }
+        }
+
+        changed = match change_events
+            .as_mut()
+            .map(|events| events.wait(EVENT_WAKE_INTERVAL))
+        {
+            Some(Ok(changed)) => changed,
+            Some(Err(e)) => {
+                warn!("Polling the clipboard from now on: {}", e);
+                change_events = None;
+                true
+            }
+            None => {
+                std::thread::sleep(monitor.poll_interval(Instant::now()));
+                true
+            }
+        };
     }
-    None
+    info!("Shutting down");
 }
 
-fn strip_tracking(url: &url::Url) -> url::Url {
-    match url.domain().unwrap() {
-        "www.youtube.com" => strip_full_youtube(&url),
-        "youtube.com" => strip_full_youtube(&url),
-        "twitter.com" => strip_params(&url, TWITTER_TRACKING_PARAMS.to_vec()),
-        "x.com" => strip_params(&url, TWITTER_TRACKING_PARAMS.to_vec()),
-        "youtu.be" => strip_params(&url, YOUTUBE_TRACKING_PARAMS.to_vec()),
-        "music.youtube.com" => strip_params(&url, YOUTUBE_TRACKING_PARAMS.to_vec()),
-        _ => strip_params(&url, COMMON_TRACKING_PARAMS.to_vec()),
+/// open_clipboard_with_retry opens the clipboard backend, trying again with increasing delays while it's
+/// unavailable, e.g. when started before the compositor. Returns None if a shutdown is requested first.
+fn open_clipboard_with_retry(backend: &str) -> Option<Box<dyn ClipboardBackend>> {
+    let mut attempt = 0;
+    loop {
+        match clipboard::open_backend(backend) {
+            Ok(clipboard) => return Some(clipboard),
+            Err(e) => {
+                let delay = retry_delay(attempt);
+                warn!(
+                    "Failed to open clipboard, trying again in {}s: {}",
+                    delay.as_secs(),
+                    e
+                );
+                let until = Instant::now() + delay;
+                while Instant::now() < until {
+                    if shutdown::requested() {
+                        return None;
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                attempt += 1;
+            }
+        }
     }
 }
 
-fn enabled_prefixes() -> Vec<String> {
-    let mut prefixes = vec![];
-    if let Some(prefixes_csv) = read_setting("YOUTUBE_PREFIXES") {
-        if prefixes_csv == "" {
-            return prefixes;
+/// reopen opens a selection with open again once REOPEN_AFTER_FAILED_READS reads in a row failed. Until a read works
+/// again, every further attempt waits retry_delay longer, so a clipboard that stays locked isn't reopened in a tight
+/// loop.
+fn reopen(
+    open: impl FnOnce() -> Result<Box<dyn ClipboardBackend>, ClipboardError>,
+    monitor: &mut Monitor,
+    now: Instant,
+) -> Option<Box<dyn ClipboardBackend>> {
+    if monitor.failed_reads < REOPEN_AFTER_FAILED_READS
+        || monitor.reopen_at.is_some_and(|reopen_at| now < reopen_at)
+    {
+        return None;
+    }
+    let delay = retry_delay(monitor.reopen_attempts);
+    monitor.reopen_attempts += 1;
+    monitor.reopen_at = Some(now + delay);
+    let name = match monitor.selection {
+        Selection::Clipboard => "clipboard",
+        Selection::Primary => "primary selection",
+    };
+    match open() {
+        Ok(reopened) => {
+            info!("Reopened the {} after reads kept failing", name);
+            Some(reopened)
         }
-        for prefix in prefixes_csv.split(',') {
-            prefixes.push(format!("/{}/", prefix.to_string()));
+        Err(e) => {
+            warn!(
+                "Failed to open the {}, trying again in {}s: {}",
+                name,
+                delay.as_secs(),
+                e
+            );
+            None
         }
     }
-    prefixes
 }
 
-fn map_youtube_prefix(url: &url::Url, prefix: &str) -> Option<url::Url> {
-    if url.path().starts_with(prefix) {
-        let mut new_url = url.clone();
-        new_url.set_host(Some("youtu.be")).unwrap();
-        let video_id = url
-            .path()
-            .strip_prefix(prefix)
-            .unwrap()
-            .split('/')
-            .next()
-            .unwrap();
-        new_url.set_path(&format!("/{}", video_id));
-        return Some(new_url);
+/// retry_delay is how long to wait before the next attempt, doubling from 1s up to MAX_RETRY_DELAY
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.min(6)).min(MAX_RETRY_DELAY)
+}
+
+/// refresh_clearurls downloads the ClearURLs rules on a background thread whenever the file is missing or
+/// older than refresh, so a slow download never delays clipboard checks
+fn refresh_clearurls(url: String, path: std::path::PathBuf, refresh: Duration) {
+    std::thread::spawn(move || loop {
+        let age = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        if age.is_none_or(|age| age >= refresh) {
+            match clearurls::download(&url, &path, CLEARURLS_DOWNLOAD_TIMEOUT) {
+                Ok(()) => info!("Downloaded ClearURLs rules from {} to {:?}", url, path),
+                Err(e) => error!("Failed to download ClearURLs rules from {}: {}", url, e),
+            }
+        }
+        std::thread::sleep(CLEARURLS_CHECK_INTERVAL);
+    });
+}
+
+/// daemonize detaches from the terminal before any threads are started, or exits if it can't
+fn daemonize(instance: &mut service::InstanceLock) {
+    #[cfg(unix)]
+    {
+        let log_file = service::daemon_log_file();
+        info!("Running in the background, logging to {:?}", log_file);
+        if let Err(e) = service::daemonize(&log_file).and_then(|()| instance.record_pid()) {
+            error!("Failed to run in the background: {}", e);
+            std::process::exit(1);
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = instance;
+        error!("--daemon is only supported on Unix, use install-service to start at login instead");
+        std::process::exit(1);
     }
-    None
 }
 
-fn strip_full_youtube(url: &url::Url) -> url::Url {
-    let prefixes = enabled_prefixes();
-    for prefix in prefixes {
-        if let Some(new_url) = map_youtube_prefix(&url, &prefix) {
-            return strip_tracking(&new_url);
+/// service_command prints the outcome of installing or uninstalling the service and exits
+fn service_command(result: Result<String, String>) -> ! {
+    match result {
+        Ok(message) => {
+            info!("{}", message);
+            std::process::exit(0);
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
         }
     }
+}
 
-    if let Some(video_id) = get_query_value(url, "v") {
-        let mut new_url = url.clone();
-        new_url.set_host(Some("youtu.be")).unwrap();
-        new_url.set_path(&format!("/{}", video_id));
-        let mut params = YOUTUBE_TRACKING_PARAMS.to_vec();
-        params.push("v");
-        let new_url = strip_params(&new_url, params);
-        new_url
-    } else {
-        let new_url = strip_params(&url, YOUTUBE_TRACKING_PARAMS.to_vec());
-        new_url
+fn notifier_from_settings(dry_run: bool) -> Option<notify::Notifier> {
+    read_bool_setting("NOTIFICATIONS", false).then(|| {
+        notify::Notifier::new(
+            Duration::from_millis(read_u64_setting("NOTIFICATION_THROTTLE_MS", 2000)),
+            dry_run,
+        )
+    })
+}
+
+/// handle_control_command runs a command received on the control socket and returns the response
+#[cfg(unix)]
+fn handle_control_command(
+    command: &str,
+    config_file: &std::path::Path,
+    clipboard: &mut dyn ClipboardBackend,
+    sanitizer: &mut Sanitizer,
+    monitor: &mut Monitor,
+    notifier: &mut Option<notify::Notifier>,
+) -> String {
+    match command {
+        "reload" => match reload(config_file, sanitizer, monitor, notifier) {
+            Ok(summary) => format!("ok\n{}", summary),
+            Err(e) => format!("error: {}", e),
+        },
+        "undo" => {
+            let Some(original) = monitor.history.last().map(|entry| entry.original.clone()) else {
+                return "error: nothing to undo".to_string();
+            };
+            match clipboard.set_text(original.clone()) {
+                Ok(()) => {
+                    info!("Restored original clipboard content: {}", original);
+                    monitor.history.undo();
+                    monitor.wrote(original.clone());
+                    format!("ok\n{}", original)
+                }
+                Err(e) => {
+                    sanitizer.errors().increment(ErrorCategory::ClipboardWrite);
+                    format!("error: {}", e)
+                }
+            }
+        }
+        "history" => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+            format!("ok\n{}", monitor.history.list(now))
+        }
+        "stats" => format!("ok\n{}{}", sanitizer.errors(), sanitizer.tracking()),
+        _ => format!(
+            "error: unknown command {:?}, expected reload, undo, history or stats",
+            command
+        ),
     }
 }
 
-fn strip_params(url: &url::Url, strip: Vec<&str>) -> url::Url {
-    debug!("Stripping params from url {}: {:?}", url, strip);
-    let mut query_pairs = url.query_pairs();
-    let mut new_url = url.clone();
-    new_url.query_pairs_mut().clear();
-    while let Some(pair) = query_pairs.next() {
-        if !strip.contains(&pair.0.as_ref()) {
-            new_url.query_pairs_mut().append_pair(&pair.0, &pair.1);
+/// send_control_command sends the command to the running instance's CONTROL_SOCKET and prints the response.
+/// Returns the exit code, which is nonzero if the command couldn't be sent or failed.
+fn send_control_command(command: &str) -> i32 {
+    #[cfg(unix)]
+    {
+        let Some(path) = read_setting("CONTROL_SOCKET").filter(|path| !path.is_empty()) else {
+            error!("Set CONTROL_SOCKET in the config to send commands to the running instance");
+            return 1;
+        };
+        match control::send(std::path::Path::new(&path), command) {
+            Ok(response) => match response.strip_prefix("ok\n") {
+                Some(output) => {
+                    print!("{}", output);
+                    0
+                }
+                None => {
+                    error!("{}", response.trim_end());
+                    1
+                }
+            },
+            Err(e) => {
+                error!("Failed to connect to control socket {}: {}", path, e);
+                1
+            }
         }
     }
-    if new_url.query_pairs().count() == 0 {
-        new_url.set_query(None);
+    #[cfg(not(unix))]
+    {
+        error!(
+            "{} needs CONTROL_SOCKET, which is only supported on Unix",
+            command
+        );
+        1
     }
-    new_url
 }
 
-fn get_query_value(url: &url::Url, var: &str) -> Option<String> {
-    for (key, value) in url.query_pairs() {
-        if key == var {
-            return Some(value.to_string());
+/// reload loads the config file again and rebuilds everything that reads the settings. If the config can't be
+/// loaded, the error is logged and returned, and the previous settings stay in effect.
+fn reload(
+    config_file: &std::path::Path,
+    sanitizer: &mut Sanitizer,
+    monitor: &mut Monitor,
+    notifier: &mut Option<notify::Notifier>,
+) -> Result<String, String> {
+    match reload_settings(config_file) {
+        Ok(summary) => {
+            // Error counts and tracking stats carry over, everything else is rebuilt from the new settings
+            *sanitizer = sanitizer_from_settings().with_stats_of(sanitizer);
+            monitor.apply_settings();
+            *notifier = notifier_from_settings(monitor.dry_run);
+            Ok(summary)
+        }
+        Err(e) => {
+            error!("Failed to reload config: {}", e);
+            Err(e)
         }
     }
-    None
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// write_clipboard replaces the clipboard content with the sanitized text. If the clipboard also had an HTML version,
+/// like browsers add when copying a link, it's sanitized and written too instead of being dropped.
+fn write_clipboard(
+    clipboard: &mut dyn ClipboardBackend,
+    sanitizer: &Sanitizer,
+    sanitized: String,
+) -> Result<(), ClipboardError> {
+    match clipboard.get_html() {
+        Some(html) => clipboard.set_text_and_html(sanitized, sanitizer.sanitize_html(&html)),
+        None => clipboard.set_text(sanitized),
+    }
+}
 
-    fn init_test_settings() {
-        let mut app_config = HashMap::new();
-        app_config.insert("YOUTUBE_PREFIXES".to_string(), "live,shorts".to_string());
-        unsafe {
-            APP_CONFIG = Some(app_config);
+/// check_clipboard sanitizes the clipboard content once, counting any errors in the sanitizer's metrics.
+/// Returns the content written to the clipboard, if any, or with dry_run the content that would have been.
+fn check_clipboard(
+    clipboard: &mut dyn ClipboardBackend,
+    monitor: &mut Monitor,
+    sanitizer: &Sanitizer,
+) -> Option<String> {
+    if let Some(sequence) = clipboard.sequence_number() {
+        if monitor.last_sequence == Some(sequence) {
+            return None;
         }
+        monitor.last_sequence = Some(sequence);
     }
+    debug!("Checking clipboard...");
+    let text = clipboard.get_text();
+    if !matches!(text, Err(ClipboardError::Read(_))) {
+        monitor.failed_reads = 0;
+        monitor.reopen_attempts = 0;
+        monitor.reopen_at = None;
+    }
+    match text {
+        Ok(content) => {
+            let source = if monitor.only_user_copies {
+                clipboard.source()
+            } else {
+                CopySource::Unknown
+            };
+            let original = content.clone();
+            let now = Instant::now();
+            let Some(sanitized) = monitor.check(content, source, sanitizer, now) else {
+                if monitor.clean_text {
+                    check_html(clipboard, monitor, sanitizer, original);
+                }
+                return None;
+            };
+            if clipboard.has_other_content() {
+                info!(
+                    "Leaving the clipboard alone, it has files or an image besides {}",
+                    original
+                );
+                return None;
+            }
+            if monitor.dry_run {
+                info!("Would strip tracking: {} -> {}", original, sanitized);
+                monitor.stripped(original, sanitized.clone(), now);
+                return Some(sanitized);
+            }
+            match write_clipboard(clipboard, sanitizer, sanitized.clone()) {
+                Ok(()) => {
+                    monitor.wrote(sanitized.clone());
+                    monitor.stripped(original.clone(), sanitized.clone(), now);
+                    logging::stripped(&original, &sanitized);
+                    monitor.history.record(original, sanitized.clone());
+                    match monitor.selection {
+                        Selection::Clipboard => info!("Stripped tracking from URL: {}", sanitized),
+                        Selection::Primary => info!(
+                            "Stripped tracking from URL in the primary selection: {}",
+                            sanitized
+                        ),
+                    }
+                    Some(sanitized)
+                }
+                Err(e) => {
+                    sanitizer.errors().increment(ErrorCategory::ClipboardWrite);
+                    error!("{}", e);
+                    None
+                }
+            }
+        }
+        // Nothing is written when the text can't be read, so images and other content are never replaced
+        Err(ClipboardError::NoText) => {
+            debug!("Clipboard doesn't contain text");
+            None
+        }
+        // Only the first failure in a row is counted and logged, the clipboard stays unreadable while it's locked
+        Err(e) if monitor.failed_reads == 0 => {
+            sanitizer.errors().increment(ErrorCategory::ClipboardRead);
+            monitor.failed_reads += 1;
+            warn!("{}", e);
+            None
+        }
+        Err(e) => {
+            monitor.failed_reads += 1;
+            debug!("{}", e);
+            None
+        }
+    }
+}
 
-    #[test]
-    fn test_strip_params() {
-        let url = Url::parse("https://example.com/path?foo=bar&baz=qux").unwrap();
-        let stripped_url = strip_params(&url, vec!["baz"]);
-        assert_eq!(
-            stripped_url.as_str(),
-            "https://example.com/path?foo=bar",
-            "Stripped URL is incorrect"
+/// check_expanded sanitizes the last clipboard content again after short links were expanded in the background,
+/// and writes their destinations over the stripped short links if the clipboard wasn't changed meanwhile
+fn check_expanded(
+    clipboard: &mut dyn ClipboardBackend,
+    monitor: &mut Monitor,
+    sanitizer: &Sanitizer,
+) -> Option<String> {
+    let (original, left) = monitor.last_sanitized.clone()?;
+    let expanded = sanitizer
+        .sanitize_expanded(&original)
+        .filter(|expanded| *expanded != left)?;
+    if clipboard.get_text().ok().as_ref() != Some(&left) || clipboard.has_other_content() {
+        monitor.last_sanitized = None;
+        return None;
+    }
+    if monitor.dry_run {
+        info!(
+            "Would write the expanded short links: {} -> {}",
+            left, expanded
         );
+        monitor.last_sanitized = None;
+        return Some(expanded);
     }
+    match write_clipboard(clipboard, sanitizer, expanded.clone()) {
+        Ok(()) => {
+            monitor.wrote(expanded.clone());
+            logging::stripped(&original, &expanded);
+            monitor.history.record(original.clone(), expanded.clone());
+            // Copying the original again still leaves it alone
+            for (stripped_original, stripped, _) in &mut monitor.recently_stripped {
+                if *stripped_original == original {
+                    *stripped = expanded.clone();
+                }
+            }
+            monitor.last_sanitized = Some((original, expanded.clone()));
+            info!("Expanded the short links in the clipboard: {}", expanded);
+            Some(expanded)
+        }
+        Err(e) => {
+            sanitizer.errors().increment(ErrorCategory::ClipboardWrite);
+            error!("{}", e);
+            None
+        }
+    }
+}
 
-    #[test]
-    fn test_strip_tracking() {
-        init_test_settings();
+/// check_html cleans the links in the HTML version of a clipboard whose text had nothing to strip, like a link copied
+/// from a page as rich text, whose text is only the link's title. The text is written back unchanged.
+fn check_html(
+    clipboard: &mut dyn ClipboardBackend,
+    monitor: &mut Monitor,
+    sanitizer: &Sanitizer,
+    text: String,
+) {
+    let Some(html) = clipboard.get_html() else {
+        return;
+    };
+    let sanitized = sanitizer.sanitize_html(&html);
+    // Writing the text and HTML would drop files or an image copied with them
+    if sanitized == html || clipboard.has_other_content() {
+        return;
+    }
+    if monitor.dry_run {
+        info!(
+            "Would strip tracking from the HTML version: {} -> {}",
+            html, sanitized
+        );
+        return;
+    }
+    match clipboard.set_text_and_html(text.clone(), sanitized) {
+        Ok(()) => {
+            monitor.wrote(text);
+            info!("Stripped tracking from the links in the HTML version of the clipboard");
+        }
+        Err(e) => {
+            sanitizer.errors().increment(ErrorCategory::ClipboardWrite);
+            error!("{}", e);
+        }
+    }
+}
 
-        let test_cases = vec![
-            (
-                "https://www.youtube.com/watch?v=1234&si=stripped&feature=share",
-                "https://youtu.be/1234",
-            ),
-            (
-                "https://music.youtube.com/watch?v=5678&si=stripped&feature=share",
-                "https://music.youtube.com/watch?v=5678",
-            ),
-            (
-                "https://example.com/path?utm_source=foo&utm_medium=bar",
-                "https://example.com/path",
-            ),
-            (
-                "https://youtu.be/1234?si=stripped&t=123",
-                "https://youtu.be/1234?t=123",
-            ),
-            (
-                "https://youtube.com/live/xxxxxxxxxx?feature=share",
-                "https://youtu.be/xxxxxxxxxx",
-            ),
-            (
-                "https://youtube.com/shorts/xxxxxxxxxx?feature=share",
-                "https://youtu.be/xxxxxxxxxx",
-            ),
-            (
-                "https://twitter.com/discord/status/1702362670836621351?t=stripped&s=19",
-                "https://twitter.com/discord/status/1702362670836621351",
-            ),
-        ];
+/// run_once cleans the clipboard content, prints it and appends it to the save file, if given, whether anything
+/// had to be stripped or not. Returns the exit code, which is nonzero if the clipboard couldn't be read or
+/// written, or if there's a save file and the clipboard had no URL.
+fn run_once(
+    clipboard: &mut dyn ClipboardBackend,
+    sanitizer: &Sanitizer,
+    save: Option<&std::path::Path>,
+    dry_run: bool,
+) -> i32 {
+    use std::io::Write;
 
-        for (input, expected) in test_cases {
-            let url = Url::parse(input).unwrap();
-            let stripped_url = strip_tracking(&url);
-            assert_eq!(stripped_url.as_str(), expected, "Stripped URL is incorrect");
+    let content = match clipboard.get_text() {
+        Ok(content) => content,
+        Err(e) => {
+            error!("Failed to read clipboard: {}", e);
+            return 1;
+        }
+    };
+    let cleaned = match sanitizer.sanitize_clipboard(&content) {
+        Some(sanitized) if dry_run => {
+            info!("Would strip tracking: {} -> {}", content, sanitized);
+            sanitized
+        }
+        Some(sanitized) if clipboard.has_other_content() => {
+            info!("Leaving the clipboard alone, it has files or an image besides the text");
+            sanitized
+        }
+        Some(sanitized) => {
+            if let Err(e) = write_clipboard(clipboard, sanitizer, sanitized.clone()) {
+                error!("{}", e);
+                return 1;
+            }
+            info!("Stripped tracking from URL: {}", sanitized);
+            sanitized
+        }
+        None if save.is_none() || batch::summarize(sanitizer, &content).found > 0 => content,
+        None => {
+            error!("Clipboard doesn't contain a URL");
+            return 1;
+        }
+    };
+    println!("{}", cleaned);
+    if let Some(path) = save {
+        let appended = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", cleaned.trim_end()));
+        if let Err(e) = appended {
+            error!("Failed to append to {}: {}", path.display(), e);
+            return 1;
+        }
+    }
+    0
+}
+
+/// clean_texts writes every text to the output on its own line, with tracking stripped from its URLs
+fn clean_texts(
+    sanitizer: &Sanitizer,
+    texts: Vec<String>,
+    mut output: impl std::io::Write,
+) -> std::io::Result<()> {
+    for text in texts {
+        let cleaned = sanitizer.sanitize_clipboard(&text).unwrap_or(text);
+        writeln!(output, "{}", cleaned)?;
+    }
+    output.flush()
+}
+
+/// filter_lines writes every line of the input to the output with tracking stripped from its URLs, as for
+/// clipboard content
+fn filter_lines(
+    sanitizer: &Sanitizer,
+    input: impl std::io::BufRead,
+    mut output: impl std::io::Write,
+) -> std::io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        match sanitizer.sanitize_clipboard(&line) {
+            Some(sanitized) => writeln!(output, "{}", sanitized)?,
+            None => writeln!(output, "{}", line)?,
+        }
+    }
+    output.flush()
+}
+
+/// process_file strips tracking from the URLs in the file and prints the result, writes it back to the file
+/// or prints a summary of the changes. Returns the exit code.
+fn process_file(path: &std::path::Path, in_place: bool, summary: bool) -> i32 {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            error!("Failed to read {:?}: {}", path, e);
+            return 1;
+        }
+    };
+    let sanitizer = sanitizer_from_settings();
+    if summary {
+        print!("{}", batch::summarize(&sanitizer, &contents));
+    }
+    let sanitized = sanitizer.sanitize_text(&contents, false);
+    if in_place {
+        if sanitized != contents {
+            if let Err(e) = std::fs::write(path, sanitized) {
+                error!("Failed to write {:?}: {}", path, e);
+                return 1;
+            }
+        }
+    } else if !summary {
+        print!("{}", sanitized);
+    }
+    0
+}
+
+/// tail_file follows the file and writes its new lines with tracking stripped to output or stdout.
+/// It only returns if the files can't be read or written.
+fn tail_file(path: &std::path::Path, output: Option<&std::path::Path>) -> i32 {
+    use std::io::Write;
+
+    let sanitizer = sanitizer_from_settings();
+    let mut tail = match tail::Tail::open_at_end(path) {
+        Ok(tail) => tail,
+        Err(e) => {
+            error!("Failed to open {:?}: {}", path, e);
+            return 1;
+        }
+    };
+    let mut output: Box<dyn Write> = match output {
+        Some(output) => match std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(output)
+        {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                error!("Failed to open {:?}: {}", output, e);
+                return 1;
+            }
+        },
+        None => Box::new(std::io::stdout()),
+    };
+
+    shutdown::install();
+    while !shutdown::requested() {
+        match tail.poll() {
+            Ok(lines) => {
+                for line in lines {
+                    let written = writeln!(output, "{}", sanitizer.sanitize_text(&line, false))
+                        .and_then(|_| output.flush());
+                    if let Err(e) = written {
+                        error!("Failed to write output: {}", e);
+                        return 1;
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to read {:?}: {}", path, e);
+                return 1;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+    info!("Shutting down");
+    0
+}
+
+/// test_corpus runs every case in the corpus file through the sanitizer, prints mismatches and returns the exit code
+fn test_corpus(path: &std::path::Path) -> i32 {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            error!("Failed to read corpus {:?}: {}", path, e);
+            return 2;
         }
+    };
+    let cases = match parse_fixture(path, &contents) {
+        Ok(cases) => cases,
+        Err(e) => {
+            error!("Failed to parse corpus {:?}: {}", path, e);
+            return 2;
+        }
+    };
+
+    let failures = run_corpus(&sanitizer_from_settings(), &cases);
+    for failure in &failures {
+        println!("FAIL {}", failure.input);
+        println!("  expected: {}", failure.expected);
+        println!("  actual:   {}", failure.actual);
+        for line in url_diff(&failure.expected, &failure.actual) {
+            println!("    {}", line);
+        }
+    }
+    println!(
+        "{} passed, {} failed",
+        cases.len() - failures.len(),
+        failures.len()
+    );
+
+    if failures.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+struct CorpusFailure {
+    input: String,
+    expected: String,
+    actual: String,
+}
+
+/// parse_fixture reads the cases of a corpus file, or of a TOML or JSON fixture by its extension
+fn parse_fixture(path: &std::path::Path, contents: &str) -> Result<Vec<(String, String)>, String> {
+    let cases: Vec<serde_json::Value> = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => {
+            let fixture: toml::Value = toml::from_str(contents).map_err(|e| e.to_string())?;
+            let cases = fixture.get("cases").and_then(toml::Value::as_array);
+            let cases = cases.ok_or("expected a [[cases]] array of tables")?;
+            cases
+                .iter()
+                .map(|case| serde_json::to_value(case).map_err(|e| e.to_string()))
+                .collect::<Result<_, _>>()?
+        }
+        Some("json") => match serde_json::from_str(contents).map_err(|e| e.to_string())? {
+            serde_json::Value::Array(cases) => cases,
+            _ => return Err("expected an array of cases".to_string()),
+        },
+        _ => return parse_corpus(contents),
+    };
+    cases
+        .iter()
+        .enumerate()
+        .map(|(number, case)| {
+            let field = |name: &str| case.get(name).and_then(serde_json::Value::as_str);
+            match (field("input"), field("expected")) {
+                (Some(input), Some(expected)) => Ok((input.to_string(), expected.to_string())),
+                _ => Err(format!(
+                    "case {}: expected input and expected strings",
+                    number + 1
+                )),
+            }
+        })
+        .collect()
+}
+
+/// url_diff describes how the actual URL differs from the expected one: the params that are missing with -, the
+/// ones that shouldn't be there with +, and the other parts that differ. It's empty if either isn't a URL.
+fn url_diff(expected: &str, actual: &str) -> Vec<String> {
+    let (Ok(expected), Ok(actual)) = (url::Url::parse(expected), url::Url::parse(actual)) else {
+        return vec![];
+    };
+    let mut diff = vec![];
+    let parts = |url: &url::Url| {
+        [
+            ("scheme", url.scheme().to_string()),
+            ("host", url.host_str().unwrap_or_default().to_string()),
+            ("path", url.path().to_string()),
+            ("fragment", url.fragment().unwrap_or_default().to_string()),
+        ]
+    };
+    for ((name, expected), (_, actual)) in parts(&expected).into_iter().zip(parts(&actual)) {
+        if expected != actual {
+            diff.push(format!(
+                "{}: expected {:?}, got {:?}",
+                name, expected, actual
+            ));
+        }
+    }
+    let pairs = |url: &url::Url| -> Vec<String> {
+        url.query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(String::from)
+            .collect()
+    };
+    let (mut missing, mut extra) = (pairs(&expected), pairs(&actual));
+    missing.retain(|pair| match extra.iter().position(|other| other == pair) {
+        Some(i) => {
+            extra.remove(i);
+            false
+        }
+        None => true,
+    });
+    diff.extend(missing.iter().map(|pair| format!("- {}", pair)));
+    diff.extend(extra.iter().map(|pair| format!("+ {}", pair)));
+    if diff.is_empty() && expected.query().unwrap_or_default() != actual.query().unwrap_or_default()
+    {
+        diff.push("params are in a different order".to_string());
+    }
+    diff
+}
+
+/// parse_corpus reads `input expected` pairs separated by whitespace, one per line.
+/// Empty lines and lines starting with # are ignored.
+fn parse_corpus(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut cases = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut columns = line.split_whitespace();
+        match (columns.next(), columns.next(), columns.next()) {
+            (Some(input), Some(expected), None) => {
+                cases.push((input.to_string(), expected.to_string()))
+            }
+            _ => return Err(format!("line {}: expected two columns", number + 1)),
+        }
+    }
+    Ok(cases)
+}
+
+/// run_corpus returns the cases whose sanitized input doesn't match the expected output
+fn run_corpus(sanitizer: &Sanitizer, cases: &[(String, String)]) -> Vec<CorpusFailure> {
+    let mut failures = vec![];
+    for (input, expected) in cases {
+        let actual = match sanitizer.parse_text(input) {
+            Some(url) => sanitizer.sanitize(&url).to_string(),
+            None => input.to_string(),
+        };
+        if &actual != expected {
+            failures.push(CorpusFailure {
+                input: input.to_string(),
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+    }
+    failures
+}
+
+/// log_filter combines --verbose and LOG_FILTER into an env_logger filter. --verbose sets the level for
+/// everything and takes precedence over a bare level in LOG_FILTER, and the module=level directives in
+/// LOG_FILTER apply on top of it. Modules of this crate can be named without the crate prefix.
+/// RUST_LOG replaces the result completely when it's set.
+fn log_filter(verbosity: Option<&str>, config_filter: Option<&str>) -> String {
+    let mut level = None;
+    let mut directives = Vec::new();
+    for directive in config_filter.unwrap_or_default().split(',') {
+        let directive = directive.trim();
+        match directive.split_once('=') {
+            None if directive.is_empty() => {}
+            None => level = Some(directive),
+            Some((module, module_level)) => {
+                let module = module.trim();
+                if LOG_MODULES.contains(&module) {
+                    directives.push(format!(
+                        "{}::{}={}",
+                        env!("CARGO_CRATE_NAME"),
+                        module,
+                        module_level.trim()
+                    ));
+                } else {
+                    directives.push(format!("{}={}", module, module_level.trim()));
+                }
+            }
+        }
+    }
+    let level = verbosity.or(level).unwrap_or(DEFAULT_LOG_LEVEL);
+    std::iter::once(level.to_string())
+        .chain(directives)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn init_settings() {
+    let config_path = config_file()
+        .parent()
+        .map(std::path::Path::to_path_buf)
+        .unwrap_or_default();
+
+    let default_config = Settings::new();
+
+    // path doesn't exist
+    if !config_path.is_dir() {
+        let res = std::fs::create_dir_all(&config_path);
+        if let Err(e) = res {
+            error!(
+                "Failed to create config directory {:?}: {:?}",
+                config_path, e
+            );
+            set_settings(default_config);
+            return;
+        }
+        let res = std::fs::write(config_path.join("config.toml"), "");
+        if let Err(e) = res {
+            error!(
+                "Failed to create config file {:?}: {:?}",
+                config_path.join("config.toml"),
+                e
+            );
+            set_settings(default_config);
+            return;
+        }
+    }
+
+    match load_settings(&config_path.join("config.toml")) {
+        Ok(map) => {
+            info!("Using config: {:?}", map);
+            set_settings(map);
+        }
+        // Like a broken reload, except there are no previous settings to keep. HOT_RELOAD isn't read from the broken
+        // file, so fixing it needs a restart or the reload command.
+        Err(e) => {
+            error!("Failed to load config, using the defaults: {}", e);
+            set_settings(default_config);
+        }
+    }
+}
+
+/// config_file is config.toml in the clipboard-sanitizer directory of the platform's config directory, or in the
+/// current directory if there's no home directory to find it from
+fn config_file() -> std::path::PathBuf {
+    config_dir()
+        .unwrap_or_default()
+        .join("clipboard-sanitizer")
+        .join("config.toml")
+}
+
+/// ConfigError is an error loading the config, with the position of the problem for syntax errors
+#[derive(Debug, PartialEq)]
+enum ConfigError {
+    Syntax {
+        path: std::path::PathBuf,
+        /// Line and column, starting from 1
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    Other(String),
+}
+
+impl ConfigError {
+    fn new(path: &std::path::Path, e: config::ConfigError) -> Self {
+        if let config::ConfigError::FileParse { cause, .. } = &e {
+            if let Some(toml_error) = cause.downcast_ref::<toml::de::Error>() {
+                if let Some((line, column)) = toml_error.line_col() {
+                    // The toml error's message ends with the position, which is shown with the path instead
+                    let message = toml_error.to_string();
+                    let message = match message.rsplit_once(" at line ") {
+                        Some((message, _)) => message.to_string(),
+                        None => message,
+                    };
+                    return ConfigError::Syntax {
+                        path: path.to_path_buf(),
+                        line: line + 1,
+                        column: column + 1,
+                        message,
+                    };
+                }
+            }
+        }
+        ConfigError::Other(e.to_string())
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Syntax {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            ConfigError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// load_settings reads the config file, with environment variables overriding its values.
+/// The keys of tables are flattened to `TABLE.key`, see settings::read_table.
+fn load_settings(config_file: &std::path::Path) -> Result<Settings, ConfigError> {
+    let values = Config::builder()
+        .add_source(config::File::from(config_file))
+        .add_source(config::Environment::with_prefix("CLIPBOARD_SANITIZER"))
+        .build()
+        .and_then(|cfg| cfg.try_deserialize::<HashMap<String, config::Value>>())
+        .map_err(|e| ConfigError::new(config_file, e))?;
+    let mut map = HashMap::new();
+    for (key, value) in values {
+        flatten_setting(key, value, &mut map).map_err(|e| ConfigError::new(config_file, e))?;
+    }
+    Ok(map)
+}
+
+/// flatten_setting inserts the value, or every value in a table and the tables in it with their keys joined by dots
+fn flatten_setting(
+    key: String,
+    value: config::Value,
+    map: &mut Settings,
+) -> Result<(), config::ConfigError> {
+    if let config::ValueKind::Table(table) = value.kind {
+        for (table_key, value) in table {
+            flatten_setting(format!("{}.{}", key, table_key), value, map)?;
+        }
+    } else {
+        map.insert(key, value.into_string()?);
+    }
+    Ok(())
+}
+
+/// reload_settings replaces the settings with the config file's current contents and returns a summary
+/// of the new settings. The settings are left unchanged if the config can't be loaded.
+fn reload_settings(config_file: &std::path::Path) -> Result<String, String> {
+    let map = load_settings(config_file).map_err(|e| e.to_string())?;
+    let mut summary: Vec<String> = map
+        .iter()
+        .map(|(key, value)| format!("{} = {:?}", key, value))
+        .collect();
+    summary.sort();
+    info!("Reloaded config: {:?}", map);
+    set_settings(map);
+    Ok(summary.join("\n"))
+}
+
+/// read_bool_setting returns true if the setting is set to "true" or "1"
+fn read_bool_setting(key: &str, default: bool) -> bool {
+    with_settings(|settings| settings::read_bool(settings, key, default))
+}
+
+/// read_u64_setting returns the setting as a number, or default if it's not set or invalid
+fn read_u64_setting(key: &str, default: u64) -> u64 {
+    with_settings(|settings| settings::read_u64(settings, key, default))
+}
+
+fn read_setting(key: &str) -> Option<String> {
+    with_settings(|settings| settings::read(settings, key))
+}
+
+/// set_settings replaces all settings. Sanitizers and monitors built before keep the settings they read.
+fn set_settings(mut map: Settings) {
+    if let Some(domains) = NO_TOUCH_DOMAINS.get() {
+        add_skip_domains(&mut map, domains);
+    }
+    if let Some(settings) = CLI_SETTINGS.get() {
+        map.extend(settings.clone());
+    }
+    // The map is replaced whole, so a panic elsewhere while holding the lock can't leave it half-written
+    *APP_CONFIG.write().unwrap_or_else(|e| e.into_inner()) = Some(map);
+    SETTINGS_REVISION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// cli_settings returns the settings given as options
+fn cli_settings(args: &Args) -> Settings {
+    [
+        ("POLL_INTERVAL_MS", args.poll_interval_ms),
+        ("POLL_MAX_INTERVAL_MS", args.poll_max_interval_ms),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((key.to_string(), value?.to_string())))
+    .collect()
+}
+
+/// add_skip_domains appends the domains to SKIP_DOMAINS
+fn add_skip_domains(map: &mut Settings, domains: &[String]) {
+    if domains.is_empty() {
+        return;
+    }
+    let skip = map.entry("SKIP_DOMAINS".to_string()).or_default();
+    *skip = skip
+        .split(',')
+        .filter(|domain| !domain.trim().is_empty())
+        .chain(domains.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(",");
+}
+
+/// with_settings calls f with the current settings, which are empty until init_settings has run
+fn with_settings<T>(f: impl FnOnce(&Settings) -> T) -> T {
+    match &*APP_CONFIG.read().unwrap_or_else(|e| e.into_inner()) {
+        Some(settings) => f(settings),
+        None => f(&Settings::new()),
+    }
+}
+
+fn sanitizer_from_settings() -> Sanitizer {
+    with_settings(Sanitizer::from_config)
+}
+
+/// history_settings returns HISTORY_SIZE and HISTORY_FILE, if set
+fn history_settings() -> (usize, Option<std::path::PathBuf>) {
+    let size = read_u64_setting("HISTORY_SIZE", DEFAULT_HISTORY_SIZE as u64);
+    let file = read_setting("HISTORY_FILE")
+        .filter(|path| !path.is_empty())
+        .map(std::path::PathBuf::from);
+    (usize::try_from(size).unwrap_or(usize::MAX), file)
+}
+
+/// Monitor remembers the last clipboard content it saw so unchanged content isn't processed again.
+/// It also remembers the originals of recently stripped URLs. If the user copies one of them again within
+/// override_window we take it as the user wanting the tracked version and leave it alone for override_cooldown.
+/// The same goes for copying a URL that strips to one we just wrote, e.g. after adding a param back by hand.
+struct Monitor {
+    last_seen: Option<String>,
+    processed: u64,
+    override_window: Duration,
+    override_cooldown: Duration,
+    /// The clipboard is polled every poll_interval after a change, backing off to poll_max_interval when idle
+    poll_interval: Duration,
+    poll_max_interval: Duration,
+    last_change: Option<Instant>,
+    /// only_user_copies leaves content alone if another program set it in the background
+    only_user_copies: bool,
+    /// recently_stripped has the original and stripped content of every strip within override_window
+    recently_stripped: Vec<(String, String, Instant)>,
+    trusted: Vec<(String, Instant)>,
+    /// last_sequence is the clipboard's sequence number at the last check, on platforms that have one
+    last_sequence: Option<u32>,
+    /// dry_run only reports what would be stripped, so nothing is written and copies aren't overrides
+    dry_run: bool,
+    /// paused leaves the clipboard alone. Content copied while paused stays alone after resuming.
+    paused: bool,
+    /// failed_reads counts the clipboard reads that failed in a row
+    failed_reads: u32,
+    /// reopen_attempts counts the times the backend was reopened since the last read that worked, and reopen_at is
+    /// when it may be reopened again
+    reopen_attempts: u32,
+    reopen_at: Option<Instant>,
+    /// clean_text is set when the last check found nothing to strip in the text, so its HTML can still be checked
+    clean_text: bool,
+    /// history has the last replacements for the undo and history commands. Only the clipboard's are kept.
+    history: history::History,
+    selection: Selection,
+    /// revision is the SETTINGS_REVISION that apply_settings read
+    revision: u32,
+    /// last_sanitized has the last content that was sanitized and what was left on the clipboard, to sanitize it
+    /// again once its short links have been expanded
+    last_sanitized: Option<(String, String)>,
+}
+
+impl Monitor {
+    fn new(override_window: Duration, override_cooldown: Duration) -> Monitor {
+        Monitor {
+            last_seen: None,
+            processed: 0,
+            override_window,
+            override_cooldown,
+            poll_interval: Duration::from_millis(50),
+            poll_max_interval: Duration::from_millis(500),
+            last_change: None,
+            only_user_copies: false,
+            recently_stripped: vec![],
+            trusted: vec![],
+            last_sequence: None,
+            dry_run: false,
+            paused: false,
+            clean_text: false,
+            failed_reads: 0,
+            history: history::History::new(DEFAULT_HISTORY_SIZE, None),
+            selection: Selection::Clipboard,
+            revision: 0,
+            last_sanitized: None,
+            reopen_attempts: 0,
+            reopen_at: None,
+        }
+    }
+
+    fn from_settings() -> Monitor {
+        Monitor::for_selection(Selection::Clipboard)
+    }
+
+    fn for_selection(selection: Selection) -> Monitor {
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        monitor.selection = selection;
+        if selection == Selection::Clipboard {
+            let (size, file) = history_settings();
+            monitor.history = history::History::new(size, file);
+        }
+        monitor.apply_settings();
+        monitor
+    }
+
+    /// refresh_settings reads the settings again if they were replaced since apply_settings last read them
+    fn refresh_settings(&mut self) {
+        if self.revision != SETTINGS_REVISION.load(Ordering::SeqCst) {
+            self.apply_settings();
+        }
+    }
+
+    /// apply_settings reads the monitor's settings again, keeping what it has seen so far
+    fn apply_settings(&mut self) {
+        self.revision = SETTINGS_REVISION.load(Ordering::SeqCst);
+        self.override_window = Duration::from_secs(read_u64_setting("OVERRIDE_WINDOW_SECS", 30));
+        self.override_cooldown =
+            Duration::from_secs(read_u64_setting("OVERRIDE_COOLDOWN_SECS", 300));
+        self.only_user_copies = read_bool_setting("ONLY_USER_COPIES", false);
+        self.poll_interval = Duration::from_millis(read_u64_setting("POLL_INTERVAL_MS", 50));
+        self.poll_max_interval =
+            Duration::from_millis(read_u64_setting("POLL_MAX_INTERVAL_MS", 500))
+                .max(self.poll_interval);
+        if self.selection == Selection::Clipboard {
+            let (size, file) = history_settings();
+            self.history.configure(size, file);
+        } else {
+            self.history.configure(0, None);
+        }
+    }
+
+    /// check returns the sanitized content if the clipboard changed and anything in it was sanitized
+    fn check(
+        &mut self,
+        content: String,
+        source: CopySource,
+        sanitizer: &Sanitizer,
+        now: Instant,
+    ) -> Option<String> {
+        self.clean_text = false;
+        if self.last_seen.as_ref() == Some(&content) {
+            return None;
+        }
+        self.last_seen = Some(content.clone());
+        self.last_change = Some(now);
+        self.last_sanitized = None;
+        if self.paused {
+            debug!("Paused, leaving clipboard alone: {}", content);
+            return None;
+        }
+        if self.only_user_copies && source == CopySource::Program {
+            debug!("Leaving content set by another program alone: {}", content);
+            return None;
+        }
+
+        self.recently_stripped
+            .retain(|(_, _, stripped_at)| now.duration_since(*stripped_at) < self.override_window);
+        self.trusted
+            .retain(|(_, trusted_at)| now.duration_since(*trusted_at) < self.override_cooldown);
+        if self.trusted.iter().any(|(trusted, _)| *trusted == content) {
+            debug!("Leaving trusted URL alone: {}", content);
+            return None;
+        }
+        if let Some(i) = self
+            .recently_stripped
+            .iter()
+            .position(|(original, _, _)| *original == content)
+        {
+            info!("Copied a stripped URL again, leaving it alone: {}", content);
+            sanitizer.record_override(&content);
+            self.recently_stripped.swap_remove(i);
+            self.trusted.push((content, now));
+            return None;
+        }
+
+        self.processed += 1;
+        let Some(sanitized) = sanitizer.sanitize_clipboard(&content) else {
+            self.clean_text = true;
+            self.last_sanitized = Some((content.clone(), content));
+            return None;
+        };
+        if let Some(i) = self
+            .recently_stripped
+            .iter()
+            .position(|(_, stripped, _)| *stripped == sanitized)
+        {
+            info!(
+                "Tracking was added back to a stripped URL, leaving it alone: {}",
+                content
+            );
+            sanitizer.record_override(&content);
+            self.recently_stripped.swap_remove(i);
+            self.trusted.push((content, now));
+            return None;
+        }
+        Some(sanitized)
+    }
+
+    /// stripped records that the sanitized content check returned was written, or would have been in a dry run.
+    /// Copying the original content again within override_window then counts as an override.
+    fn stripped(&mut self, content: String, sanitized: String, now: Instant) {
+        let left = if self.dry_run {
+            content.clone()
+        } else {
+            sanitized.clone()
+        };
+        self.last_sanitized = Some((content.clone(), left));
+        if !self.override_window.is_zero() && !self.dry_run {
+            self.recently_stripped.push((content, sanitized, now));
+        }
+    }
+
+    /// poll_interval returns how long to wait before the next check. The interval grows with the time
+    /// since the clipboard last changed, so it's polled quickly right after activity and rarely when idle.
+    fn poll_interval(&self, now: Instant) -> Duration {
+        let Some(last_change) = self.last_change else {
+            return self.poll_interval;
+        };
+        (now.duration_since(last_change) / 20).clamp(self.poll_interval, self.poll_max_interval)
+    }
+
+    /// wrote records content we put on the clipboard so it isn't processed again on the next check
+    fn wrote(&mut self, content: String) {
+        self.last_seen = Some(content);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use url::Url;
+
+    /// Tests that write the settings hold this so tests reading them don't see another test's settings
+    static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+    #[cfg(unix)]
+    fn init_test_settings() {
+        let mut app_config = HashMap::new();
+        app_config.insert("YOUTUBE_PREFIXES".to_string(), "live,shorts".to_string());
+        set_settings(app_config);
+    }
+
+    fn test_sanitizer() -> Sanitizer {
+        Sanitizer::from_config(&HashMap::from([(
+            "YOUTUBE_PREFIXES".to_string(),
+            "live,shorts".to_string(),
+        )]))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reload_over_control_socket() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir =
+            std::env::temp_dir().join(format!("clipboard-sanitizer-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_file = dir.join("config.toml");
+        let socket_path = dir.join("control.sock");
+        std::fs::write(&config_file, "").unwrap();
+        init_test_settings();
+        let mut sanitizer = sanitizer_from_settings();
+        let mut monitor = Monitor::from_settings();
+        let mut notifier = None;
+        let mut clipboard = clipboard::MockBackend::default();
+        let url = Url::parse("https://example.com/?gclid=1&utm_source=x").unwrap();
+        assert_eq!(
+            sanitizer.sanitize(&url).as_str(),
+            "https://example.com/?gclid=1"
+        );
+
+        let socket = control::ControlSocket::bind(&socket_path).unwrap();
+        let mut send = |command: &'static str| {
+            let client = {
+                let socket_path = socket_path.clone();
+                std::thread::spawn(move || control::send(&socket_path, command).unwrap())
+            };
+            let mut answered = false;
+            while !answered {
+                socket.poll(|command| {
+                    answered = true;
+                    handle_control_command(
+                        command,
+                        &config_file,
+                        &mut clipboard,
+                        &mut sanitizer,
+                        &mut monitor,
+                        &mut notifier,
+                    )
+                });
+            }
+            client.join().unwrap()
+        };
+
+        std::fs::write(
+            &config_file,
+            "EXTRA_TRACKING_PARAMS = \"gclid\"\nPOLL_INTERVAL_MS = 100\n",
+        )
+        .unwrap();
+        assert_eq!(
+            send("reload"),
+            "ok\nEXTRA_TRACKING_PARAMS = \"gclid\"\nPOLL_INTERVAL_MS = \"100\"\n"
+        );
+
+        // A broken config is reported and the previous settings stay in effect
+        std::fs::write(&config_file, "EXTRA_TRACKING_PARAMS = [").unwrap();
+        assert!(send("reload").starts_with("error: "));
+        assert!(send("status").starts_with("error: unknown command"));
+
+        assert_eq!(send("history"), "ok\n");
+        assert_eq!(send("undo"), "error: nothing to undo\n");
+        assert!(send("stats").starts_with("ok\nerrors_"));
+
+        drop(socket);
+        assert_eq!(sanitizer.sanitize(&url).as_str(), "https://example.com/");
+        assert_eq!(monitor.poll_interval, Duration::from_millis(100));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_domains_table() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = std::env::temp_dir().join("clipboard-sanitizer-test-domains.toml");
+        std::fs::write(
+            &path,
+            "YOUTUBE_PREFIXES = \"live,shorts\"\n\
+             [DOMAINS]\n\
+             \"Example.com\" = \"ref\"\n\
+             \"*\" = \"utm_source,gclid\"\n\
+             [RULES.\"example.net\"]\n\
+             params = \"source\"\n\
+             common = true\n",
+        )
+        .unwrap();
+        let settings = load_settings(&path);
+        std::fs::remove_file(&path).unwrap();
+        set_settings(settings.unwrap());
+        let sanitizer = sanitizer_from_settings();
+
+        let cases = [
+            // Only the configured params are stripped on example.com and its subdomains
+            (
+                "https://example.com/?ref=a&utm_source=b&id=1",
+                "https://example.com/?utm_source=b&id=1",
+            ),
+            ("https://www.example.com/?ref=a", "https://www.example.com/"),
+            // "*" replaces the default utm params and prefixes everywhere else
+            (
+                "https://other.example.org/?utm_source=a&gclid=b&utm_medium=c&ref=d",
+                "https://other.example.org/?utm_medium=c&ref=d",
+            ),
+            // Rules in nested tables are read too
+            (
+                "https://example.net/?source=a&gclid=b&id=1",
+                "https://example.net/?id=1",
+            ),
+            // YouTube isn't overridden, so its rule still applies
+            (
+                "https://www.youtube.com/watch?v=abc&si=x&utm_source=y",
+                "https://youtu.be/abc?utm_source=y",
+            ),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(sanitizer.sanitize(&url).as_str(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_run_once_and_save() {
+        let sanitizer = test_sanitizer();
+        let save = std::env::temp_dir().join("clipboard-sanitizer-test-once.txt");
+        let _ = std::fs::remove_file(&save);
+        let mut clipboard = clipboard::MockBackend {
+            text: Some("https://example.com/?utm_source=x&id=1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(run_once(&mut clipboard, &sanitizer, Some(&save), false), 0);
+        assert_eq!(clipboard.text.as_deref(), Some("https://example.com/?id=1"));
+
+        // A URL with nothing to strip is saved too
+        clipboard.text = Some("https://example.com/clean".to_string());
+        assert_eq!(run_once(&mut clipboard, &sanitizer, Some(&save), false), 0);
+
+        clipboard.text = Some("not a link".to_string());
+        assert_eq!(run_once(&mut clipboard, &sanitizer, Some(&save), false), 1);
+        clipboard.text = None;
+        assert_eq!(run_once(&mut clipboard, &sanitizer, Some(&save), false), 1);
+
+        let saved = std::fs::read_to_string(&save).unwrap();
+        std::fs::remove_file(&save).unwrap();
+        assert_eq!(
+            saved,
+            "https://example.com/?id=1\nhttps://example.com/clean\n"
+        );
+    }
+
+    #[test]
+    fn test_run_once_without_save() {
+        let sanitizer = test_sanitizer();
+        let mut clipboard = clipboard::MockBackend {
+            text: Some("not a link".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(run_once(&mut clipboard, &sanitizer, None, false), 0);
+        clipboard.fail_reads = true;
+        assert_eq!(run_once(&mut clipboard, &sanitizer, None, false), 1);
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::from_secs(30), Duration::from_secs(300));
+        monitor.dry_run = true;
+        let tracked = "https://example.com/?utm_source=foo".to_string();
+        let mut clipboard = clipboard::MockBackend {
+            text: Some(tracked.clone()),
+            ..Default::default()
+        };
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer),
+            Some("https://example.com/".to_string())
+        );
+        assert_eq!(clipboard.text.as_ref(), Some(&tracked));
+        assert_eq!(monitor.processed, 1);
+        assert!(monitor.history.last().is_none());
+
+        // Copying it again isn't an override, since it was never stripped
+        clipboard.text = Some("other".to_string());
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        clipboard.text = Some(tracked.clone());
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert!(monitor.trusted.is_empty());
+        assert_eq!(monitor.processed, 3);
+
+        assert_eq!(run_once(&mut clipboard, &sanitizer, None, true), 0);
+        assert_eq!(clipboard.text, Some(tracked));
+    }
+
+    #[test]
+    fn test_filter_lines() {
+        let sanitizer = test_sanitizer();
+        let input = "https://example.com/?utm_source=x&id=1\nnot a link\n\nsee https://x.com/u/status/1?s=20\n";
+        let mut output = vec![];
+        filter_lines(&sanitizer, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "https://example.com/?id=1\nnot a link\n\nsee https://x.com/u/status/1\n"
+        );
+    }
+
+    #[test]
+    fn test_clean_texts() {
+        let sanitizer = test_sanitizer();
+        let mut output = vec![];
+        let texts = vec![
+            "https://www.youtube.com/watch?v=1234&si=x".to_string(),
+            "not a link".to_string(),
+        ];
+        clean_texts(&sanitizer, texts, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "https://youtu.be/1234\nnot a link\n"
+        );
+
+        let args = Args::try_parse_from(["clipboard-sanitizer", "clean", "a", "b"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Clean { texts }) if texts == ["a", "b"]));
+        assert!(Args::try_parse_from(["clipboard-sanitizer", "clean"]).is_err());
+    }
+
+    #[test]
+    fn test_config_syntax_error() {
+        let path = std::env::temp_dir().join("clipboard-sanitizer-test-bad-config.toml");
+        std::fs::write(
+            &path,
+            "YOUTUBE_PREFIXES = \"live\"\nCANONICAL_ENCODE = \"true\n",
+        )
+        .unwrap();
+        let e = load_settings(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            e,
+            ConfigError::Syntax {
+                path: path.clone(),
+                line: 2,
+                column: 25,
+                message: "newline in string found".to_string(),
+            }
+        );
+        assert_eq!(
+            e.to_string(),
+            format!("{}:2:25: newline in string found", path.display())
+        );
+    }
+
+    #[test]
+    fn test_log_filter() {
+        assert_eq!(log_filter(None, None), "info");
+        assert_eq!(log_filter(Some("debug"), None), "debug");
+        assert_eq!(
+            log_filter(None, Some("params=debug, clipboard=warn")),
+            "info,clipboard_sanitizer::params=debug,clipboard_sanitizer::clipboard=warn"
+        );
+        assert_eq!(
+            log_filter(None, Some("pipeline=debug,expand=trace")),
+            "info,clipboard_sanitizer::pipeline=debug,clipboard_sanitizer::expand=trace"
+        );
+        assert_eq!(
+            log_filter(Some("trace"), Some("warn,arboard=error")),
+            "trace,arboard=error"
+        );
+        assert_eq!(log_filter(None, Some("warn,")), "warn");
+    }
+
+    #[test]
+    fn test_log_modules() {
+        // Log records are tagged with their module_path!, which starts with the prefix log_filter adds
+        assert_eq!(
+            module_path!(),
+            format!("{}::tests", env!("CARGO_CRATE_NAME"))
+        );
+        // Every module in src can be named, also ones behind a platform or feature
+        let mut modules: Vec<String> =
+            std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/src"))
+                .unwrap()
+                .filter_map(|entry| {
+                    let path = entry.unwrap().path();
+                    let name = path.file_stem()?.to_str()?.to_string();
+                    (path.extension()? == "rs" && name != "lib" && name != "main").then_some(name)
+                })
+                .collect();
+        modules.sort();
+        assert_eq!(modules, LOG_MODULES);
+    }
+
+    #[test]
+    fn test_error_metrics_by_category() {
+        let sanitizer = Sanitizer::from_config(&Settings::from([
+            ("POST_PROCESS_CMD".to_string(), "echo not a url".to_string()),
+            ("POST_PROCESS_TIMEOUT_MS".to_string(), "5000".to_string()),
+        ]));
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let mut clipboard = clipboard::MockBackend::default();
+
+        // An empty clipboard isn't an error
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(sanitizer.errors().total(), 0);
+
+        clipboard.fail_reads = true;
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(sanitizer.errors().get(ErrorCategory::ClipboardRead), 1);
+
+        // The post-process command printing garbage is a parse error, and writing the result fails
+        clipboard.fail_reads = false;
+        clipboard.fail_writes = true;
+        clipboard.text = Some("https://example.com/?utm_source=foo".to_string());
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(sanitizer.errors().get(ErrorCategory::Parse), 1);
+        assert_eq!(sanitizer.errors().get(ErrorCategory::ClipboardWrite), 1);
+
+        assert_eq!(sanitizer.errors().get(ErrorCategory::NetworkTimeout), 0);
+        assert_eq!(sanitizer.errors().total(), 3);
+    }
+
+    #[test]
+    fn test_run_corpus() {
+        let sanitizer = test_sanitizer();
+
+        let cases = parse_corpus(include_str!("../corpus.txt")).unwrap();
+        assert!(!cases.is_empty());
+        let failures = run_corpus(&sanitizer, &cases);
+        assert!(
+            failures.is_empty(),
+            "Corpus case failed: {}",
+            failures[0].input
+        );
+
+        let cases = parse_corpus(
+            "# comment\n\nhttps://example.com/?utm_source=foo https://example.com/?utm_source=foo\n",
+        )
+        .unwrap();
+        let failures = run_corpus(&sanitizer, &cases);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].actual, "https://example.com/");
+
+        assert!(parse_corpus("https://example.com/").is_err());
+
+        let case = vec![(
+            "https://example.com/?utm_source=x".to_string(),
+            "https://example.com/".to_string(),
+        )];
+        let toml = "[[cases]]\ninput = \"https://example.com/?utm_source=x\"\nexpected = \"https://example.com/\"\n";
+        let json = r#"[{"input": "https://example.com/?utm_source=x", "expected": "https://example.com/"}]"#;
+        let tsv = "https://example.com/?utm_source=x\thttps://example.com/\n";
+        for (file, contents) in [
+            ("cases.toml", toml),
+            ("cases.json", json),
+            ("cases.txt", tsv),
+        ] {
+            assert_eq!(
+                parse_fixture(std::path::Path::new(file), contents).unwrap(),
+                case,
+                "{}",
+                file
+            );
+        }
+        assert!(parse_fixture(std::path::Path::new("cases.json"), r#"[{"input": "a"}]"#).is_err());
+        assert!(parse_fixture(std::path::Path::new("cases.toml"), "input = \"a\"").is_err());
+
+        assert_eq!(
+            url_diff(
+                "https://example.com/a?id=1&page=2",
+                "https://www.example.com/a?id=1&utm_source=x"
+            ),
+            [
+                "host: expected \"example.com\", got \"www.example.com\"",
+                "- page=2",
+                "+ utm_source=x",
+            ]
+        );
+        assert_eq!(
+            url_diff(
+                "https://example.com/?a=1&b=2",
+                "https://example.com/?b=2&a=1"
+            ),
+            ["params are in a different order"]
+        );
+        assert!(url_diff("not a url", "https://example.com/").is_empty());
+    }
+
+    #[test]
+    fn test_no_touch_adds_skip_domains() {
+        let mut map = Settings::new();
+        add_skip_domains(&mut map, &[]);
+        assert!(map.is_empty());
+        add_skip_domains(&mut map, &["example.com".to_string()]);
+        assert_eq!(map["SKIP_DOMAINS"], "example.com");
+
+        map.insert("SKIP_DOMAINS".to_string(), "intranet.*,".to_string());
+        add_skip_domains(
+            &mut map,
+            &["a.example".to_string(), "b.example".to_string()],
+        );
+        assert_eq!(map["SKIP_DOMAINS"], "intranet.*,a.example,b.example");
+    }
+
+    #[test]
+    fn test_monitor_leaves_clipboard_alone_while_paused() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let now = Instant::now();
+        let tracked = "https://example.com/?utm_source=foo".to_string();
+
+        monitor.paused = true;
+        assert!(monitor
+            .check(tracked.clone(), CopySource::Unknown, &sanitizer, now)
+            .is_none());
+        // Content copied while paused isn't cleaned after resuming, only what's copied next
+        monitor.paused = false;
+        assert!(monitor
+            .check(tracked.clone(), CopySource::Unknown, &sanitizer, now)
+            .is_none());
+        assert_eq!(
+            monitor.check(
+                "https://example.com/?utm_medium=bar".to_string(),
+                CopySource::Unknown,
+                &sanitizer,
+                now
+            ),
+            Some("https://example.com/".to_string())
+        );
+    }
+
+    #[test]
+    fn test_monitor_skips_unchanged_content() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let now = Instant::now();
+
+        let tracked = "https://example.com/?utm_source=foo".to_string();
+        let sanitized = monitor
+            .check(tracked.clone(), CopySource::Unknown, &sanitizer, now)
+            .unwrap();
+        assert_eq!(sanitized, "https://example.com/");
+        monitor.wrote(sanitized.clone());
+
+        // Idle iterations reading back our own write or any other unchanged content do no work
+        for _ in 0..100 {
+            assert!(monitor
+                .check(sanitized.clone(), CopySource::Unknown, &sanitizer, now)
+                .is_none());
+        }
+        assert_eq!(monitor.processed, 1);
+
+        assert!(monitor
+            .check(
+                "some text".to_string(),
+                CopySource::Unknown,
+                &sanitizer,
+                now
+            )
+            .is_none());
+        assert!(monitor
+            .check(
+                "some text".to_string(),
+                CopySource::Unknown,
+                &sanitizer,
+                now
+            )
+            .is_none());
+        assert_eq!(monitor.processed, 2);
+
+        // Copying the tracked URL again after something else is processed normally
+        assert!(monitor
+            .check(tracked, CopySource::Unknown, &sanitizer, now)
+            .is_some());
+        assert_eq!(monitor.processed, 3);
+    }
+
+    #[test]
+    fn test_check_clipboard_leaves_files_and_images() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::from_secs(30), Duration::from_secs(300));
+        let mut clipboard = clipboard::MockBackend {
+            text: Some("https://example.com/?utm_source=x".to_string()),
+            other_content: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer),
+            None
+        );
+        assert_eq!(clipboard.writes, 0);
+        assert!(clipboard.other_content);
+
+        // Only text is written once the files are gone
+        clipboard.text = Some("https://example.org/?utm_source=x".to_string());
+        clipboard.other_content = false;
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer).as_deref(),
+            Some("https://example.org/")
+        );
+        assert_eq!(clipboard.other_content_checks, 2);
+
+        // The URL that was left alone wasn't stripped, so copying it again isn't an override
+        clipboard.text = Some("https://example.com/?utm_source=x".to_string());
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer).as_deref(),
+            Some("https://example.com/")
+        );
+        assert_eq!(clipboard.writes, 2);
+        assert_eq!(clipboard.other_content_checks, 3);
+
+        // Files are only looked for when there's something to write
+        clipboard.text = Some("nothing to strip".to_string());
+        clipboard.html = Some("<b>nothing to strip</b>".to_string());
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(clipboard.other_content_checks, 3);
+        clipboard.html = Some("<a href=\"https://example.com/?utm_source=x\">link</a>".to_string());
+        clipboard.other_content = true;
+        clipboard.text = Some("link".to_string());
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(clipboard.other_content_checks, 4);
+        assert_eq!(clipboard.writes, 2);
+    }
+
+    #[test]
+    fn test_check_clipboard_writes_expanded_links() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let response = if request.starts_with("HEAD /s?") {
+                    format!(
+                        "HTTP/1.1 301 Moved Permanently\r\nLocation: http://localhost:{}/article?utm_source=x&id=1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        port
+                    )
+                } else {
+                    "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        let sanitizer = Sanitizer::from_config(&HashMap::from([
+            ("SHORTENER_ACTION".to_string(), "expand".to_string()),
+            (
+                "EXTRA_SHORTENER_DOMAINS".to_string(),
+                "localhost".to_string(),
+            ),
+            ("EXPAND_TIMEOUT_MS".to_string(), "5000".to_string()),
+        ]));
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let mut clipboard = clipboard::MockBackend {
+            text: Some(format!("see http://localhost:{}/s?utm_source=y", port)),
+            ..Default::default()
+        };
+
+        // The short link's params are stripped without waiting for it to be expanded
+        let stripped = format!("see http://localhost:{}/s", port);
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer),
+            Some(stripped)
+        );
+        let started = Instant::now();
+        while !sanitizer.expansions_finished() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "expansion never finished"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let expanded = format!("see http://localhost:{}/article?id=1", port);
+        assert_eq!(
+            check_expanded(&mut clipboard, &mut monitor, &sanitizer),
+            Some(expanded.clone())
+        );
+        assert_eq!(clipboard.text, Some(expanded));
+        assert_eq!(clipboard.writes, 2);
+        assert_eq!(
+            check_expanded(&mut clipboard, &mut monitor, &sanitizer),
+            None
+        );
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer),
+            None
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_check_clipboard_keeps_html() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let mut clipboard = clipboard::MockBackend {
+            text: Some("https://example.com/?utm_source=x&id=1".to_string()),
+            html: Some(
+                "<a href=\"https://example.com/?utm_source=x&amp;id=1\">Example</a>".to_string(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer).as_deref(),
+            Some("https://example.com/?id=1")
+        );
+        assert_eq!(
+            clipboard.html.as_deref(),
+            Some("<a href=\"https://example.com/?id=1\">Example</a>")
+        );
+
+        // Text only clipboards stay text only
+        clipboard.text = Some("https://example.org/?utm_source=x".to_string());
+        clipboard.html = None;
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(clipboard.text.as_deref(), Some("https://example.org/"));
+        assert_eq!(clipboard.html, None);
+
+        // Links copied as rich text are cleaned in the HTML even if the text has no URL
+        clipboard.text = Some("Example".to_string());
+        clipboard.html =
+            Some("<a href=\"https://example.com/?utm_source=x&amp;id=1\">Example</a>".to_string());
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer),
+            None
+        );
+        assert_eq!(clipboard.text.as_deref(), Some("Example"));
+        assert_eq!(
+            clipboard.html.as_deref(),
+            Some("<a href=\"https://example.com/?id=1\">Example</a>")
+        );
+        let writes = clipboard.writes;
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        clipboard.text = Some("Other".to_string());
+        clipboard.html = Some("<b>Other</b>".to_string());
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(clipboard.writes, writes);
+
+        // A clipboard without text, like an image, or one that can't be read is never written
+        let writes = clipboard.writes;
+        clipboard.text = None;
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        clipboard.text = Some("https://example.net/?utm_source=x".to_string());
+        clipboard.fail_reads = true;
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(clipboard.writes, writes);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_undo_restores_original() {
+        let mut sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let mut notifier = None;
+        let mut clipboard = clipboard::MockBackend {
+            text: Some("https://example.com/?utm_source=x".to_string()),
+            ..Default::default()
+        };
+        let config_file = std::path::Path::new("unused.toml");
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        clipboard.text = Some("https://example.org/?utm_medium=1&id=2".to_string());
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(clipboard.text.as_deref(), Some("https://example.org/?id=2"));
+
+        let mut command = |command| {
+            handle_control_command(
+                command,
+                config_file,
+                &mut clipboard,
+                &mut sanitizer,
+                &mut monitor,
+                &mut notifier,
+            )
+        };
+        let history = command("history");
+        let lines: Vec<&str> = history.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].ends_with(
+            " ago  https://example.org/?utm_medium=1&id=2 -> https://example.org/?id=2"
+        ));
+        assert_eq!(
+            command("undo"),
+            "ok\nhttps://example.org/?utm_medium=1&id=2"
+        );
+        assert_eq!(command("undo"), "ok\nhttps://example.com/?utm_source=x");
+        assert_eq!(command("undo"), "error: nothing to undo");
+
+        // The restored original isn't cleaned again
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer),
+            None
+        );
+        assert_eq!(
+            clipboard.text.as_deref(),
+            Some("https://example.com/?utm_source=x")
+        );
+    }
+
+    #[test]
+    fn test_selections_are_cleaned_separately() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let sanitizer = test_sanitizer();
+        set_settings(HashMap::from([(
+            "OVERRIDE_WINDOW_SECS".to_string(),
+            "30".to_string(),
+        )]));
+        let mut clipboard_monitor = Monitor::from_settings();
+        let mut primary_monitor = Monitor::for_selection(Selection::Primary);
+        let mut clipboard = clipboard::open_selection("mock", Selection::Clipboard).unwrap();
+        let mut primary = clipboard::open_selection("mock", Selection::Primary).unwrap();
+        clipboard
+            .set_text("https://example.com/?utm_source=x".to_string())
+            .unwrap();
+        primary
+            .set_text("https://example.org/?utm_medium=y".to_string())
+            .unwrap();
+
+        check_clipboard(clipboard.as_mut(), &mut clipboard_monitor, &sanitizer);
+        check_clipboard(primary.as_mut(), &mut primary_monitor, &sanitizer);
+        assert_eq!(clipboard.get_text().unwrap(), "https://example.com/");
+        assert_eq!(primary.get_text().unwrap(), "https://example.org/");
+        // Only the clipboard's replacements can be undone
+        assert!(clipboard_monitor.history.last().is_some());
+        assert!(primary_monitor.history.last().is_none());
+
+        set_settings(HashMap::from([(
+            "OVERRIDE_WINDOW_SECS".to_string(),
+            "0".to_string(),
+        )]));
+        primary_monitor.refresh_settings();
+        assert_eq!(primary_monitor.override_window, Duration::ZERO);
+        assert_eq!(clipboard_monitor.override_window, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_failed_reads_are_counted_in_a_row() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let mut clipboard = clipboard::MockBackend {
+            fail_reads: true,
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        }
+        assert_eq!(monitor.failed_reads, 3);
+        assert_eq!(sanitizer.errors().get(ErrorCategory::ClipboardRead), 1);
+        // An empty clipboard was read fine
+        clipboard.fail_reads = false;
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(monitor.failed_reads, 0);
+        clipboard.fail_reads = true;
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(sanitizer.errors().get(ErrorCategory::ClipboardRead), 2);
+
+        assert_eq!(retry_delay(0), Duration::from_secs(1));
+        assert_eq!(retry_delay(3), Duration::from_secs(8));
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_reopen_backs_off() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let mut clipboard = clipboard::MockBackend {
+            fail_reads: true,
+            ..Default::default()
+        };
+        let open = || clipboard::open_backend("mock");
+        let now = Instant::now();
+        for _ in 0..REOPEN_AFTER_FAILED_READS - 1 {
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        }
+        assert!(reopen(open, &mut monitor, now).is_none());
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert!(reopen(open, &mut monitor, now).is_some());
+
+        // Reads still fail after reopening, so the next attempts wait longer and longer
+        for _ in 0..REOPEN_AFTER_FAILED_READS {
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        }
+        assert!(reopen(open, &mut monitor, now + Duration::from_millis(500)).is_none());
+        assert!(reopen(open, &mut monitor, now + Duration::from_secs(1)).is_some());
+        assert!(reopen(open, &mut monitor, now + Duration::from_secs(2)).is_none());
+        let failing = || clipboard::open_backend("missing");
+        assert!(reopen(failing, &mut monitor, now + Duration::from_secs(3)).is_none());
+        assert_eq!(monitor.reopen_at, Some(now + Duration::from_secs(7)));
+        assert_eq!(sanitizer.errors().get(ErrorCategory::ClipboardRead), 1);
+
+        // A read that works starts over
+        clipboard.fail_reads = false;
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(monitor.reopen_attempts, 0);
+        assert!(reopen(open, &mut monitor, now).is_none());
+    }
+
+    #[test]
+    fn test_check_clipboard_skips_own_writes() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let mut clipboard = clipboard::MockBackend {
+            text: Some("https://example.com/?utm_source=foo".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer).as_deref(),
+            Some("https://example.com/")
+        );
+        // Reading back what was just written isn't parsed or stripped again
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer),
+            None
+        );
+        assert_eq!(monitor.processed, 1);
+
+        // After an unrelated copy resets the guard, pasting the stripped URL back in is a new copy
+        clipboard.text = Some("unrelated".to_string());
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        clipboard.text = Some("https://example.com/".to_string());
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer),
+            None
+        );
+        assert_eq!(monitor.processed, 3);
+        clipboard.text = Some("https://example.com/?utm_source=bar".to_string());
+        assert_eq!(
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer).as_deref(),
+            Some("https://example.com/")
+        );
+    }
+
+    #[test]
+    fn test_learn_exceptions_from_repeated_overrides() {
+        let learned = std::env::temp_dir().join(format!(
+            "clipboard-sanitizer-learned-{}.txt",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&learned);
+        let sanitizer = Sanitizer::from_config(&Settings::from([
+            ("LEARN_EXCEPTIONS".to_string(), "true".to_string()),
+            (
+                "LEARNED_EXCEPTIONS_FILE".to_string(),
+                learned.to_string_lossy().to_string(),
+            ),
+        ]));
+        let mut monitor = Monitor::new(Duration::from_secs(30), Duration::ZERO);
+        let now = Instant::now();
+        let tracked = "https://example.com/?utm_source=newsletter&id=1".to_string();
+
+        for _ in 0..2 {
+            let sanitized = monitor
+                .check(tracked.clone(), CopySource::Unknown, &sanitizer, now)
+                .unwrap();
+            assert_eq!(sanitized, "https://example.com/?id=1");
+            monitor.wrote(sanitized.clone());
+            monitor.stripped(tracked.clone(), sanitized, now);
+            // The user restores the original URL
+            assert!(monitor
+                .check(tracked.clone(), CopySource::Unknown, &sanitizer, now)
+                .is_none());
+            monitor.check("other".to_string(), CopySource::Unknown, &sanitizer, now);
+        }
+
+        // utm_source is now kept on example.com, but still stripped elsewhere
+        assert!(monitor
+            .check(tracked, CopySource::Unknown, &sanitizer, now)
+            .is_none());
+        let url = Url::parse("https://example.org/?utm_source=newsletter").unwrap();
+        assert_eq!(sanitizer.sanitize(&url).as_str(), "https://example.org/");
+        let _ = std::fs::remove_file(&learned);
+    }
+
+    #[test]
+    fn test_poll_interval_backs_off_when_idle() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let start = Instant::now();
+        assert_eq!(monitor.poll_interval(start), Duration::from_millis(50));
+
+        monitor.check("a".to_string(), CopySource::Unknown, &sanitizer, start);
+        assert_eq!(monitor.poll_interval(start), Duration::from_millis(50));
+
+        // Reading the same content again isn't a change, so the interval keeps growing
+        let idle = start + Duration::from_secs(4);
+        monitor.check("a".to_string(), CopySource::Unknown, &sanitizer, idle);
+        assert_eq!(monitor.poll_interval(idle), Duration::from_millis(200));
+        let long_idle = start + Duration::from_secs(3600);
+        assert_eq!(monitor.poll_interval(long_idle), Duration::from_millis(500));
+
+        monitor.check("b".to_string(), CopySource::Unknown, &sanitizer, long_idle);
+        assert_eq!(monitor.poll_interval(long_idle), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_poll_interval_options() {
+        let args =
+            Args::try_parse_from(["clipboard-sanitizer", "--poll-interval-ms", "200"]).unwrap();
+        assert_eq!(
+            cli_settings(&args),
+            Settings::from([("POLL_INTERVAL_MS".to_string(), "200".to_string())])
+        );
+        let args = Args::try_parse_from(["clipboard-sanitizer"]).unwrap();
+        assert!(cli_settings(&args).is_empty());
+    }
+
+    #[test]
+    fn test_unchanged_sequence_number_skips_reads() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        let mut clipboard = clipboard::MockBackend {
+            text: Some("https://example.com/?utm_source=foo".to_string()),
+            sequence: Some(1),
+            ..Default::default()
+        };
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(clipboard.text.as_deref(), Some("https://example.com/"));
+
+        // The mock doesn't bump the sequence number on writes, so this content is never read
+        clipboard.text = Some("https://example.com/?utm_source=bar".to_string());
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(
+            clipboard.text.as_deref(),
+            Some("https://example.com/?utm_source=bar")
+        );
+
+        clipboard.sequence = Some(2);
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(clipboard.text.as_deref(), Some("https://example.com/"));
+    }
+
+    #[test]
+    fn test_only_user_copies() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::ZERO, Duration::ZERO);
+        monitor.only_user_copies = true;
+        let mut clipboard = clipboard::MockBackend::default();
+        let tracked = "https://example.com/?utm_source=foo".to_string();
+
+        clipboard.text = Some(tracked.clone());
+        clipboard.source = CopySource::Program;
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(clipboard.text.as_ref(), Some(&tracked));
+
+        // Focusing the program afterwards doesn't make its content look like a user copy
+        clipboard.source = CopySource::User;
+        check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+        assert_eq!(clipboard.text.as_ref(), Some(&tracked));
+
+        // Platforms that can't tell are treated like user copies
+        for source in [CopySource::User, CopySource::Unknown] {
+            clipboard.text = Some(format!("{}&{:?}", tracked, source));
+            clipboard.source = source;
+            check_clipboard(&mut clipboard, &mut monitor, &sanitizer);
+            assert_eq!(
+                clipboard.text,
+                Some(format!("https://example.com/?{:?}", source))
+            );
+        }
+    }
+
+    #[test]
+    fn test_monitor_respects_copying_stripped_url_again() {
+        let sanitizer = test_sanitizer();
+        let mut monitor = Monitor::new(Duration::from_secs(30), Duration::from_secs(300));
+        let start = Instant::now();
+
+        let tracked = "https://example.com/?utm_source=foo".to_string();
+        let sanitized = monitor
+            .check(tracked.clone(), CopySource::Unknown, &sanitizer, start)
+            .unwrap();
+        monitor.wrote(sanitized.clone());
+        monitor.stripped(tracked.clone(), sanitized.clone(), start);
+
+        // The user copies the tracked URL again shortly after, so it's left alone for the cooldown
+        let override_at = start + Duration::from_secs(10);
+        assert!(monitor
+            .check(
+                tracked.clone(),
+                CopySource::Unknown,
+                &sanitizer,
+                override_at
+            )
+            .is_none());
+        assert!(monitor
+            .check(
+                "other".to_string(),
+                CopySource::Unknown,
+                &sanitizer,
+                override_at
+            )
+            .is_none());
+        let still_trusted = override_at + Duration::from_secs(299);
+        assert!(monitor
+            .check(
+                tracked.clone(),
+                CopySource::Unknown,
+                &sanitizer,
+                still_trusted
+            )
+            .is_none());
+
+        // After the cooldown it's stripped again
+        assert!(monitor
+            .check(
+                "other".to_string(),
+                CopySource::Unknown,
+                &sanitizer,
+                still_trusted
+            )
+            .is_none());
+        let expired = override_at + Duration::from_secs(300);
+        assert_eq!(
+            monitor.check(tracked.clone(), CopySource::Unknown, &sanitizer, expired),
+            Some(sanitized.clone())
+        );
+
+        // Re-copying only counts within the window
+        monitor.wrote(sanitized.clone());
+        monitor.stripped(tracked.clone(), sanitized.clone(), expired);
+        let too_late = expired + Duration::from_secs(30);
+        assert!(monitor
+            .check(tracked.clone(), CopySource::Unknown, &sanitizer, too_late)
+            .is_some());
+
+        // Adding a param back to the stripped URL by hand is an override too
+        monitor.wrote(sanitized.clone());
+        monitor.stripped(tracked, sanitized, too_late);
+        let edited = "https://example.com/?utm_source=bar".to_string();
+        let edited_at = too_late + Duration::from_secs(5);
+        assert!(monitor
+            .check(edited.clone(), CopySource::Unknown, &sanitizer, edited_at)
+            .is_none());
+        assert!(monitor
+            .check(
+                "other".to_string(),
+                CopySource::Unknown,
+                &sanitizer,
+                edited_at
+            )
+            .is_none());
+        assert!(monitor
+            .check(edited, CopySource::Unknown, &sanitizer, edited_at)
+            .is_none());
     }
 }
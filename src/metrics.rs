@@ -0,0 +1,186 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::params::{ParamCategory, TrackingScore};
+
+/// ErrorCategory groups processing errors for the error counters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCategory {
+    ClipboardRead,
+    ClipboardWrite,
+    /// Content we tried to process couldn't be parsed or serialized
+    Parse,
+    NetworkTimeout,
+    /// A rule rewrote a URL into one that would be rewritten again too many times
+    RecursionLimit,
+}
+
+impl ErrorCategory {
+    pub const ALL: [ErrorCategory; 5] = [
+        ErrorCategory::ClipboardRead,
+        ErrorCategory::ClipboardWrite,
+        ErrorCategory::Parse,
+        ErrorCategory::NetworkTimeout,
+        ErrorCategory::RecursionLimit,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorCategory::ClipboardRead => "clipboard_read",
+            ErrorCategory::ClipboardWrite => "clipboard_write",
+            ErrorCategory::Parse => "parse",
+            ErrorCategory::NetworkTimeout => "network_timeout",
+            ErrorCategory::RecursionLimit => "recursion_limit",
+        }
+    }
+}
+
+/// ErrorMetrics counts errors by category. It can be shared between threads.
+#[derive(Default)]
+pub struct ErrorMetrics {
+    counts: [AtomicU64; ErrorCategory::ALL.len()],
+}
+
+impl ErrorMetrics {
+    pub fn increment(&self, category: ErrorCategory) {
+        self.counts[category as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, category: ErrorCategory) -> u64 {
+        self.counts[category as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        ErrorCategory::ALL
+            .iter()
+            .map(|category| self.get(*category))
+            .sum()
+    }
+}
+
+/// Formats the counters as `name count` lines, which is also the format of the metrics file
+impl fmt::Display for ErrorMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for category in ErrorCategory::ALL {
+            writeln!(f, "errors_{} {}", category.name(), self.get(category))?;
+        }
+        Ok(())
+    }
+}
+
+/// TrackingStats adds up the params stripped from URLs by category, and the tracking score of each domain
+#[derive(Default)]
+pub struct TrackingStats {
+    stripped: [AtomicU64; ParamCategory::ALL.len()],
+    domains: Mutex<BTreeMap<String, u64>>,
+}
+
+impl TrackingStats {
+    pub fn record(&self, domain: &str, score: &TrackingScore) {
+        for category in ParamCategory::ALL {
+            self.stripped[category as usize]
+                .fetch_add(score.counts[category as usize], Ordering::Relaxed);
+        }
+        *self
+            .domains
+            .lock()
+            .unwrap()
+            .entry(domain.to_string())
+            .or_default() += score.score();
+    }
+
+    pub fn stripped(&self, category: ParamCategory) -> u64 {
+        self.stripped[category as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        ParamCategory::ALL
+            .iter()
+            .map(|category| self.stripped(*category))
+            .sum()
+    }
+
+    /// categories returns the name of every param category with how many of its params were stripped
+    pub fn categories(&self) -> Vec<(&'static str, u64)> {
+        ParamCategory::ALL
+            .iter()
+            .map(|category| (category.name(), self.stripped(*category)))
+            .collect()
+    }
+
+    /// scores returns the tracking score of every domain, from highest to lowest
+    pub fn scores(&self) -> Vec<(String, u64)> {
+        let mut scores: Vec<(String, u64)> = self
+            .domains
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(domain, score)| (domain.clone(), *score))
+            .collect();
+        scores.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+        scores
+    }
+}
+
+/// Formats the counters as `name count` lines like ErrorMetrics, with the domains' scores from highest to lowest
+impl fmt::Display for TrackingStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for category in ParamCategory::ALL {
+            writeln!(
+                f,
+                "stripped_{} {}",
+                category.name(),
+                self.stripped(category)
+            )?;
+        }
+        for (domain, score) in self.scores() {
+            writeln!(f, "score_{} {}", domain, score)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_metrics() {
+        let metrics = ErrorMetrics::default();
+        metrics.increment(ErrorCategory::Parse);
+        metrics.increment(ErrorCategory::Parse);
+        metrics.increment(ErrorCategory::RecursionLimit);
+
+        assert_eq!(metrics.get(ErrorCategory::Parse), 2);
+        assert_eq!(metrics.get(ErrorCategory::ClipboardRead), 0);
+        assert_eq!(metrics.total(), 3);
+        assert_eq!(
+            metrics.to_string(),
+            "errors_clipboard_read 0\n\
+             errors_clipboard_write 0\n\
+             errors_parse 2\n\
+             errors_network_timeout 0\n\
+             errors_recursion_limit 1\n"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+/// Notifier shows a desktop notification when a URL is cleaned. Notifications are throttled so
+/// cleaning many URLs in a short time shows one summary instead of a notification for each.
+pub struct Notifier {
+    throttle: Duration,
+    window_start: Option<Instant>,
+    pending: Vec<String>,
+    /// dry_run words the notifications as what would have been cleaned
+    dry_run: bool,
+}
+
+impl Notifier {
+    pub fn new(throttle: Duration, dry_run: bool) -> Notifier {
+        Notifier {
+            throttle,
+            window_start: None,
+            pending: vec![],
+            dry_run,
+        }
+    }
+
+    fn single(&self, url: &str) -> String {
+        if self.dry_run {
+            return format!("Would strip tracking from {}", url);
+        }
+        format!("Stripped tracking from {}", url)
+    }
+
+    fn summary(&self, cleaned: usize) -> String {
+        if self.dry_run {
+            return format!("Would clean {} URLs", cleaned);
+        }
+        format!("Cleaned {} URLs", cleaned)
+    }
+
+    /// cleaned returns the message to show for the cleaned URL, or None if it's held back for a summary
+    pub fn cleaned(&mut self, url: &str, now: Instant) -> Option<String> {
+        match self.window_start {
+            Some(start) if now.duration_since(start) < self.throttle => {
+                self.pending.push(url.to_string());
+                None
+            }
+            _ => {
+                // URLs still pending from the previous window are included rather than dropped
+                self.window_start = Some(now);
+                let cleaned = self.pending.len() + 1;
+                self.pending.clear();
+                if cleaned > 1 {
+                    return Some(self.summary(cleaned));
+                }
+                Some(self.single(url))
+            }
+        }
+    }
+
+    /// flush returns the summary of the URLs cleaned during the throttle window once it's over
+    pub fn flush(&mut self, now: Instant) -> Option<String> {
+        let start = self.window_start?;
+        if now.duration_since(start) < self.throttle {
+            return None;
+        }
+        self.window_start = None;
+        match self.pending.len() {
+            0 => None,
+            1 => {
+                let url = self.pending.remove(0);
+                Some(self.single(&url))
+            }
+            cleaned => {
+                self.pending.clear();
+                Some(self.summary(cleaned))
+            }
+        }
+    }
+}
+
+/// show displays the message as a desktop notification
+pub fn show(message: &str) {
+    if let Err(e) = notify_rust::Notification::new()
+        .summary("clipboard-sanitizer")
+        .body(message)
+        .show()
+    {
+        debug!("Failed to show notification: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notifications_are_coalesced() {
+        let mut notifier = Notifier::new(Duration::from_secs(2), false);
+        let start = Instant::now();
+        assert_eq!(
+            notifier.cleaned("https://example.com/", start),
+            Some("Stripped tracking from https://example.com/".to_string())
+        );
+        for i in 1..=5 {
+            let at = start + Duration::from_millis(i * 100);
+            assert_eq!(notifier.cleaned("https://example.org/", at), None);
+            assert_eq!(notifier.flush(at), None);
+        }
+
+        let after = start + Duration::from_secs(2);
+        assert_eq!(notifier.flush(after), Some("Cleaned 5 URLs".to_string()));
+        assert_eq!(notifier.flush(after), None);
+
+        // A URL cleaned right as the window ends includes the held back ones
+        assert!(notifier.cleaned("https://example.org/", after).is_some());
+        assert!(notifier
+            .cleaned("https://example.org/", after + Duration::from_secs(1))
+            .is_none());
+        let before_flush = after + Duration::from_secs(2);
+        assert_eq!(
+            notifier.cleaned("https://example.org/", before_flush),
+            Some("Cleaned 2 URLs".to_string())
+        );
+        assert_eq!(notifier.flush(before_flush + Duration::from_secs(2)), None);
+
+        // The next URL after a quiet period is shown right away
+        let later = after + Duration::from_secs(10);
+        assert!(notifier.cleaned("https://example.net/", later).is_some());
+        assert_eq!(notifier.flush(later + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn test_dry_run_notifications() {
+        let mut notifier = Notifier::new(Duration::from_secs(2), true);
+        let start = Instant::now();
+        assert_eq!(
+            notifier.cleaned("https://example.com/", start),
+            Some("Would strip tracking from https://example.com/".to_string())
+        );
+        notifier.cleaned("https://example.org/", start);
+        notifier.cleaned("https://example.net/", start);
+        assert_eq!(
+            notifier.flush(start + Duration::from_secs(2)),
+            Some("Would clean 2 URLs".to_string())
+        );
+    }
+}
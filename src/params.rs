@@ -0,0 +1,521 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashSet;
+
+use log::error;
+
+const YOUTUBE_TRACKING_PARAMS: [&str; 2] = ["si", "feature"];
+/// s and t are share tokens, ref_src and ref_url are added to links in embedded tweets
+const TWITTER_TRACKING_PARAMS: [&str; 4] = ["s", "t", "ref_src", "ref_url"];
+/// fbclid is added to every outgoing link, mibextid, rdid and sfnsn to shared links, __tn__ to links in the feed
+const FACEBOOK_TRACKING_PARAMS: [&str; 5] = ["fbclid", "mibextid", "rdid", "sfnsn", "__tn__"];
+/// Links in the feed also get __cft__[0], __cft__[1] and so on, one for each level of the post
+const FACEBOOK_TRACKING_PREFIX: &str = "__cft__";
+/// igsh and igshid tie shared links to the account that shared them
+const INSTAGRAM_TRACKING_PARAMS: [&str; 2] = ["igsh", "igshid"];
+/// Google Search adds these to result links and search URLs. They record the click, the session and how the
+/// search was typed, while q and the result filters like tbm and tbs are kept.
+const GOOGLE_TRACKING_PARAMS: [&str; 23] = [
+    "ved", "usg", "ei", "sa", "sca_esv", "sca_upv", "sxsrf", "oq", "aqs", "gs_lcp", "gs_lcrp",
+    "gs_lp", "gs_ssp", "sourceid", "source", "client", "rlz", "uact", "bih", "biw", "dpr",
+    "iflsig", "sclient",
+];
+const COMMON_TRACKING_PARAMS: [&str; 5] = [
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+];
+/// Params added to newsletter links by marketing automation platforms, stripped on every domain
+const MARKETING_AUTOMATION_PARAMS: [&str; 10] = [
+    // Klaviyo
+    "_kx",
+    // HubSpot
+    "_hsenc",
+    "_hsmi",
+    "__hssc",
+    "__hstc",
+    "__hsfp",
+    "hsCtaTracking",
+    // Salesforce Marketing Cloud
+    "sfmc_id",
+    "sfmc_activityid",
+    // Marketo
+    "mkt_tok",
+];
+/// Amazon's own tracking of how a product page was reached
+const AMAZON_TRACKING_PARAMS: [&str; 19] = [
+    "_encoding",
+    "content-id",
+    "crid",
+    "dib",
+    "dib_tag",
+    "pd_rd_i",
+    "pd_rd_r",
+    "pd_rd_w",
+    "pd_rd_wg",
+    "pf_rd_i",
+    "pf_rd_m",
+    "pf_rd_p",
+    "pf_rd_r",
+    "pf_rd_s",
+    "pf_rd_t",
+    "qid",
+    "ref_",
+    "sprefix",
+    "sr",
+];
+/// Referral and affiliate codes that credit whoever shared the link, stripped on every domain if enabled
+const REFERRAL_PARAMS: [&str; 2] = ["ref", "referral"];
+/// Amazon Associates affiliate params
+const AMAZON_REFERRAL_PARAMS: [&str; 7] = [
+    "tag",
+    "ascsubtag",
+    "linkCode",
+    "linkId",
+    "camp",
+    "creative",
+    "creativeASIN",
+];
+/// The only params stripped from calendar and webinar links besides utm_ ones, since everything else
+/// might be needed to open the event
+const CONSERVATIVE_TRACKING_PARAMS: [&str; 1] = ["gclid"];
+/// Google Calendar event, invite and template params, which are never stripped
+pub const CALENDAR_PROTECTED_PARAMS: [&str; 15] = [
+    "eid", "cid", "ctz", "authuser", "pli", "sf", "output", "src", "tmeid", "tmsrc", "action",
+    "text", "dates", "details", "location",
+];
+/// Zoom meeting passcodes and webinar registration tokens, which are never stripped
+pub const ZOOM_PROTECTED_PARAMS: [&str; 5] = ["pwd", "tk", "uname", "zak", "from"];
+/// Any param starting with these is stripped by the common rule, since new utm_ params keep appearing.
+/// pk_ is used by Matomo.
+const COMMON_TRACKING_PREFIXES: [&str; 2] = ["utm_", "pk_"];
+
+/// ParamCategory is what a param is used for, to score how much tracking a URL had
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamCategory {
+    /// Analytics of where a visitor came from, like the utm_ params, and any params added in the config
+    Tracking,
+    /// Marketing automation params, which identify the recipient of a newsletter
+    Marketing,
+    Referral,
+}
+
+impl ParamCategory {
+    pub const ALL: [ParamCategory; 3] = [
+        ParamCategory::Tracking,
+        ParamCategory::Marketing,
+        ParamCategory::Referral,
+    ];
+
+    pub fn of(param: &str) -> ParamCategory {
+        if MARKETING_AUTOMATION_PARAMS.contains(&param) {
+            ParamCategory::Marketing
+        } else if REFERRAL_PARAMS.contains(&param) || AMAZON_REFERRAL_PARAMS.contains(&param) {
+            ParamCategory::Referral
+        } else {
+            ParamCategory::Tracking
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParamCategory::Tracking => "tracking",
+            ParamCategory::Marketing => "marketing",
+            ParamCategory::Referral => "referral",
+        }
+    }
+
+    /// weight is how much a stripped param adds to the score. Marketing params count double because
+    /// they tie the visit to a person rather than a campaign.
+    pub fn weight(&self) -> u64 {
+        match self {
+            ParamCategory::Marketing => 2,
+            ParamCategory::Tracking | ParamCategory::Referral => 1,
+        }
+    }
+}
+
+/// TrackingScore counts the params stripped from a URL by category
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackingScore {
+    pub counts: [u64; ParamCategory::ALL.len()],
+}
+
+impl TrackingScore {
+    pub fn add(&mut self, param: &str) {
+        self.counts[ParamCategory::of(param) as usize] += 1;
+    }
+
+    pub fn score(&self) -> u64 {
+        ParamCategory::ALL
+            .iter()
+            .map(|category| self.counts[*category as usize] * category.weight())
+            .sum()
+    }
+}
+
+/// Formats the score with the counts it's made of, like `4 (2 tracking, 1 marketing)`
+impl std::fmt::Display for TrackingScore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let counts: Vec<String> = ParamCategory::ALL
+            .iter()
+            .filter(|category| self.counts[**category as usize] > 0)
+            .map(|category| format!("{} {}", self.counts[*category as usize], category.name()))
+            .collect();
+        write!(f, "{} ({})", self.score(), counts.join(", "))
+    }
+}
+
+/// ParamPattern is a regex that param names are matched against, compiled once when the config is loaded
+#[derive(Debug, Clone)]
+pub struct ParamPattern(regex::Regex);
+
+impl PartialEq for ParamPattern {
+    fn eq(&self, other: &ParamPattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// TrackingParams is a set of query param names that a rule strips, and prefixes and patterns of param names
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackingParams {
+    params: HashSet<String>,
+    /// removed are never matched, also if a prefix or pattern matches them, like -utm_term with the utm_ prefix
+    removed: HashSet<String>,
+    /// lowercase_params and lowercase_removed hold the names lowercased, to look them up when ignore_case is set
+    lowercase_params: HashSet<String>,
+    lowercase_removed: HashSet<String>,
+    prefixes: Vec<String>,
+    patterns: Vec<ParamPattern>,
+    /// ignore_case matches param names regardless of their case, so UTM_Source is stripped like utm_source
+    ignore_case: bool,
+}
+
+impl TrackingParams {
+    pub fn from_defaults(defaults: &[&str]) -> TrackingParams {
+        let mut params = TrackingParams::default();
+        for param in defaults {
+            params.add(param);
+        }
+        params
+    }
+
+    /// merge applies a configured list to the set. Params are added, or removed if they start with `-`.
+    pub fn merge(&mut self, config: &[String]) {
+        for param in config {
+            match param.strip_prefix('-') {
+                Some(param) => self.remove(param),
+                None => self.add(param),
+            }
+        }
+    }
+
+    pub fn add(&mut self, param: &str) {
+        self.removed.remove(param);
+        self.lowercase_removed.remove(&param.to_ascii_lowercase());
+        self.params.insert(param.to_string());
+        self.lowercase_params.insert(param.to_ascii_lowercase());
+    }
+
+    /// extend adds the params, prefixes and patterns of other
+    pub fn extend(&mut self, other: &TrackingParams) {
+        self.params.extend(other.params.iter().cloned());
+        self.removed.extend(other.removed.iter().cloned());
+        self.lowercase_params
+            .extend(other.lowercase_params.iter().cloned());
+        self.lowercase_removed
+            .extend(other.lowercase_removed.iter().cloned());
+        self.prefixes.extend(other.prefixes.iter().cloned());
+        self.patterns.extend(other.patterns.iter().cloned());
+    }
+
+    /// remove stops matching the param, also by the prefixes and patterns
+    pub fn remove(&mut self, param: &str) {
+        self.params.remove(param);
+        self.lowercase_params.remove(&param.to_ascii_lowercase());
+        self.removed.insert(param.to_string());
+        self.lowercase_removed.insert(param.to_ascii_lowercase());
+    }
+
+    /// set_prefixes replaces the prefixes. Empty prefixes are ignored because they'd match every param.
+    pub fn set_prefixes(&mut self, prefixes: &[String]) {
+        self.prefixes = prefixes
+            .iter()
+            .filter(|prefix| !prefix.is_empty())
+            .cloned()
+            .collect();
+    }
+
+    pub fn add_prefix(&mut self, prefix: &str) {
+        if !prefix.is_empty() {
+            self.prefixes.push(prefix.to_string());
+        }
+    }
+
+    /// add_patterns adds regexes that match whole groups of params. Entries that are just a param name are added
+    /// as that param, so they don't also match longer names containing them. Invalid regexes are logged and skipped.
+    pub fn add_patterns(&mut self, patterns: &[String]) {
+        for pattern in patterns {
+            if pattern
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                self.add(pattern);
+                continue;
+            }
+            match regex::Regex::new(pattern) {
+                Ok(regex) => self.patterns.push(ParamPattern(regex)),
+                Err(e) => error!("Invalid param pattern {:?}, ignoring it: {}", pattern, e),
+            }
+        }
+    }
+
+    /// add_regex strips params matching the regex, which isn't checked for being a plain name like in add_patterns
+    pub fn add_regex(&mut self, regex: regex::Regex) {
+        self.patterns.push(ParamPattern(regex));
+    }
+
+    /// set_ignore_case makes the set match param names regardless of their case
+    pub fn set_ignore_case(&mut self, ignore_case: bool) {
+        self.ignore_case = ignore_case;
+    }
+
+    pub fn contains(&self, param: &str) -> bool {
+        let lowercase = self.ignore_case.then(|| param.to_ascii_lowercase());
+        if self.removed.contains(param)
+            || lowercase
+                .as_ref()
+                .is_some_and(|lowercase| self.lowercase_removed.contains(lowercase))
+        {
+            return false;
+        }
+        if !self.ignore_case {
+            return self.params.contains(param)
+                || self
+                    .prefixes
+                    .iter()
+                    .any(|prefix| param.starts_with(prefix.as_str()))
+                || self
+                    .patterns
+                    .iter()
+                    .any(|pattern| pattern.0.is_match(param));
+        }
+        // Patterns are usually written for lowercase names, and can use (?i) to match any case themselves
+        let lowercase = lowercase.unwrap_or_default();
+        self.params.contains(param)
+            || self.lowercase_params.contains(&lowercase)
+            || self.prefixes.iter().any(|prefix| {
+                param
+                    .get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+            })
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.0.is_match(param) || pattern.0.is_match(&lowercase))
+    }
+
+    /// strips returns true if the param is removed: it's in the set and not one of the kept params, which are
+    /// compared with the same case sensitivity
+    pub fn strips(&self, param: &str, keep: &[String]) -> bool {
+        self.contains(param) && !self.is_kept(param, keep)
+    }
+
+    /// is_kept returns true if the param is one of the kept params
+    pub fn is_kept(&self, param: &str, keep: &[String]) -> bool {
+        keep.iter()
+            .any(|kept| kept == param || (self.ignore_case && kept.eq_ignore_ascii_case(param)))
+    }
+}
+
+/// RuleParams holds the params stripped by each of the built-in rules
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleParams {
+    pub youtube: TrackingParams,
+    pub twitter: TrackingParams,
+    pub facebook: TrackingParams,
+    pub instagram: TrackingParams,
+    pub google: TrackingParams,
+    pub amazon: TrackingParams,
+    /// conservative only strips the clearly-tracking params, for links that break if anything else is stripped
+    pub conservative: TrackingParams,
+    pub common: TrackingParams,
+}
+
+impl RuleParams {
+    /// new builds the rules' params from the tracking params, the referral params or both
+    pub fn new(tracking: bool, referral: bool) -> RuleParams {
+        let rule = |tracking_params: &[&str], referral_params: &[&str]| {
+            let mut params = TrackingParams::default();
+            if tracking {
+                params = TrackingParams::from_defaults(tracking_params);
+                for param in MARKETING_AUTOMATION_PARAMS {
+                    params.add(param);
+                }
+            }
+            if referral {
+                for param in referral_params.iter().chain(&REFERRAL_PARAMS) {
+                    params.add(param);
+                }
+            }
+            params
+        };
+        let youtube = rule(&YOUTUBE_TRACKING_PARAMS, &[]);
+        let mut common = rule(&COMMON_TRACKING_PARAMS, &[]);
+        if tracking {
+            common.prefixes = COMMON_TRACKING_PREFIXES.map(String::from).to_vec();
+        }
+        let mut facebook = rule(&FACEBOOK_TRACKING_PARAMS, &[]);
+        facebook.add_prefix(FACEBOOK_TRACKING_PREFIX);
+        let mut conservative = TrackingParams::default();
+        if tracking {
+            conservative = TrackingParams::from_defaults(&CONSERVATIVE_TRACKING_PARAMS);
+            conservative.add_prefix("utm_");
+        }
+        RuleParams {
+            youtube,
+            twitter: rule(&TWITTER_TRACKING_PARAMS, &[]),
+            facebook,
+            instagram: rule(&INSTAGRAM_TRACKING_PARAMS, &[]),
+            google: rule(&GOOGLE_TRACKING_PARAMS, &[]),
+            amazon: rule(&AMAZON_TRACKING_PARAMS, &AMAZON_REFERRAL_PARAMS),
+            conservative,
+            common,
+        }
+    }
+
+    /// set_ignore_case makes every rule match param names regardless of their case
+    pub fn set_ignore_case(&mut self, ignore_case: bool) {
+        for params in [
+            &mut self.youtube,
+            &mut self.twitter,
+            &mut self.facebook,
+            &mut self.instagram,
+            &mut self.google,
+            &mut self.amazon,
+            &mut self.conservative,
+            &mut self.common,
+        ] {
+            params.set_ignore_case(ignore_case);
+        }
+    }
+
+    /// set_common_params replaces the built-in utm params and the utm_ and pk_ prefixes of the common rule, keeping
+    /// its marketing automation params
+    pub fn set_common_params(&mut self, params: &[String]) {
+        for param in COMMON_TRACKING_PARAMS {
+            self.common.params.remove(param);
+            self.common.lowercase_params.remove(param);
+        }
+        self.common
+            .prefixes
+            .retain(|prefix| !COMMON_TRACKING_PREFIXES.contains(&prefix.as_str()));
+        self.common.merge(params);
+    }
+}
+
+impl Default for RuleParams {
+    fn default() -> RuleParams {
+        RuleParams::new(true, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracking_score() {
+        let mut score = TrackingScore::default();
+        assert_eq!(score.score(), 0);
+        for param in ["utm_source", "utm_medium", "_hsenc", "tag"] {
+            score.add(param);
+        }
+        assert_eq!(score.counts, [2, 1, 1]);
+        assert_eq!(score.score(), 5);
+        assert_eq!(score.to_string(), "5 (2 tracking, 1 marketing, 1 referral)");
+    }
+
+    #[test]
+    fn test_merge_tracking_params() {
+        let mut params = TrackingParams::from_defaults(&COMMON_TRACKING_PARAMS);
+        params.merge(&["gclid".to_string(), "-utm_term".to_string()]);
+        assert!(params.contains("gclid"));
+        assert!(params.contains("utm_source"));
+        assert!(!params.contains("utm_term"));
+        assert!(!params.contains("-utm_term"));
+
+        // Removed params also beat the prefixes and patterns of the common rule
+        let mut params = RuleParams::default().common;
+        params.add_patterns(&["^utm_.*".to_string()]);
+        params.set_ignore_case(true);
+        params.merge(&["-utm_term".to_string()]);
+        assert!(!params.contains("utm_term"));
+        assert!(!params.contains("UTM_Term"));
+        assert!(params.contains("utm_source"));
+        params.merge(&["utm_term".to_string()]);
+        assert!(params.contains("utm_term"));
+        params.merge(&["Campaign_ID".to_string()]);
+        assert!(params.contains("campaign_id"));
+        assert!(params.contains("CAMPAIGN_ID"));
+    }
+
+    #[test]
+    fn test_param_patterns() {
+        let mut params = TrackingParams::default();
+        params.add_patterns(&[
+            "^utm_.*".to_string(),
+            "_source$".to_string(),
+            "fbclid".to_string(),
+            "([".to_string(),
+        ]);
+        for param in [
+            "utm_source",
+            "utm_weird_custom",
+            "newsletter_source",
+            "fbclid",
+        ] {
+            assert!(params.contains(param), "{}", param);
+        }
+        for param in ["source_code", "xfbclid", "(["] {
+            assert!(!params.contains(param), "{}", param);
+        }
+    }
+
+    #[test]
+    fn test_tracking_prefixes() {
+        let mut params = RuleParams::default().common;
+        assert!(params.contains("utm_something_new"));
+        assert!(params.contains("pk_campaign"));
+        assert!(!params.contains("utm"));
+        assert!(!params.contains("page"));
+
+        params.set_prefixes(&[String::new()]);
+        assert!(!params.contains("utm_something_new"));
+        assert!(params.contains("utm_source"));
+        let mut rules = RuleParams::default();
+        rules.set_common_params(&["gclid".to_string()]);
+        assert!(rules.common.contains("gclid"));
+        assert!(!rules.common.contains("utm_source"));
+        assert!(!rules.common.contains("utm_something_new"));
+        assert!(!rules.common.contains("pk_campaign"));
+    }
+}
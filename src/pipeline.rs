@@ -0,0 +1,190 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! The stages a URL goes through after it's been checked against ONLY_DOMAINS and SKIP_DOMAINS, in the order
+//! set by PIPELINE. Library users can add their own stages with [`crate::Sanitizer::with_transform`].
+
+use log::warn;
+use url::Url;
+
+/// Transform is a custom stage of the pipeline. It runs where PIPELINE lists its name, or after the built-in
+/// stages if PIPELINE doesn't.
+pub trait Transform: Send + Sync {
+    /// name is how PIPELINE and --explain refer to the stage
+    fn name(&self) -> &str;
+
+    /// apply returns the transformed URL, or None to pass the URL on to the next stage unchanged
+    fn apply(&self, url: &Url) -> Option<Url>;
+}
+
+/// Step is a stage of the pipeline. The stages that rewrite a URL into another one, like host_rewrite and a
+/// successful shortener expansion, run the whole pipeline again on the new URL.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Step {
+    /// clearurls_redirect replaces ClearURLs redirect wrappers with their destination
+    ClearUrlsRedirect,
+    HostRewrite,
+    Deamp,
+    Shortener,
+    /// params strips the params of the RULES and DOMAINS tables or the built-in rules
+    Params,
+    ClearUrls,
+    NestedUrls,
+    /// fragment strips scroll-to-text directives and tracking params from the fragment
+    Fragment,
+    Custom(String),
+}
+
+const BUILT_IN_STEPS: [Step; 8] = [
+    Step::ClearUrlsRedirect,
+    Step::HostRewrite,
+    Step::Deamp,
+    Step::Shortener,
+    Step::Params,
+    Step::ClearUrls,
+    Step::NestedUrls,
+    Step::Fragment,
+];
+
+impl Step {
+    fn name(&self) -> &str {
+        match self {
+            Step::ClearUrlsRedirect => "clearurls_redirect",
+            Step::HostRewrite => "host_rewrite",
+            Step::Deamp => "deamp",
+            Step::Shortener => "shortener",
+            Step::Params => "params",
+            Step::ClearUrls => "clearurls",
+            Step::NestedUrls => "nested_urls",
+            Step::Fragment => "fragment",
+            Step::Custom(name) => name,
+        }
+    }
+
+    fn from_name(name: &str) -> Step {
+        BUILT_IN_STEPS
+            .iter()
+            .find(|step| step.name().eq_ignore_ascii_case(name))
+            .cloned()
+            .unwrap_or_else(|| Step::Custom(name.to_string()))
+    }
+}
+
+/// Pipeline is the ordered list of stages and the custom transforms added to it
+pub(crate) struct Pipeline {
+    steps: Vec<Step>,
+    transforms: Vec<Box<dyn Transform>>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        Pipeline {
+            steps: BUILT_IN_STEPS.to_vec(),
+            transforms: vec![],
+        }
+    }
+}
+
+impl Pipeline {
+    /// from_names builds the pipeline from the stage names in PIPELINE, or the built-in order if there are none.
+    /// Built-in stages left out don't run. Names that aren't built in are kept for custom transforms.
+    pub(crate) fn from_names(names: &[String]) -> Pipeline {
+        if names.is_empty() {
+            return Pipeline::default();
+        }
+        let mut steps: Vec<Step> = vec![];
+        for name in names {
+            let step = Step::from_name(name);
+            if steps.contains(&step) {
+                warn!(
+                    "PIPELINE lists {} more than once, only running it once",
+                    name
+                );
+                continue;
+            }
+            if let Step::Custom(name) = &step {
+                warn!(
+                    "PIPELINE stage {:?} isn't built in, it only runs if a custom transform named that is added",
+                    name
+                );
+            }
+            steps.push(step);
+        }
+        Pipeline {
+            steps,
+            transforms: vec![],
+        }
+    }
+
+    pub(crate) fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// add adds a custom transform, and runs it last if PIPELINE doesn't list it
+    pub(crate) fn add(&mut self, transform: Box<dyn Transform>) {
+        let step = Step::Custom(transform.name().to_string());
+        if !self.steps.contains(&step) {
+            self.steps.push(step);
+        }
+        self.transforms.push(transform);
+    }
+
+    /// transform returns the custom transform with the name, if one was added
+    pub(crate) fn transform(&self, name: &str) -> Option<&dyn Transform> {
+        self.transforms
+            .iter()
+            .find(|transform| transform.name() == name)
+            .map(|transform| transform.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_names() {
+        assert_eq!(Pipeline::from_names(&[]).steps(), BUILT_IN_STEPS);
+
+        let names = ["Params", "mine", "params", "host_rewrite"].map(String::from);
+        let mut pipeline = Pipeline::from_names(&names);
+        assert_eq!(
+            pipeline.steps(),
+            [
+                Step::Params,
+                Step::Custom("mine".to_string()),
+                Step::HostRewrite
+            ]
+        );
+
+        struct Mine;
+        impl Transform for Mine {
+            fn name(&self) -> &str {
+                "mine"
+            }
+
+            fn apply(&self, _url: &Url) -> Option<Url> {
+                None
+            }
+        }
+        pipeline.add(Box::new(Mine));
+        assert_eq!(pipeline.steps().len(), 3);
+        assert!(pipeline.transform("mine").is_some());
+        assert!(pipeline.transform("other").is_none());
+    }
+}
@@ -0,0 +1,451 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::info;
+
+/// NAME is the name of the lock, the systemd unit and the Windows startup entry
+const NAME: &str = "clipboard-sanitizer";
+/// LAUNCHD_LABEL names the launchd agent, in reverse domain notation like launchd expects
+const LAUNCHD_LABEL: &str = "fi.3v.clipboard-sanitizer";
+/// RUN_KEY is the registry key of the programs Windows starts at login
+const RUN_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Run";
+
+/// InstanceLock makes sure only one instance monitors the clipboard, so two of them don't fight over it.
+/// It's held until the process exits.
+pub struct InstanceLock {
+    #[cfg(unix)]
+    file: std::fs::File,
+    #[cfg(windows)]
+    _mutex: windows_sys::Win32::Foundation::HANDLE,
+}
+
+impl InstanceLock {
+    /// acquire takes the lock, or fails with an AlreadyExists error if another instance holds it
+    #[cfg(unix)]
+    pub fn acquire(path: &Path) -> io::Result<InstanceLock> {
+        use std::io::Read;
+        use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+        use std::os::unix::io::AsRawFd;
+
+        // Without a runtime directory the lock is in the shared temporary directory, where another user could
+        // put a link to one of our files in its place. Links aren't followed, and a file that isn't only ours
+        // is never written to.
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)?;
+        let metadata = file.metadata()?;
+        if metadata.uid() != unsafe { libc::getuid() } || metadata.nlink() != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{} is owned by another user or linked elsewhere",
+                    path.display()
+                ),
+            ));
+        }
+        // flock locks are released by the kernel when the process exits, so a crash never leaves a stale lock
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(error);
+            }
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("already running with pid {}", pid.trim()),
+            ));
+        }
+        let mut lock = InstanceLock { file };
+        lock.record_pid()?;
+        Ok(lock)
+    }
+
+    /// record_pid writes the process ID to the lock file, for the error of the next instance. A daemon records
+    /// its own again after the fork.
+    #[cfg(unix)]
+    pub fn record_pid(&mut self) -> io::Result<()> {
+        use std::io::{Seek, Write};
+
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        write!(self.file, "{}", std::process::id())
+    }
+
+    #[cfg(windows)]
+    pub fn acquire(_path: &Path) -> io::Result<InstanceLock> {
+        use windows_sys::Win32::Foundation::{GetLastError, ERROR_ALREADY_EXISTS};
+        use windows_sys::Win32::System::Threading::CreateMutexW;
+
+        // The mutex is local to the login session, like the clipboard
+        let name: Vec<u16> = format!("Local\\{}", NAME)
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
+        let mutex = unsafe { CreateMutexW(std::ptr::null(), 0, name.as_ptr()) };
+        if mutex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "already running",
+            ));
+        }
+        Ok(InstanceLock { _mutex: mutex })
+    }
+}
+
+/// lock_file is where the instance lock is kept on Unix: in the user's runtime directory, or the temporary
+/// directory with the user's ID in the name where there's none. acquire never uses a file there that another user
+/// could have planted.
+pub fn lock_file() -> PathBuf {
+    #[cfg(unix)]
+    let user = unsafe { libc::getuid() }.to_string();
+    #[cfg(not(unix))]
+    let user = String::new();
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join(format!("{}.lock", NAME)),
+        None => std::env::temp_dir().join(format!("{}-{}.lock", NAME, user)),
+    }
+}
+
+/// daemonize detaches the process from the terminal to keep running in the background. Only the child
+/// returns. Its stdin is /dev/null and stdout and stderr, so also the log, go to the log file.
+/// It has to be called before any threads are started, since only the calling thread survives the fork.
+#[cfg(unix)]
+pub fn daemonize(log_file: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if let Some(dir) = log_file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+    let null = std::fs::File::open("/dev/null")?;
+
+    // Fork twice with a new session in between, so the daemon isn't a session leader and can never get a
+    // controlling terminal again
+    for new_session in [true, false] {
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => std::process::exit(0),
+        }
+        if new_session && unsafe { libc::setsid() } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // The working directory stays, so relative paths in the config keep working
+    for (from, to) in [
+        (null.as_raw_fd(), libc::STDIN_FILENO),
+        (log.as_raw_fd(), libc::STDOUT_FILENO),
+        (log.as_raw_fd(), libc::STDERR_FILENO),
+    ] {
+        if unsafe { libc::dup2(from, to) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// daemon_log_file is where the log of a --daemon goes
+#[cfg(unix)]
+pub fn daemon_log_file() -> PathBuf {
+    dirs::state_dir()
+        .or_else(dirs::cache_dir)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(NAME)
+        .join(format!("{}.log", NAME))
+}
+
+/// install registers clipboard-sanitizer to start at login with the given arguments and starts it, where the
+/// platform allows. Returns a description of what was installed.
+pub fn install(args: &[String]) -> Result<String, String> {
+    let exe = std::env::current_exe()
+        .map_err(|e| format!("Failed to find the path of clipboard-sanitizer: {}", e))?;
+    let exe = exe.to_string_lossy();
+    match platform() {
+        Platform::Systemd => {
+            let path = systemd_unit_file()?;
+            write(&path, &systemd_unit(&exe, args))?;
+            run("systemctl", &["--user", "daemon-reload"])?;
+            run("systemctl", &["--user", "enable", "--now", &unit_name()])?;
+            Ok(format!(
+                "Installed and started systemd user unit {:?}",
+                path
+            ))
+        }
+        Platform::Launchd => {
+            let path = launchd_plist_file()?;
+            write(&path, &launchd_plist(&exe, args))?;
+            run("launchctl", &["load", "-w", &path.to_string_lossy()])?;
+            Ok(format!("Installed and started launchd agent {:?}", path))
+        }
+        Platform::Windows => {
+            let command = windows_command(&exe, args);
+            run(
+                "reg",
+                &[
+                    "add", RUN_KEY, "/v", NAME, "/t", "REG_SZ", "/d", &command, "/f",
+                ],
+            )?;
+            Ok(format!(
+                "Registered {} to start at the next login: {}",
+                NAME, command
+            ))
+        }
+    }
+}
+
+/// uninstall stops the service that install set up and removes it
+pub fn uninstall() -> Result<String, String> {
+    match platform() {
+        Platform::Systemd => {
+            let path = systemd_unit_file()?;
+            run("systemctl", &["--user", "disable", "--now", &unit_name()])?;
+            remove(&path)?;
+            run("systemctl", &["--user", "daemon-reload"])?;
+            Ok(format!("Stopped and removed systemd user unit {:?}", path))
+        }
+        Platform::Launchd => {
+            let path = launchd_plist_file()?;
+            run("launchctl", &["unload", "-w", &path.to_string_lossy()])?;
+            remove(&path)?;
+            Ok(format!("Stopped and removed launchd agent {:?}", path))
+        }
+        Platform::Windows => {
+            run("reg", &["delete", RUN_KEY, "/v", NAME, "/f"])?;
+            Ok(format!(
+                "Removed {} from the programs started at login",
+                NAME
+            ))
+        }
+    }
+}
+
+enum Platform {
+    Systemd,
+    Launchd,
+    Windows,
+}
+
+fn platform() -> Platform {
+    if cfg!(windows) {
+        Platform::Windows
+    } else if cfg!(target_os = "macos") {
+        Platform::Launchd
+    } else {
+        Platform::Systemd
+    }
+}
+
+fn unit_name() -> String {
+    format!("{}.service", NAME)
+}
+
+fn systemd_unit_file() -> Result<PathBuf, String> {
+    let dir = dirs::config_dir().ok_or("Failed to find the config directory")?;
+    Ok(dir.join("systemd").join("user").join(unit_name()))
+}
+
+fn launchd_plist_file() -> Result<PathBuf, String> {
+    let dir = dirs::home_dir().ok_or("Failed to find the home directory")?;
+    Ok(dir
+        .join("Library")
+        .join("LaunchAgents")
+        .join(format!("{}.plist", LAUNCHD_LABEL)))
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {:?}: {}", dir, e))?;
+    }
+    std::fs::write(path, contents).map_err(|e| format!("Failed to write {:?}: {}", path, e))?;
+    info!("Wrote {:?}", path);
+    Ok(())
+}
+
+fn remove(path: &Path) -> Result<(), String> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Failed to remove {:?}: {}", path, e)),
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), String> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if !status.success() {
+        return Err(format!("{} {} failed: {}", program, args.join(" "), status));
+    }
+    Ok(())
+}
+
+/// systemd_unit is a user unit that runs with the graphical session, which is when there's a clipboard
+#[cfg_attr(any(windows, target_os = "macos"), allow(dead_code))]
+fn systemd_unit(exe: &str, args: &[String]) -> String {
+    let command: Vec<String> = std::iter::once(exe)
+        .chain(args.iter().map(String::as_str))
+        .map(|arg| {
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\')
+            {
+                format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+            } else {
+                arg.to_string()
+            }
+        })
+        .collect();
+    format!(
+        "[Unit]
+Description=Strip tracking parameters from URLs in the clipboard
+PartOf=graphical-session.target
+After=graphical-session.target
+
+[Service]
+ExecStart={}
+Restart=on-failure
+
+[Install]
+WantedBy=graphical-session.target
+",
+        command.join(" ")
+    )
+}
+
+/// launchd_plist is a launch agent that starts at login and is restarted if it fails
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn launchd_plist(exe: &str, args: &[String]) -> String {
+    let arguments: String = std::iter::once(exe)
+        .chain(args.iter().map(String::as_str))
+        .map(|arg| {
+            let arg = arg
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+            format!("        <string>{}</string>\n", arg)
+        })
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+</dict>
+</plist>
+"#,
+        LAUNCHD_LABEL, arguments
+    )
+}
+
+/// windows_command is the command line of the startup registration
+#[cfg_attr(not(windows), allow(dead_code))]
+fn windows_command(exe: &str, args: &[String]) -> String {
+    std::iter::once(exe)
+        .chain(args.iter().map(String::as_str))
+        .map(|arg| {
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"') {
+                format!("\"{}\"", arg.replace('"', "\\\""))
+            } else {
+                arg.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_instance_lock() {
+        let path = std::env::temp_dir().join(format!("{}-test-{}.lock", NAME, std::process::id()));
+        let lock = InstanceLock::acquire(&path).unwrap();
+        let error = InstanceLock::acquire(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(error.to_string().contains(&std::process::id().to_string()));
+
+        drop(lock);
+        assert!(InstanceLock::acquire(&path).is_ok());
+
+        // A link planted where the lock goes isn't followed, so the file it points to is never truncated
+        let target = path.with_extension("target");
+        std::fs::write(&target, "keep").unwrap();
+        let _ = std::fs::remove_file(&path);
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+        assert!(InstanceLock::acquire(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::hard_link(&target, &path).unwrap();
+        assert_eq!(
+            InstanceLock::acquire(&path).err().unwrap().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "keep");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&target);
+    }
+
+    #[test]
+    fn test_service_definitions() {
+        let args = vec!["--tray".to_string(), "two words".to_string()];
+        let unit = systemd_unit("/opt/clipboard sanitizer/clipboard-sanitizer", &args);
+        assert!(unit.contains(
+            "\nExecStart=\"/opt/clipboard sanitizer/clipboard-sanitizer\" --tray \"two words\"\n"
+        ));
+        assert!(unit.contains("\nWantedBy=graphical-session.target\n"));
+
+        let plist = launchd_plist("/usr/local/bin/clipboard-sanitizer", &args);
+        assert!(plist.contains(
+            "        <string>/usr/local/bin/clipboard-sanitizer</string>\n        <string>--tray</string>\n"
+        ));
+        assert!(plist.contains("<string>fi.3v.clipboard-sanitizer</string>"));
+
+        assert_eq!(
+            windows_command(r"C:\Program Files\clipboard-sanitizer.exe", &args),
+            r#""C:\Program Files\clipboard-sanitizer.exe" --tray "two words""#
+        );
+    }
+}
@@ -0,0 +1,59 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+
+use log::error;
+
+/// Settings are the values from the config file by key. The keys of tables are flattened to `TABLE.key`.
+pub type Settings = HashMap<String, String>;
+
+pub fn read(settings: &Settings, key: &str) -> Option<String> {
+    settings.get(key).cloned()
+}
+
+/// read_bool returns true if the setting is set to "true" or "1"
+pub fn read_bool(settings: &Settings, key: &str, default: bool) -> bool {
+    match read(settings, key) {
+        Some(value) => value.eq_ignore_ascii_case("true") || value == "1",
+        None => default,
+    }
+}
+
+/// read_u64 returns the setting as a number, or default if it's not set or invalid
+pub fn read_u64(settings: &Settings, key: &str, default: u64) -> u64 {
+    match read(settings, key) {
+        Some(value) => value.parse().unwrap_or_else(|e| {
+            error!("Invalid value for {}: {:?}: {}", key, value, e);
+            default
+        }),
+        None => default,
+    }
+}
+
+/// read_table returns the keys and values of a table in the config, like `[DOMAINS]`
+pub fn read_table(settings: &Settings, table: &str) -> Vec<(String, String)> {
+    let prefix = format!("{}.", table);
+    settings
+        .iter()
+        .filter_map(|(key, value)| {
+            key.strip_prefix(&prefix)
+                .map(|key| (key.to_string(), value.to_string()))
+        })
+        .collect()
+}
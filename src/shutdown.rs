@@ -0,0 +1,102 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::error;
+
+/// Set by the signal handler. Storing to an atomic is all a signal handler can safely do.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set by the SIGHUP handler until the main loop reloads the config
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// install makes SIGINT and SIGTERM (Ctrl-C and closing the console on Windows) request a shutdown
+/// instead of killing the process, so the main loop can finish its iteration and clean up.
+/// On Unix SIGHUP requests a config reload instead of hanging up.
+pub fn install() {
+    #[cfg(unix)]
+    {
+        extern "C" fn handle(signal: libc::c_int) {
+            if signal == libc::SIGHUP {
+                RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+            } else {
+                REQUESTED.store(true, Ordering::SeqCst);
+            }
+        }
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                error!(
+                    "Failed to install handler for signal {}: {}",
+                    signal,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        unsafe extern "system" fn handle(_ctrl_type: u32) -> windows_sys::Win32::Foundation::BOOL {
+            REQUESTED.store(true, Ordering::SeqCst);
+            1
+        }
+        if unsafe { windows_sys::Win32::System::Console::SetConsoleCtrlHandler(Some(handle), 1) }
+            == 0
+        {
+            error!(
+                "Failed to install console control handler: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// requested returns true once a shutdown signal was received
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// take_reload_request returns true once after SIGHUP was received
+pub fn take_reload_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// request shuts down like a signal would, e.g. from the tray's Quit item
+#[cfg(feature = "tray")]
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal_requests_shutdown() {
+        install();
+        assert!(!take_reload_request());
+        unsafe { libc::raise(libc::SIGHUP) };
+        assert!(!requested());
+        assert!(take_reload_request());
+        assert!(!take_reload_request());
+
+        unsafe { libc::raise(libc::SIGTERM) };
+        assert!(requested());
+    }
+}
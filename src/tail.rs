@@ -0,0 +1,123 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+/// Tail follows a file like `tail -f`, returning the lines appended to it.
+/// If the file is truncated or replaced, it's followed again from the start.
+pub struct Tail {
+    path: PathBuf,
+    position: u64,
+    #[cfg(unix)]
+    inode: u64,
+    partial: Vec<u8>,
+}
+
+impl Tail {
+    /// open_at_end starts following the file from its current end, so existing lines are skipped
+    pub fn open_at_end(path: &Path) -> io::Result<Tail> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Tail {
+            path: path.to_path_buf(),
+            position: metadata.len(),
+            #[cfg(unix)]
+            inode: std::os::unix::fs::MetadataExt::ino(&metadata),
+            partial: vec![],
+        })
+    }
+
+    /// poll returns the complete lines appended since the last poll, without their line endings.
+    /// A line without a newline at the end is held back until it's finished.
+    pub fn poll(&mut self) -> io::Result<Vec<String>> {
+        let metadata = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // The file is being rotated, try again on the next poll
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        #[cfg(unix)]
+        {
+            let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+            if inode != self.inode {
+                info!("{:?} was replaced, following the new file", self.path);
+                self.inode = inode;
+                self.restart();
+            }
+        }
+        if metadata.len() < self.position {
+            info!("{:?} was truncated, following from the start", self.path);
+            self.restart();
+        }
+        if metadata.len() == self.position {
+            return Ok(vec![]);
+        }
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.position))?;
+        let read = file.read_to_end(&mut self.partial)?;
+        self.position += read as u64;
+
+        let mut lines = vec![];
+        while let Some(end) = self.partial.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            lines.push(line.trim_end_matches(['\r', '\n']).to_string());
+        }
+        Ok(lines)
+    }
+
+    fn restart(&mut self) {
+        self.position = 0;
+        self.partial.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_tail_follows_appends_and_truncation() {
+        let path = std::env::temp_dir().join(format!(
+            "clipboard-sanitizer-tail-{}.log",
+            std::process::id()
+        ));
+        std::fs::write(&path, "existing line\n").unwrap();
+        let mut tail = Tail::open_at_end(&path).unwrap();
+        assert!(tail.poll().unwrap().is_empty());
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"first\r\nsecond\nunfinis").unwrap();
+        assert_eq!(tail.poll().unwrap(), vec!["first", "second"]);
+        file.write_all(b"hed\n").unwrap();
+        assert_eq!(tail.poll().unwrap(), vec!["unfinished"]);
+
+        std::fs::write(&path, "after truncation\n").unwrap();
+        assert_eq!(tail.poll().unwrap(), vec!["after truncation"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
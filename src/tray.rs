@@ -0,0 +1,792 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+
+use crate::shutdown;
+
+/// How many of the last cleaned clipboard contents the menu lists
+const RECENT_COUNT: usize = 10;
+/// Recent contents longer than this are cut short in the menu
+const MAX_LABEL_CHARS: usize = 60;
+
+const PAUSE_ID: i32 = 1;
+const QUIT_ID: i32 = 2;
+/// The recent contents have the IDs from RECENT_ID up, newest first
+const RECENT_ID: i32 = 100;
+
+#[derive(Debug, PartialEq)]
+enum MenuItem {
+    Separator,
+    Entry {
+        id: i32,
+        label: String,
+        enabled: bool,
+        /// Some for items with a checkmark
+        checked: Option<bool>,
+    },
+}
+
+/// TrayState is what the tray shows and what its menu changes. It's shared between the main loop and
+/// the thread the platform calls the tray on.
+#[derive(Default)]
+struct TrayState {
+    paused: AtomicBool,
+    /// recent are the last cleaned clipboard contents, newest first
+    recent: Mutex<VecDeque<String>>,
+    /// copy is a recent content the user picked from the menu, for the main loop to put on the clipboard
+    copy: Mutex<Option<String>>,
+    /// revision changes whenever the menu does, so the D-Bus menu knows when to fetch it again
+    revision: AtomicU32,
+}
+
+impl TrayState {
+    fn paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn status(&self) -> &'static str {
+        if self.paused() {
+            "Paused, the clipboard is left alone"
+        } else {
+            "Cleaning URLs copied to the clipboard"
+        }
+    }
+
+    fn menu(&self) -> Vec<MenuItem> {
+        let mut items = vec![
+            MenuItem::Entry {
+                id: PAUSE_ID,
+                label: "Paused".to_string(),
+                enabled: true,
+                checked: Some(self.paused()),
+            },
+            MenuItem::Separator,
+        ];
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.is_empty() {
+            items.push(MenuItem::Entry {
+                id: RECENT_ID,
+                label: "Nothing cleaned yet".to_string(),
+                enabled: false,
+                checked: None,
+            });
+        }
+        for (i, content) in recent.iter().enumerate() {
+            items.push(MenuItem::Entry {
+                id: RECENT_ID + i as i32,
+                label: label(content),
+                enabled: true,
+                checked: None,
+            });
+        }
+        items.push(MenuItem::Separator);
+        items.push(MenuItem::Entry {
+            id: QUIT_ID,
+            label: "Quit".to_string(),
+            enabled: true,
+            checked: None,
+        });
+        items
+    }
+
+    /// activate runs the menu item the user clicked. Picking a recent content copies it again.
+    fn activate(&self, id: i32) {
+        match id {
+            PAUSE_ID => {
+                let paused = !self.paused.fetch_xor(true, Ordering::SeqCst);
+                debug!(
+                    "{} from the tray",
+                    if paused { "Paused" } else { "Resumed" }
+                );
+            }
+            QUIT_ID => shutdown::request(),
+            _ => {
+                let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+                let Some(content) = usize::try_from(id - RECENT_ID)
+                    .ok()
+                    .and_then(|i| recent.get(i))
+                else {
+                    return;
+                };
+                *self.copy.lock().unwrap_or_else(|e| e.into_inner()) = Some(content.clone());
+            }
+        }
+        self.revision.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn cleaned(&self, content: &str) {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.retain(|previous| previous != content);
+        recent.push_front(content.to_string());
+        recent.truncate(RECENT_COUNT);
+        self.revision.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// label is the first line of the content, cut short if it's long
+fn label(content: &str) -> String {
+    let line = content.lines().next().unwrap_or_default().trim();
+    let mut label: String = line.chars().take(MAX_LABEL_CHARS).collect();
+    if label.len() < content.trim().len() {
+        label.push('…');
+    }
+    label
+}
+
+/// Tray is the icon in the system tray, or notification area on Windows. Its menu pauses and resumes cleaning,
+/// lists the last cleaned contents to copy them again and quits.
+pub struct Tray {
+    state: Arc<TrayState>,
+    platform: platform::Handle,
+}
+
+impl Tray {
+    /// start shows the icon. It fails if the desktop has no tray, e.g. GNOME without the AppIndicator extension.
+    pub fn start() -> Result<Tray, String> {
+        let state = Arc::new(TrayState::default());
+        let platform = platform::start(state.clone())?;
+        Ok(Tray { state, platform })
+    }
+
+    pub fn paused(&self) -> bool {
+        self.state.paused()
+    }
+
+    /// take_copy returns the recent content the user picked from the menu since the last call, if any
+    pub fn take_copy(&self) -> Option<String> {
+        self.state
+            .copy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+
+    /// cleaned adds content written to the clipboard to the recent list
+    pub fn cleaned(&self, content: &str) {
+        self.state.cleaned(content);
+        platform::menu_changed(&self.platform, &self.state);
+    }
+}
+
+/// The tray is a StatusNotifierItem with a com.canonical.dbusmenu menu, which KDE, most wlroots bars and
+/// GNOME with the AppIndicator extension show
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+mod platform {
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use log::warn;
+    use zbus::zvariant::{Array, Dict, ObjectPath, OwnedValue, StructureBuilder, Value};
+    use zbus::{dbus_interface, SignalContext};
+
+    use super::{MenuItem, TrayState};
+
+    const ITEM_PATH: &str = "/StatusNotifierItem";
+    const MENU_PATH: &str = "/MenuBar";
+
+    pub type Handle = zbus::blocking::Connection;
+
+    pub fn start(state: Arc<TrayState>) -> Result<Handle, String> {
+        let name = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+        let connection = zbus::blocking::ConnectionBuilder::session()
+            .and_then(|builder| builder.name(name.as_str()))
+            .and_then(|builder| {
+                builder.serve_at(
+                    ITEM_PATH,
+                    Item {
+                        state: state.clone(),
+                    },
+                )
+            })
+            .and_then(|builder| builder.serve_at(MENU_PATH, Menu { state }))
+            .and_then(|builder| builder.build())
+            .map_err(|e| format!("D-Bus session bus: {}", e))?;
+        connection
+            .call_method(
+                Some("org.kde.StatusNotifierWatcher"),
+                "/StatusNotifierWatcher",
+                Some("org.kde.StatusNotifierWatcher"),
+                "RegisterStatusNotifierItem",
+                &name,
+            )
+            .map_err(|e| format!("no StatusNotifierItem host: {}", e))?;
+        Ok(connection)
+    }
+
+    pub fn menu_changed(connection: &Handle, state: &TrayState) {
+        let revision = state.revision.load(Ordering::SeqCst);
+        if let Err(e) = connection.emit_signal(
+            None::<()>,
+            MENU_PATH,
+            "com.canonical.dbusmenu",
+            "LayoutUpdated",
+            &(revision, 0i32),
+        ) {
+            warn!("Failed to update the tray menu: {}", e);
+        }
+    }
+
+    /// The icon name, icon pixmaps (width, height and ARGB data), title and description of a tooltip
+    type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>, String, String);
+
+    struct Item {
+        state: Arc<TrayState>,
+    }
+
+    #[dbus_interface(name = "org.kde.StatusNotifierItem")]
+    impl Item {
+        #[dbus_interface(property)]
+        fn category(&self) -> &str {
+            "ApplicationStatus"
+        }
+
+        #[dbus_interface(property)]
+        fn id(&self) -> &str {
+            "clipboard-sanitizer"
+        }
+
+        #[dbus_interface(property)]
+        fn title(&self) -> &str {
+            "clipboard-sanitizer"
+        }
+
+        #[dbus_interface(property)]
+        fn status(&self) -> &str {
+            "Active"
+        }
+
+        #[dbus_interface(property)]
+        fn icon_name(&self) -> &str {
+            if self.state.paused() {
+                "media-playback-pause"
+            } else {
+                "edit-paste"
+            }
+        }
+
+        #[dbus_interface(property)]
+        fn tool_tip(&self) -> ToolTip {
+            (
+                String::new(),
+                vec![],
+                "clipboard-sanitizer".to_string(),
+                self.state.status().to_string(),
+            )
+        }
+
+        /// The host shows the menu on every click instead of calling activate
+        #[dbus_interface(property)]
+        fn item_is_menu(&self) -> bool {
+            true
+        }
+
+        #[dbus_interface(property)]
+        fn menu(&self) -> ObjectPath<'_> {
+            ObjectPath::from_static_str_unchecked(MENU_PATH)
+        }
+
+        fn activate(&self, _x: i32, _y: i32) {}
+
+        fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+        fn context_menu(&self, _x: i32, _y: i32) {}
+
+        fn scroll(&self, _delta: i32, _orientation: &str) {}
+    }
+
+    /// A menu item's ID, properties and children, the layout format of dbusmenu
+    type Layout = (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>);
+
+    struct Menu {
+        state: Arc<TrayState>,
+    }
+
+    impl Menu {
+        fn items(&self) -> Vec<(i32, HashMap<String, OwnedValue>)> {
+            let mut separators = 0;
+            self.state
+                .menu()
+                .into_iter()
+                .map(|item| match item {
+                    MenuItem::Separator => {
+                        // Separators need IDs too, below the ones of the entries
+                        separators += 1;
+                        let properties =
+                            HashMap::from([("type".to_string(), Value::from("separator").into())]);
+                        (-separators, properties)
+                    }
+                    MenuItem::Entry {
+                        id,
+                        label,
+                        enabled,
+                        checked,
+                    } => {
+                        let mut properties = HashMap::from([
+                            ("label".to_string(), Value::from(label).into()),
+                            ("enabled".to_string(), Value::from(enabled).into()),
+                        ]);
+                        if let Some(checked) = checked {
+                            properties
+                                .insert("toggle-type".to_string(), Value::from("checkmark").into());
+                            properties.insert(
+                                "toggle-state".to_string(),
+                                Value::from(checked as i32).into(),
+                            );
+                        }
+                        (id, properties)
+                    }
+                })
+                .collect()
+        }
+    }
+
+    #[dbus_interface(name = "com.canonical.dbusmenu")]
+    impl Menu {
+        /// The menu is flat, so whatever is asked for, the root and all items are returned
+        fn get_layout(
+            &self,
+            _parent_id: i32,
+            _recursion_depth: i32,
+            _property_names: Vec<String>,
+        ) -> (u32, Layout) {
+            let children = self
+                .items()
+                .into_iter()
+                .map(|(id, properties)| {
+                    let properties: HashMap<String, Value> = properties
+                        .into_iter()
+                        .map(|(key, value)| (key, value.into()))
+                        .collect();
+                    let item = StructureBuilder::new()
+                        .add_field(id)
+                        .append_field(Value::Dict(Dict::from(properties)))
+                        .append_field(Value::Array(Array::from(Vec::<Value>::new())))
+                        .build();
+                    Value::Structure(item).into()
+                })
+                .collect();
+            let root = HashMap::from([(
+                "children-display".to_string(),
+                Value::from("submenu").into(),
+            )]);
+            (
+                self.state.revision.load(Ordering::SeqCst),
+                (0, root, children),
+            )
+        }
+
+        fn get_group_properties(
+            &self,
+            ids: Vec<i32>,
+            _property_names: Vec<String>,
+        ) -> Vec<(i32, HashMap<String, OwnedValue>)> {
+            self.items()
+                .into_iter()
+                .filter(|(id, _)| ids.contains(id))
+                .collect()
+        }
+
+        fn get_property(&self, id: i32, name: String) -> zbus::fdo::Result<OwnedValue> {
+            self.items()
+                .into_iter()
+                .find(|(item, _)| *item == id)
+                .and_then(|(_, mut properties)| properties.remove(&name))
+                .ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("no {} on item {}", name, id)))
+        }
+
+        async fn event(
+            &self,
+            #[zbus(signal_context)] context: SignalContext<'_>,
+            id: i32,
+            event_id: String,
+            _data: OwnedValue,
+            _timestamp: u32,
+        ) {
+            if event_id != "clicked" {
+                return;
+            }
+            let was_paused = self.state.paused();
+            self.state.activate(id);
+            let revision = self.state.revision.load(Ordering::SeqCst);
+            if let Err(e) = Menu::layout_updated(&context, revision, 0).await {
+                warn!("Failed to update the tray menu: {}", e);
+            }
+            if self.state.paused() != was_paused {
+                for signal in ["NewIcon", "NewToolTip"] {
+                    let result = context
+                        .connection()
+                        .emit_signal(
+                            None::<()>,
+                            ITEM_PATH,
+                            "org.kde.StatusNotifierItem",
+                            signal,
+                            &(),
+                        )
+                        .await;
+                    if let Err(e) = result {
+                        warn!("Failed to update the tray icon: {}", e);
+                    }
+                }
+            }
+        }
+
+        async fn event_group(
+            &self,
+            #[zbus(signal_context)] context: SignalContext<'_>,
+            events: Vec<(i32, String, OwnedValue, u32)>,
+        ) -> Vec<i32> {
+            for (id, event_id, data, timestamp) in events {
+                self.event(context.clone(), id, event_id, data, timestamp)
+                    .await;
+            }
+            vec![]
+        }
+
+        fn about_to_show(&self, _id: i32) -> bool {
+            false
+        }
+
+        fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+            (vec![], vec![])
+        }
+
+        #[dbus_interface(property)]
+        fn version(&self) -> u32 {
+            3
+        }
+
+        #[dbus_interface(property)]
+        fn text_direction(&self) -> &str {
+            "ltr"
+        }
+
+        #[dbus_interface(property)]
+        fn status(&self) -> &str {
+            "normal"
+        }
+
+        #[dbus_interface(property)]
+        fn icon_theme_path(&self) -> Vec<String> {
+            vec![]
+        }
+
+        #[dbus_interface(signal)]
+        async fn layout_updated(
+            context: &SignalContext<'_>,
+            revision: u32,
+            parent: i32,
+        ) -> zbus::Result<()>;
+    }
+}
+
+/// The tray is a notification area icon of a hidden window, which shows the menu when the icon is clicked
+#[cfg(windows)]
+mod platform {
+    use std::sync::Arc;
+
+    use windows_sys::Win32::Foundation::{HWND, LPARAM, LRESULT, POINT, WPARAM};
+    use windows_sys::Win32::UI::Shell::{
+        Shell_NotifyIconW, NIF_ICON, NIF_MESSAGE, NIF_TIP, NIM_ADD, NIM_DELETE, NIM_MODIFY,
+        NOTIFYICONDATAW,
+    };
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        AppendMenuW, CreatePopupMenu, CreateWindowExW, DefWindowProcW, DestroyMenu,
+        DispatchMessageW, GetCursorPos, GetMessageW, LoadIconW, RegisterClassW,
+        SetForegroundWindow, TrackPopupMenu, IDI_APPLICATION, MF_CHECKED, MF_GRAYED, MF_SEPARATOR,
+        MF_STRING, MSG, TPM_NONOTIFY, TPM_RETURNCMD, TPM_RIGHTBUTTON, WM_APP, WM_LBUTTONUP,
+        WM_RBUTTONUP, WNDCLASSW,
+    };
+
+    use super::{MenuItem, TrayState};
+
+    /// The message the icon sends the window when it's clicked
+    const WM_TRAY: u32 = WM_APP + 1;
+
+    thread_local! {
+        static STATE: std::cell::RefCell<Option<Arc<TrayState>>> = const { std::cell::RefCell::new(None) };
+    }
+
+    /// Handle is the hidden window, which removes the icon when it's dropped
+    pub struct Handle(HWND);
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            // SAFETY: The icon is identified by the window and ID, the rest of the data is ignored
+            unsafe {
+                let mut data: NOTIFYICONDATAW = std::mem::zeroed();
+                data.cbSize = std::mem::size_of::<NOTIFYICONDATAW>() as u32;
+                data.hWnd = self.0;
+                data.uID = 1;
+                Shell_NotifyIconW(NIM_DELETE, &data);
+            }
+        }
+    }
+
+    pub fn start(state: Arc<TrayState>) -> Result<Handle, String> {
+        let (ready, window) = std::sync::mpsc::channel();
+        std::thread::spawn(move || run(state, ready));
+        window
+            .recv()
+            .unwrap_or_else(|_| Err("tray thread exited".to_string()))
+            .map(Handle)
+    }
+
+    /// The menu is built from the state whenever it's opened, so only the tooltip needs updating
+    pub fn menu_changed(_window: &Handle, _state: &TrayState) {}
+
+    fn set_icon(window: HWND, state: &TrayState, action: u32) -> bool {
+        // SAFETY: The data is fully initialized and only read during the call
+        unsafe {
+            let mut data: NOTIFYICONDATAW = std::mem::zeroed();
+            data.cbSize = std::mem::size_of::<NOTIFYICONDATAW>() as u32;
+            data.hWnd = window;
+            data.uID = 1;
+            data.uFlags = NIF_ICON | NIF_MESSAGE | NIF_TIP;
+            data.uCallbackMessage = WM_TRAY;
+            data.hIcon = LoadIconW(0, IDI_APPLICATION);
+            let tip: Vec<u16> = format!("clipboard-sanitizer: {}", state.status())
+                .encode_utf16()
+                .take(data.szTip.len() - 1)
+                .collect();
+            data.szTip[..tip.len()].copy_from_slice(&tip);
+            Shell_NotifyIconW(action, &data) != 0
+        }
+    }
+
+    fn show_menu(window: HWND, state: &TrayState) {
+        // SAFETY: The menu and its labels live until TrackPopupMenu returns, and the menu is destroyed after
+        unsafe {
+            let menu = CreatePopupMenu();
+            for item in state.menu() {
+                match item {
+                    MenuItem::Separator => {
+                        AppendMenuW(menu, MF_SEPARATOR, 0, std::ptr::null());
+                    }
+                    MenuItem::Entry {
+                        id,
+                        label,
+                        enabled,
+                        checked,
+                    } => {
+                        let label: Vec<u16> = label.encode_utf16().chain([0]).collect();
+                        let mut flags = MF_STRING;
+                        if !enabled {
+                            flags |= MF_GRAYED;
+                        }
+                        if checked == Some(true) {
+                            flags |= MF_CHECKED;
+                        }
+                        AppendMenuW(menu, flags, id as usize, label.as_ptr());
+                    }
+                }
+            }
+            let mut cursor = POINT { x: 0, y: 0 };
+            GetCursorPos(&mut cursor);
+            // Without this the menu doesn't close when clicking elsewhere
+            SetForegroundWindow(window);
+            let id = TrackPopupMenu(
+                menu,
+                TPM_RETURNCMD | TPM_NONOTIFY | TPM_RIGHTBUTTON,
+                cursor.x,
+                cursor.y,
+                0,
+                window,
+                std::ptr::null(),
+            );
+            DestroyMenu(menu);
+            if id != 0 {
+                let was_paused = state.paused();
+                state.activate(id);
+                if state.paused() != was_paused {
+                    set_icon(window, state, NIM_MODIFY);
+                }
+            }
+        }
+    }
+
+    unsafe extern "system" fn window_proc(
+        window: HWND,
+        message: u32,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        if message == WM_TRAY {
+            let event = (lparam & 0xffff) as u32;
+            if event == WM_LBUTTONUP || event == WM_RBUTTONUP {
+                STATE.with(|state| {
+                    if let Some(state) = &*state.borrow() {
+                        show_menu(window, state);
+                    }
+                });
+            }
+            return 0;
+        }
+        DefWindowProcW(window, message, wparam, lparam)
+    }
+
+    /// run creates the hidden window with the icon and runs its message loop.
+    /// ready gets the window, or why it couldn't be created.
+    fn run(state: Arc<TrayState>, ready: std::sync::mpsc::Sender<Result<HWND, String>>) {
+        let class_name: Vec<u16> = "clipboard-sanitizer-tray\0".encode_utf16().collect();
+        // SAFETY: The class name outlives the window, and the window is only used on this thread
+        unsafe {
+            let class = WNDCLASSW {
+                style: 0,
+                lpfnWndProc: Some(window_proc),
+                cbClsExtra: 0,
+                cbWndExtra: 0,
+                hInstance: 0,
+                hIcon: 0,
+                hCursor: 0,
+                hbrBackground: 0,
+                lpszMenuName: std::ptr::null(),
+                lpszClassName: class_name.as_ptr(),
+            };
+            RegisterClassW(&class);
+            // A hidden top-level window rather than a message-only one, which the taskbar doesn't notify
+            let window = CreateWindowExW(
+                0,
+                class_name.as_ptr(),
+                std::ptr::null(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                std::ptr::null(),
+            );
+            if window == 0 || !set_icon(window, &state, NIM_ADD) {
+                let _ = ready.send(Err(std::io::Error::last_os_error().to_string()));
+                return;
+            }
+            STATE.with(|cell| *cell.borrow_mut() = Some(state));
+            let _ = ready.send(Ok(window));
+            let mut message: MSG = std::mem::zeroed();
+            while GetMessageW(&mut message, window, 0, 0) > 0 {
+                DispatchMessageW(&message);
+            }
+        }
+    }
+}
+
+#[cfg(not(any(
+    windows,
+    all(
+        unix,
+        not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+    )
+)))]
+mod platform {
+    use std::sync::Arc;
+
+    use super::TrayState;
+
+    pub type Handle = ();
+
+    pub fn start(_state: Arc<TrayState>) -> Result<Handle, String> {
+        Err("tray icons aren't supported on this platform".to_string())
+    }
+
+    pub fn menu_changed(_handle: &Handle, _state: &TrayState) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(state: &TrayState) -> Vec<String> {
+        state
+            .menu()
+            .into_iter()
+            .map(|item| match item {
+                MenuItem::Separator => "-".to_string(),
+                MenuItem::Entry {
+                    label,
+                    enabled,
+                    checked,
+                    ..
+                } => format!(
+                    "{}{}{}",
+                    label,
+                    if enabled { "" } else { " (disabled)" },
+                    match checked {
+                        Some(true) => " [x]",
+                        Some(false) => " [ ]",
+                        None => "",
+                    }
+                ),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tray_menu() {
+        let state = TrayState::default();
+        assert_eq!(
+            labels(&state),
+            [
+                "Paused [ ]",
+                "-",
+                "Nothing cleaned yet (disabled)",
+                "-",
+                "Quit"
+            ]
+        );
+
+        state.activate(PAUSE_ID);
+        assert!(state.paused());
+        let long = format!("https://example.com/{}", "a".repeat(60));
+        for content in ["https://example.com/1", &long, "https://example.com/1"] {
+            state.cleaned(content);
+        }
+        // Copying the same content again moves it to the top instead of listing it twice
+        assert_eq!(
+            labels(&state),
+            [
+                "Paused [x]".to_string(),
+                "-".to_string(),
+                "https://example.com/1".to_string(),
+                format!("{}…", &long[..MAX_LABEL_CHARS]),
+                "-".to_string(),
+                "Quit".to_string(),
+            ]
+        );
+
+        state.activate(RECENT_ID + 1);
+        assert_eq!(state.copy.lock().unwrap().take(), Some(long));
+        state.activate(RECENT_ID + 5);
+        assert_eq!(state.copy.lock().unwrap().take(), None);
+
+        for i in 0..RECENT_COUNT + 5 {
+            state.cleaned(&format!("https://example.com/{}", i));
+        }
+        assert_eq!(state.recent.lock().unwrap().len(), RECENT_COUNT);
+    }
+}
@@ -0,0 +1,101 @@
+/*
+Clipboard Sanitizer - A simple program to strip tracking parameters from URLs in the clipboard
+Copyright (C) 2023  Werner Vänttinen
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::warn;
+
+/// ConfigWatcher notices when the config file changes by polling its modification time and size.
+/// It's checked from the main loop, at most every check_interval, so the reload runs between clipboard checks.
+/// Polling is used instead of OS file events (the notify crate): a stat a second is cheap, it behaves the same on
+/// every platform and network drive, and it still sees editors that save by replacing the file.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    check_interval: Duration,
+    last_check: Instant,
+    state: Option<(SystemTime, u64)>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path, check_interval: Duration) -> ConfigWatcher {
+        ConfigWatcher {
+            path: path.to_path_buf(),
+            check_interval,
+            last_check: Instant::now(),
+            state: file_state(path),
+        }
+    }
+
+    /// changed returns true once after each change to the file. A missing file isn't a change, since
+    /// editors that save by replacing the file briefly remove it.
+    pub fn changed(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.last_check) < self.check_interval {
+            return false;
+        }
+        self.last_check = now;
+        let Some(state) = file_state(&self.path) else {
+            return false;
+        };
+        if self.state == Some(state) {
+            return false;
+        }
+        self.state = Some(state);
+        true
+    }
+}
+
+fn file_state(path: &Path) -> Option<(SystemTime, u64)> {
+    match std::fs::metadata(path).and_then(|metadata| Ok((metadata.modified()?, metadata.len()))) {
+        Ok(state) => Some(state),
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to check {:?} for changes: {}", path, e);
+            }
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_watcher_sees_changes() {
+        let path = std::env::temp_dir().join(format!(
+            "clipboard-sanitizer-watch-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, "").unwrap();
+        let mut watcher = ConfigWatcher::new(&path, Duration::ZERO);
+        assert!(!watcher.changed(Instant::now()));
+
+        std::fs::write(&path, "PROCESS_TABULAR = true\n").unwrap();
+        assert!(watcher.changed(Instant::now()));
+        assert!(!watcher.changed(Instant::now()));
+
+        // The file is missing while an editor replaces it
+        std::fs::remove_file(&path).unwrap();
+        assert!(!watcher.changed(Instant::now()));
+        std::fs::write(&path, "").unwrap();
+        assert!(watcher.changed(Instant::now()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}